@@ -0,0 +1,5 @@
+-- The tables may also be dropped by reverting 20220101000000_create_v3_tables.
+DROP TABLE IF EXISTS `Programs`;
+DROP TABLE IF EXISTS `Settings`;
+DROP TABLE IF EXISTS `Slave_dev`;
+DROP TABLE IF EXISTS `Users`;
@@ -0,0 +1 @@
+DROP TABLE State_audit;
@@ -0,0 +1,8 @@
+CREATE TABLE `State_audit` (
+	`audit_id`	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	`date_created`	INTEGER NOT NULL,
+	`user`	TEXT NOT NULL,
+	`requested_state`	TEXT NOT NULL,
+	`message`	TEXT NOT NULL,
+	`outcome`	TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP INDEX State_journal_date_created;
+DROP TABLE State_journal;
@@ -0,0 +1,10 @@
+CREATE TABLE `State_journal` (
+	`journal_id`	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	`date_created`	INTEGER NOT NULL,
+	`user`	TEXT NOT NULL,
+	`event`	TEXT NOT NULL,
+	`prior_state`	TEXT NOT NULL,
+	`resulting_state`	TEXT NOT NULL,
+	`error`	TEXT
+);
+CREATE INDEX `State_journal_date_created` ON `State_journal` (`date_created`);
@@ -0,0 +1 @@
+ALTER TABLE `Programs` DROP COLUMN `Date_updated`;
@@ -0,0 +1 @@
+DROP TABLE Program_revisions;
@@ -0,0 +1,13 @@
+CREATE TABLE `Program_revisions` (
+	`revision_id`	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	`prog_id`	INTEGER NOT NULL,
+	`revision`	INTEGER NOT NULL,
+	`file`	TEXT NOT NULL,
+	`hash`	TEXT NOT NULL,
+	`date_upload`	INTEGER NOT NULL,
+	UNIQUE(`prog_id`, `revision`)
+);
+-- Programs uploaded before there were revisions become the first revision.
+-- We don't know the hash of their contents.
+INSERT INTO `Program_revisions` (`prog_id`, `revision`, `file`, `hash`, `date_upload`)
+	SELECT `Prog_ID`, 1, `File`, '', `Date_upload` FROM `Programs`;
@@ -0,0 +1 @@
+ALTER TABLE `Programs` DROP COLUMN `Metadata`;
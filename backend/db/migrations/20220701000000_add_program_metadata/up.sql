@@ -0,0 +1 @@
+ALTER TABLE `Programs` ADD COLUMN `Metadata` TEXT;
@@ -0,0 +1,2 @@
+DROP INDEX Compilations_prog_id;
+DROP TABLE Compilations;
@@ -0,0 +1,13 @@
+-- The dates are in milliseconds since the epoch.
+CREATE TABLE `Compilations` (
+	`job_id`	INTEGER NOT NULL PRIMARY KEY,
+	`prog_id`	INTEGER NOT NULL,
+	`date_created`	INTEGER NOT NULL,
+	`date_started`	INTEGER,
+	`date_finished`	INTEGER,
+	`duration_ms`	INTEGER,
+	`exit_code`	INTEGER,
+	`status`	TEXT NOT NULL,
+	`log`	TEXT NOT NULL
+);
+CREATE INDEX `Compilations_prog_id` ON `Compilations` (`prog_id`);
@@ -0,0 +1,78 @@
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::Build;
+
+use super::response::*;
+use super::schema::state_audit;
+use super::sqlite::DbConn;
+
+use self::diesel::prelude::*;
+use rocket_sync_db_pools::diesel;
+
+// The number of audit entries that we return.
+const AUDIT_LIMIT: i64 = 100;
+
+// A record of an operator asking the PLC to change state. We keep these
+// so that we know who started or stopped the PLC and why.
+#[derive(Debug, Clone, Serialize, Queryable, Insertable)]
+#[serde(crate = "rocket::serde")]
+#[table_name = "state_audit"]
+pub struct AuditEntry {
+    #[serde(rename = "id")]
+    audit_id: Option<i32>,
+    // Seconds since the epoch.
+    #[serde(rename = "createdAt")]
+    date_created: i64,
+    user: String,
+    #[serde(rename = "requestedState")]
+    requested_state: String,
+    message: String,
+    // Either the resulting state or the reason the request failed.
+    outcome: String,
+}
+
+impl AuditEntry {
+    pub fn new(user: String, requested_state: String, message: String, outcome: String) -> Self {
+        AuditEntry {
+            audit_id: None,
+            date_created: Utc::now().timestamp(),
+            user,
+            requested_state,
+            message,
+            outcome,
+        }
+    }
+
+    pub async fn create(db: &DbConn, entry: AuditEntry) -> Result<usize, diesel::result::Error> {
+        db.run(move |conn| {
+            diesel::insert_into(state_audit::table)
+                .values(&entry)
+                .execute(conn)
+        })
+        .await
+    }
+
+    async fn recent(db: DbConn) -> Result<Vec<AuditEntry>, diesel::result::Error> {
+        db.run(move |conn| {
+            state_audit::table
+                .order(state_audit::audit_id.desc())
+                .limit(AUDIT_LIMIT)
+                .load(conn)
+        })
+        .await
+    }
+}
+
+#[get("/state/audit")]
+async fn get_audit(db: DbConn) -> OkResponse<Vec<AuditEntry>> {
+    AuditEntry::recent(db)
+        .await
+        .map(|entries| Ok(Json(entries)))
+        .map_err(|e| Error::from(e).to_response(Status::InternalServerError))?
+}
+
+pub fn mount(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
+    rocket.mount("/", routes![get_audit])
+}
@@ -0,0 +1,796 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::sync::mpsc::{self, UnboundedSender};
+use rocket::tokio::sync::Notify;
+use rocket::tokio::time;
+use rocket::{Build, State};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::logs::{LogBuffer, LogSink, Stream};
+use super::plc;
+use super::response::*;
+use super::schema::compilations;
+use super::settings::Settings;
+use super::sqlite::{BackgroundDb, DbConn};
+use super::users::Operator;
+
+use self::diesel::prelude::*;
+use rocket_sync_db_pools::diesel;
+
+type QueryResult<T> = std::result::Result<T, diesel::result::Error>;
+
+// How long we wait for the compiler to exit after we kill it.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+// How many compilations we keep in memory for each program. Once the
+// compiler exits, the compilation is also in the database.
+const HISTORY_PER_PROGRAM: usize = 10;
+
+// The record of a single compilation of a program. The record is filled
+// in as the compiler runs, so it may be read before the compiler exits.
+#[derive(Debug)]
+struct CompileRecord {
+    job_id: u32,
+    program_id: i32,
+    // The file of the program revision that we compile.
+    file: String,
+    // When we were asked to compile.
+    created_at: DateTime<Utc>,
+    // When the compiler started and exited (if it has).
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    started: Option<Instant>,
+    lines: Vec<String>,
+    // The duration in milliseconds once the compiler has exited.
+    duration_ms: Option<u64>,
+    exit_code: Option<i32>,
+    finished: bool,
+    // Whether someone asked us to stop the compiler.
+    cancelled: bool,
+}
+
+// Where a compilation is up to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "lowercase")]
+pub enum CompileStatus {
+    // Waiting for the PLC to stop so that the compiler can start.
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl CompileStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CompileStatus::Queued => "queued",
+            CompileStatus::Running => "running",
+            CompileStatus::Succeeded => "succeeded",
+            CompileStatus::Failed => "failed",
+            CompileStatus::Cancelled => "cancelled",
+        }
+    }
+
+    fn parse(status: &str) -> CompileStatus {
+        match status {
+            "queued" => CompileStatus::Queued,
+            "running" => CompileStatus::Running,
+            "succeeded" => CompileStatus::Succeeded,
+            "cancelled" => CompileStatus::Cancelled,
+            _ => CompileStatus::Failed,
+        }
+    }
+}
+
+impl CompileRecord {
+    fn status(&self) -> CompileStatus {
+        if !self.finished {
+            return match self.started {
+                Some(_) => CompileStatus::Running,
+                None => CompileStatus::Queued,
+            };
+        }
+        match (self.exit_code, self.cancelled) {
+            (Some(0), _) => CompileStatus::Succeeded,
+            (_, true) => CompileStatus::Cancelled,
+            _ => CompileStatus::Failed,
+        }
+    }
+}
+
+// A snapshot of the compilation that we return from the API.
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CompileLog {
+    #[serde(rename = "jobId")]
+    job_id: u32,
+    #[serde(rename = "programId")]
+    program_id: i32,
+    #[serde(rename = "createdAt")]
+    created_at: DateTime<Utc>,
+    #[serde(rename = "startedAt")]
+    started_at: Option<DateTime<Utc>>,
+    #[serde(rename = "finishedAt")]
+    finished_at: Option<DateTime<Utc>>,
+    #[serde(rename = "durationMs")]
+    duration_ms: Option<u64>,
+    #[serde(rename = "exitCode")]
+    exit_code: Option<i32>,
+    finished: bool,
+    status: CompileStatus,
+    // The output of the compiler (both stdout and stderr).
+    data: String,
+}
+
+impl CompileLog {
+    pub fn job_id(&self) -> u32 {
+        self.job_id
+    }
+}
+
+#[cfg(test)]
+impl CompileLog {
+    pub fn status(&self) -> CompileStatus {
+        self.status
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+}
+
+// A compilation in the database. We store each compilation once the
+// compiler exits. The dates are in milliseconds since the epoch.
+#[derive(Debug, Queryable, Insertable)]
+#[table_name = "compilations"]
+pub struct StoredCompilation {
+    job_id: i32,
+    prog_id: i32,
+    date_created: i64,
+    date_started: Option<i64>,
+    date_finished: Option<i64>,
+    duration_ms: Option<i64>,
+    exit_code: Option<i32>,
+    status: String,
+    log: String,
+}
+
+impl From<&CompileLog> for StoredCompilation {
+    fn from(log: &CompileLog) -> Self {
+        StoredCompilation {
+            job_id: log.job_id as i32,
+            prog_id: log.program_id,
+            date_created: log.created_at.timestamp_millis(),
+            date_started: log.started_at.map(|date| date.timestamp_millis()),
+            date_finished: log.finished_at.map(|date| date.timestamp_millis()),
+            duration_ms: log.duration_ms.map(|duration| duration as i64),
+            exit_code: log.exit_code,
+            status: String::from(log.status.as_str()),
+            log: log.data.clone(),
+        }
+    }
+}
+
+impl From<StoredCompilation> for CompileLog {
+    fn from(stored: StoredCompilation) -> Self {
+        CompileLog {
+            job_id: stored.job_id as u32,
+            program_id: stored.prog_id,
+            created_at: Utc.timestamp_millis(stored.date_created),
+            started_at: stored.date_started.map(|date| Utc.timestamp_millis(date)),
+            finished_at: stored.date_finished.map(|date| Utc.timestamp_millis(date)),
+            duration_ms: stored.duration_ms.map(|duration| duration as u64),
+            exit_code: stored.exit_code,
+            finished: true,
+            status: CompileStatus::parse(&stored.status),
+            data: stored.log,
+        }
+    }
+}
+
+impl StoredCompilation {
+    fn insert(conn: &SqliteConnection, log: &CompileLog) -> QueryResult<()> {
+        diesel::replace_into(compilations::table)
+            .values(StoredCompilation::from(log))
+            .execute(conn)
+            .map(|_| ())
+    }
+
+    fn get(conn: &SqliteConnection, job_id: i32) -> QueryResult<Option<CompileLog>> {
+        compilations::table
+            .find(job_id)
+            .first::<StoredCompilation>(conn)
+            .optional()
+            .map(|stored| stored.map(CompileLog::from))
+    }
+
+    // Gets the compilations of the program, newest first.
+    fn for_program(conn: &SqliteConnection, prog_id: i32) -> QueryResult<Vec<CompileLog>> {
+        compilations::table
+            .filter(compilations::prog_id.eq(prog_id))
+            .order(compilations::job_id.desc())
+            .load::<StoredCompilation>(conn)
+            .map(|stored| stored.into_iter().map(CompileLog::from).collect())
+    }
+
+    // Gets every compilation, newest first.
+    fn all(conn: &SqliteConnection) -> QueryResult<Vec<CompileLog>> {
+        compilations::table
+            .order(compilations::job_id.desc())
+            .load::<StoredCompilation>(conn)
+            .map(|stored| stored.into_iter().map(CompileLog::from).collect())
+    }
+
+    fn latest(conn: &SqliteConnection) -> QueryResult<Option<CompileLog>> {
+        compilations::table
+            .order(compilations::job_id.desc())
+            .first::<StoredCompilation>(conn)
+            .optional()
+            .map(|stored| stored.map(CompileLog::from))
+    }
+
+    fn last_job_id(conn: &SqliteConnection) -> QueryResult<Option<i32>> {
+        compilations::table
+            .select(diesel::dsl::max(compilations::job_id))
+            .first(conn)
+    }
+
+    // Removes the compilations of the program.
+    pub fn delete_all(conn: &SqliteConnection, prog_id: i32) -> QueryResult<usize> {
+        diesel::delete(compilations::table)
+            .filter(compilations::prog_id.eq(prog_id))
+            .execute(conn)
+    }
+}
+
+// Combines the compilations that we have in memory with the ones in the
+// database, newest first. The ones in memory may still be running.
+fn merge(in_memory: Vec<Compilation>, stored: Vec<CompileLog>) -> Vec<CompileLog> {
+    let mut logs = in_memory.iter().map(|c| c.log()).collect::<Vec<_>>();
+    let jobs = logs.iter().map(|log| log.job_id).collect::<HashSet<_>>();
+    logs.extend(stored.into_iter().filter(|log| !jobs.contains(&log.job_id)));
+    logs.sort_by_key(|log| std::cmp::Reverse(log.job_id));
+    logs
+}
+
+// Where we send each compilation when the compiler exits (if anywhere).
+type Recorder = Arc<Mutex<Option<UnboundedSender<Compilation>>>>;
+
+// A handle to a compilation. The handle is given to the runtime which
+// writes the compiler output into the log as it runs. The output is
+// also written to the stream of all compiler output.
+#[derive(Clone)]
+pub struct Compilation {
+    record: Arc<Mutex<CompileRecord>>,
+    stream: LogBuffer,
+    // Wakes anyone waiting for the compiler to exit.
+    done: Arc<Notify>,
+    recorder: Recorder,
+}
+
+impl fmt::Debug for Compilation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Compilation {}", self.job_id())
+    }
+}
+
+impl Compilation {
+    pub fn new(job_id: u32, program_id: i32, file: String, stream: LogBuffer) -> Self {
+        Compilation {
+            record: Arc::new(Mutex::new(CompileRecord {
+                job_id,
+                program_id,
+                file,
+                created_at: Utc::now(),
+                started_at: None,
+                finished_at: None,
+                started: None,
+                lines: Vec::new(),
+                duration_ms: None,
+                exit_code: None,
+                finished: false,
+                cancelled: false,
+            })),
+            stream,
+            done: Arc::new(Notify::new()),
+            recorder: Recorder::default(),
+        }
+    }
+
+    pub fn job_id(&self) -> u32 {
+        self.record.lock().unwrap().job_id
+    }
+
+    pub fn program_id(&self) -> i32 {
+        self.record.lock().unwrap().program_id
+    }
+
+    pub fn file(&self) -> String {
+        self.record.lock().unwrap().file.clone()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.record.lock().unwrap().finished
+    }
+
+    // Records that the compiler is running.
+    pub fn start(&self) {
+        let mut record = self.record.lock().unwrap();
+        if record.started.is_none() {
+            record.started_at = Some(Utc::now());
+            record.started = Some(Instant::now());
+        }
+    }
+
+    // Records that we are stopping the compiler so that we report the
+    // compilation as cancelled rather than failed.
+    pub fn cancel(&self) {
+        let mut record = self.record.lock().unwrap();
+        if !record.finished {
+            record.cancelled = true;
+        }
+    }
+
+    // Waits for the compiler to exit and returns the exit code.
+    pub async fn finished(&self) -> Option<i32> {
+        loop {
+            // We must ask to be notified before we check so that we
+            // don't miss the notification.
+            let notified = self.done.notified();
+            {
+                let record = self.record.lock().unwrap();
+                if record.finished {
+                    return record.exit_code;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    pub fn log(&self) -> CompileLog {
+        let record = self.record.lock().unwrap();
+        CompileLog {
+            job_id: record.job_id,
+            program_id: record.program_id,
+            created_at: record.created_at,
+            started_at: record.started_at,
+            finished_at: record.finished_at,
+            duration_ms: record.duration_ms,
+            exit_code: record.exit_code,
+            finished: record.finished,
+            status: record.status(),
+            data: record.lines.join("\n"),
+        }
+    }
+}
+
+impl LogSink for Compilation {
+    fn append(&self, stream: Stream, line: String) {
+        self.record.lock().unwrap().lines.push(line.clone());
+        self.stream.push(stream, line);
+    }
+
+    fn finish(&self, exit_code: Option<i32>) {
+        let mut record = self.record.lock().unwrap();
+        if record.finished {
+            return;
+        }
+        record.finished_at = Some(Utc::now());
+        record.duration_ms = record
+            .started
+            .map(|started| started.elapsed().as_millis() as u64);
+        record.exit_code = exit_code;
+        record.finished = true;
+        let cancelled = record.status() == CompileStatus::Cancelled;
+        if cancelled {
+            record.lines.push(String::from("Compilation cancelled"));
+        }
+        drop(record);
+
+        if cancelled {
+            self.stream
+                .push(Stream::Stderr, String::from("Compilation cancelled"));
+        }
+
+        self.stream.finish(exit_code);
+        self.done.notify_waiters();
+
+        // It is not an error if the recorder is no longer listening.
+        if let Some(recorder) = self.recorder.lock().unwrap().as_ref() {
+            let _ = recorder.send(self.clone());
+        }
+    }
+}
+
+// Keeps the recent compilations of each program, so that clients can
+// look up a compilation by its job ID or find the history of a program.
+#[derive(Clone)]
+pub struct Compilations {
+    next_job_id: Arc<AtomicU32>,
+    by_job: Arc<Mutex<HashMap<u32, Compilation>>>,
+    // The compilations of each program, oldest first.
+    by_program: Arc<Mutex<HashMap<i32, VecDeque<Compilation>>>>,
+    latest: Arc<Mutex<Option<Compilation>>>,
+    // The stream of output from all compilations.
+    stream: LogBuffer,
+    recorder: Recorder,
+}
+
+impl Compilations {
+    pub fn new(stream: LogBuffer) -> Self {
+        Compilations {
+            next_job_id: Arc::new(AtomicU32::new(1)),
+            by_job: Arc::new(Mutex::new(HashMap::new())),
+            by_program: Arc::new(Mutex::new(HashMap::new())),
+            latest: Arc::new(Mutex::new(None)),
+            stream,
+            recorder: Recorder::default(),
+        }
+    }
+
+    // Numbers the jobs from now on after the job, so that they don't
+    // collide with the jobs that are in the database.
+    fn continue_after(&self, job_id: u32) {
+        self.next_job_id
+            .fetch_max(job_id.saturating_add(1), Ordering::SeqCst);
+    }
+
+    // Sends each compilation from now on to the recorder when the
+    // compiler exits.
+    fn set_recorder(&self, recorder: UnboundedSender<Compilation>) {
+        *self.recorder.lock().unwrap() = Some(recorder);
+    }
+
+    // Starts a new compilation log for the program, forgetting the oldest
+    // compilation of the program if it has too many.
+    pub fn begin(&self, program_id: i32, file: String) -> Compilation {
+        let job_id = self.next_job_id.fetch_add(1, Ordering::SeqCst);
+        let mut compilation = Compilation::new(job_id, program_id, file, self.stream.clone());
+        compilation.recorder = self.recorder.clone();
+
+        let mut by_job = self.by_job.lock().unwrap();
+        let mut by_program = self.by_program.lock().unwrap();
+        let history = by_program.entry(program_id).or_default();
+        history.push_back(compilation.clone());
+        while history.len() > HISTORY_PER_PROGRAM {
+            if let Some(oldest) = history.pop_front() {
+                by_job.remove(&oldest.job_id());
+            }
+        }
+        by_job.insert(job_id, compilation.clone());
+        drop(by_program);
+        drop(by_job);
+
+        *self.latest.lock().unwrap() = Some(compilation.clone());
+        compilation
+    }
+
+    pub fn latest(&self) -> Option<Compilation> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    pub fn job(&self, job_id: u32) -> Option<Compilation> {
+        self.by_job.lock().unwrap().get(&job_id).cloned()
+    }
+
+    // Gets the compilations of the program, newest first.
+    pub fn history(&self, program_id: i32) -> Vec<Compilation> {
+        self.by_program
+            .lock()
+            .unwrap()
+            .get(&program_id)
+            .map(|history| history.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Gets every compilation that we remember, newest first.
+    pub fn all(&self) -> Vec<Compilation> {
+        let mut all: Vec<Compilation> = self.by_job.lock().unwrap().values().cloned().collect();
+        all.sort_by_key(|compilation| std::cmp::Reverse(compilation.job_id()));
+        all
+    }
+
+    // Finds the compilation in memory, or in the database if it is one
+    // that we no longer remember.
+    pub async fn find(&self, db: &DbConn, job_id: u32) -> QueryResult<Option<CompileLog>> {
+        if let Some(compilation) = self.job(job_id) {
+            return Ok(Some(compilation.log()));
+        }
+        let job_id = match i32::try_from(job_id) {
+            Ok(job_id) => job_id,
+            Err(_) => return Ok(None),
+        };
+        db.run(move |conn| StoredCompilation::get(conn, job_id))
+            .await
+    }
+
+    // Gets the compilations of the program, newest first.
+    pub async fn find_history(&self, db: &DbConn, program_id: i32) -> QueryResult<Vec<CompileLog>> {
+        let stored = db
+            .run(move |conn| StoredCompilation::for_program(conn, program_id))
+            .await?;
+        Ok(merge(self.history(program_id), stored))
+    }
+
+    // Gets every compilation, newest first.
+    pub async fn find_all(&self, db: &DbConn) -> QueryResult<Vec<CompileLog>> {
+        let stored = db.run(|conn| StoredCompilation::all(conn)).await?;
+        Ok(merge(self.all(), stored))
+    }
+
+    // Gets the most recent compilation of any program.
+    pub async fn find_latest(&self, db: &DbConn) -> QueryResult<Option<CompileLog>> {
+        if let Some(compilation) = self.latest() {
+            return Ok(Some(compilation.log()));
+        }
+        db.run(|conn| StoredCompilation::latest(conn)).await
+    }
+}
+
+// Records each compilation in the database once we have launched (and so
+// have a database). We also remember the program that last compiled so
+// that we can start it when we next start up.
+pub fn stage() -> AdHoc {
+    AdHoc::on_liftoff("Compilation Recorder", |rocket| {
+        Box::pin(async move {
+            let compilations = match rocket.state::<Compilations>() {
+                Some(compilations) => compilations,
+                None => return,
+            };
+            let db = match BackgroundDb::new(rocket) {
+                Ok(db) => db,
+                Err(e) => {
+                    error!("Unable to record compilations: {}", e);
+                    return;
+                }
+            };
+            match db.run(StoredCompilation::last_job_id).await {
+                Ok(Some(job_id)) => compilations.continue_after(job_id as u32),
+                Ok(None) => {}
+                Err(e) => error!("Unable to find the last compilation: {}", e),
+            }
+
+            let (sender, mut receiver) = mpsc::unbounded_channel::<Compilation>();
+            compilations.set_recorder(sender);
+
+            rocket::tokio::spawn(async move {
+                while let Some(compilation) = receiver.recv().await {
+                    let log = compilation.log();
+                    let file = compilation.file();
+                    let result = db
+                        .run(move |conn| {
+                            conn.transaction(|| {
+                                StoredCompilation::insert(conn, &log)?;
+                                if log.status == CompileStatus::Succeeded {
+                                    Settings::set_last_compiled(conn, log.program_id, file)?;
+                                }
+                                Ok(())
+                            })
+                        })
+                        .await;
+                    if let Err(e) = result {
+                        error!("Unable to record the compilation: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
+
+#[get("/compilations")]
+async fn get_compilations(
+    db: DbConn,
+    compilations: &State<Compilations>,
+) -> OkResponse<Vec<CompileLog>> {
+    compilations
+        .find_all(&db)
+        .await
+        .map(Json)
+        .map_err(|e| Error::from(e).to_response(Status::InternalServerError))
+}
+
+#[get("/compilations/<job_id>")]
+async fn get_compilation(
+    db: DbConn,
+    compilations: &State<Compilations>,
+    job_id: u32,
+) -> OkResponse<CompileLog> {
+    compilations
+        .find(&db, job_id)
+        .await
+        .map_err(|e| Error::from(e).to_response(Status::InternalServerError))?
+        .map(Json)
+        .ok_or_else(|| {
+            Error::response(
+                Status::NotFound,
+                "not_found",
+                "The compilation does not exist",
+            )
+        })
+}
+
+// Kills the compiler (and everything that it started) and returns the
+// log once the compiler has exited.
+#[delete("/compilations/<job_id>")]
+async fn cancel_compilation(
+    plc: &State<plc::SharedPlcStateMachine>,
+    compilations: &State<Compilations>,
+    operator: Operator,
+    job_id: u32,
+) -> OkResponse<CompileLog> {
+    let compilation = compilations.job(job_id).ok_or_else(|| {
+        Error::response(
+            Status::NotFound,
+            "not_found",
+            "The compilation does not exist",
+        )
+    })?;
+
+    plc.run_as(plc::PlcEvent::CancelCompile(job_id), &operator.0)
+        .await
+        .map_err(Error::from_plc)?;
+
+    // Even if the compiler hasn't exited yet, we have killed it, so
+    // we return what we know.
+    let _ = time::timeout(CANCEL_TIMEOUT, compilation.finished()).await;
+    Ok(Json(compilation.log()))
+}
+
+pub fn mount(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
+    rocket.mount(
+        "/",
+        routes![get_compilations, get_compilation, cancel_compilation],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::runtime::SimulatedRuntime;
+    use super::super::test_async_client;
+    use super::*;
+
+    #[rocket::async_test]
+    async fn test_finished_waits_for_exit_code() {
+        let compilation = Compilation::new(1, 1, String::from("blink.st"), LogBuffer::new(10));
+        let finisher = compilation.clone();
+        rocket::tokio::spawn(async move {
+            finisher.append(Stream::Stdout, String::from("done"));
+            finisher.finish(Some(0));
+        });
+
+        assert_eq!(compilation.finished().await, Some(0));
+        // Once finished, we return immediately.
+        assert_eq!(compilation.finished().await, Some(0));
+        assert_eq!(compilation.log().status(), CompileStatus::Succeeded);
+    }
+
+    #[test]
+    fn test_status_reports_cancelled_and_failed() {
+        let cancelled = Compilation::new(1, 1, String::from("blink.st"), LogBuffer::new(10));
+        assert_eq!(cancelled.log().status(), CompileStatus::Queued);
+        cancelled.start();
+        assert_eq!(cancelled.log().status(), CompileStatus::Running);
+        cancelled.cancel();
+        cancelled.finish(None);
+        assert_eq!(cancelled.log().status(), CompileStatus::Cancelled);
+
+        let failed = Compilation::new(2, 1, String::from("blink.st"), LogBuffer::new(10));
+        failed.finish(Some(1));
+        // Cancelling after the fact doesn't change the outcome.
+        failed.cancel();
+        assert_eq!(failed.log().status(), CompileStatus::Failed);
+    }
+
+    #[test]
+    fn test_begin_assigns_job_ids() {
+        let compilations = Compilations::new(LogBuffer::new(10));
+        let first = compilations.begin(3, String::from("blink.st"));
+        let second = compilations.begin(3, String::from("blink.st"));
+        assert_eq!(first.job_id(), 1);
+        assert_eq!(second.job_id(), 2);
+        assert_eq!(compilations.job(1).unwrap().program_id(), 3);
+        assert_eq!(compilations.history(3)[0].job_id(), 2);
+        assert!(compilations.job(3).is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_recorder_receives_finished_compilations() {
+        let compilations = Compilations::new(LogBuffer::new(10));
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        compilations.set_recorder(sender);
+
+        let compilation = compilations.begin(4, String::from("blink.st"));
+        assert!(receiver.try_recv().is_err());
+        compilation.finish(Some(0));
+        // Finishing again is not another compilation.
+        compilation.finish(Some(1));
+        let recorded = receiver.recv().await.unwrap();
+        assert_eq!(recorded.job_id(), compilation.job_id());
+        assert_eq!(recorded.log().status(), CompileStatus::Succeeded);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_history_keeps_recent_compilations() {
+        let compilations = Compilations::new(LogBuffer::new(10));
+        compilations.begin(1, String::from("blink.st"));
+        for _ in 0..HISTORY_PER_PROGRAM {
+            compilations.begin(2, String::from("blink.st"));
+        }
+        let latest = compilations.begin(1, String::from("blink.st"));
+
+        let history = compilations.history(1);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].job_id(), latest.job_id());
+        assert_eq!(history[1].job_id(), 1);
+        assert_eq!(compilations.all().len(), HISTORY_PER_PROGRAM + 2);
+
+        // The oldest compilation is forgotten.
+        compilations.begin(2, String::from("blink.st"));
+        assert_eq!(compilations.history(2).len(), HISTORY_PER_PROGRAM);
+        assert!(compilations.job(2).is_none());
+        assert!(compilations.history(3).is_empty());
+    }
+
+    #[test]
+    fn test_continue_after_stored_jobs() {
+        let compilations = Compilations::new(LogBuffer::new(10));
+        compilations.continue_after(7);
+        assert_eq!(compilations.begin(1, String::from("blink.st")).job_id(), 8);
+    }
+
+    #[rocket::async_test]
+    async fn test_history_is_kept_in_the_database() {
+        let (client, _plc) = test_async_client(&SimulatedRuntime::new()).await;
+        let compilations = client.rocket().state::<Compilations>().unwrap();
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+
+        for _ in 0..=HISTORY_PER_PROGRAM {
+            let compilation = compilations.begin(1, String::from("blink.st"));
+            compilation.start();
+            compilation.append(Stream::Stderr, String::from("syntax error"));
+            compilation.finish(Some(1));
+        }
+        let end = Instant::now() + Duration::from_secs(5);
+        while db
+            .run(|conn| StoredCompilation::all(conn))
+            .await
+            .unwrap()
+            .len()
+            <= HISTORY_PER_PROGRAM
+            && Instant::now() < end
+        {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // We no longer have the first compilation in memory.
+        assert!(compilations.job(1).is_none());
+        let first = compilations.find(&db, 1).await.unwrap().unwrap();
+        assert_eq!(first.status(), CompileStatus::Failed);
+        assert_eq!(first.exit_code(), Some(1));
+        assert_eq!(first.data(), "syntax error");
+        assert!(first.duration_ms.is_some());
+
+        let history = compilations.find_history(&db, 1).await.unwrap();
+        assert_eq!(history.len(), HISTORY_PER_PROGRAM + 1);
+        assert_eq!(history.last().unwrap().job_id(), 1);
+
+        let response = client.get("/compilations/1").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/compilations/100").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
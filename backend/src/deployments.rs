@@ -0,0 +1,468 @@
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::compilations::{Compilation, Compilations};
+use super::logs::{LogSink, Stream};
+use super::plc;
+use super::response::Error;
+
+// How long we wait for the PLC to stop before we give up on deploying.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Deploying a program stops the PLC, compiles the program and then runs
+// it. If that doesn't work, then we go back to what was running before.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub enum DeployPhase {
+    Stopping,
+    Compiling,
+    Starting,
+    RollingBack,
+    // The new program is running.
+    Succeeded,
+    // The previous program is running again.
+    RolledBack,
+    // Neither program is running.
+    Failed,
+}
+
+impl DeployPhase {
+    fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            DeployPhase::Succeeded | DeployPhase::RolledBack | DeployPhase::Failed
+        )
+    }
+}
+
+// The most recent deployment and where it is up to.
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Deployment {
+    #[serde(rename = "programId")]
+    program_id: i32,
+    // The compilation of the program.
+    #[serde(rename = "jobId")]
+    job_id: u32,
+    phase: DeployPhase,
+    // Why the deployment didn't succeed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // The file of the program that was running before, which we start
+    // again if the deployment fails.
+    #[serde(skip)]
+    previous: Option<String>,
+}
+
+#[cfg(test)]
+impl Deployment {
+    pub fn phase(&self) -> DeployPhase {
+        self.phase
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+// The response to a request that would interfere with the deployment in
+// progress.
+pub fn deploying() -> Custom<Json<Error>> {
+    Error::response(
+        Status::Conflict,
+        "deploying",
+        "Another program is being deployed",
+    )
+}
+
+// Keeps track of the most recent deployment. We only deploy one program
+// at a time.
+#[derive(Clone, Default)]
+pub struct Deployments {
+    current: Arc<Mutex<Option<Deployment>>>,
+}
+
+impl Deployments {
+    pub fn new() -> Self {
+        Deployments::default()
+    }
+
+    pub fn current(&self) -> Option<Deployment> {
+        self.current.lock().unwrap().clone()
+    }
+
+    // Whether a deployment has started and not yet finished. While it is
+    // in progress, only the deployment may change what the PLC is doing.
+    pub fn in_progress(&self) -> bool {
+        self.current
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|deployment| !deployment.phase.is_finished())
+    }
+
+    // Starts deploying the program and begins the compilation for it,
+    // unless we are already deploying a program.
+    pub fn begin(
+        &self,
+        program_id: i32,
+        file: String,
+        compilations: &Compilations,
+    ) -> Option<(Deployment, Compilation)> {
+        let mut current = self.current.lock().unwrap();
+        if let Some(deployment) = current.as_ref() {
+            if !deployment.phase.is_finished() {
+                return None;
+            }
+        }
+
+        let compilation = compilations.begin(program_id, file);
+        let deployment = Deployment {
+            program_id,
+            job_id: compilation.job_id(),
+            phase: DeployPhase::Stopping,
+            error: None,
+            previous: None,
+        };
+        *current = Some(deployment.clone());
+        Some((deployment, compilation))
+    }
+
+    // The file that the deployment in progress may go back to.
+    pub fn rollback_target(&self) -> Option<String> {
+        self.current
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|deployment| !deployment.phase.is_finished())
+            .and_then(|deployment| deployment.previous.clone())
+    }
+
+    fn set_previous(&self, previous: Option<String>) {
+        if let Some(deployment) = self.current.lock().unwrap().as_mut() {
+            deployment.previous = previous;
+        }
+    }
+
+    fn set_phase(&self, phase: DeployPhase, error: Option<String>) {
+        if let Some(deployment) = self.current.lock().unwrap().as_mut() {
+            deployment.phase = phase;
+            deployment.error = error;
+        }
+    }
+
+    // Runs the deployment through each phase. The deployment must have
+    // been started with begin.
+    pub async fn deploy(
+        self,
+        plc: plc::SharedPlcStateMachine,
+        compilation: Compilation,
+        file: String,
+        user: String,
+    ) {
+        let snapshot = plc.snapshot();
+        let previous = match snapshot.state {
+            plc::PlcState::Running(_) => snapshot.program,
+            _ => None,
+        };
+        self.set_previous(previous.clone());
+
+        if let Err(e) = self.compile(&plc, &compilation, file.clone(), &user).await {
+            // The compiler never started so record why in the log.
+            compilation.append(Stream::Stderr, e.clone());
+            compilation.finish(None);
+            return self.roll_back(&plc, previous, e, &user).await;
+        }
+
+        // Let the state machine see that the compiler has exited so that
+        // we can start the program straight away.
+        let exit_code = compilation.finished().await;
+        let _ = plc.run(plc::PlcEvent::NoOp).await;
+        if exit_code != Some(0) {
+            let error = format!("Compiling {} failed", file);
+            return self.roll_back(&plc, previous, error, &user).await;
+        }
+
+        self.set_phase(DeployPhase::Starting, None);
+        match plc.run_as(plc::PlcEvent::Run(file), &user).await {
+            Ok(_) => self.set_phase(DeployPhase::Succeeded, None),
+            Err(e) => self.roll_back(&plc, previous, e.to_string(), &user).await,
+        }
+    }
+
+    // Stops the PLC and starts the compiler.
+    async fn compile(
+        &self,
+        plc: &plc::SharedPlcStateMachine,
+        compilation: &Compilation,
+        file: String,
+        user: &str,
+    ) -> Result<(), String> {
+        plc.transition_as(plc::PlcEvent::NoOp, STOP_TIMEOUT, user)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        plc.run_as(plc::PlcEvent::Compile(file, compilation.clone()), user)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.set_phase(DeployPhase::Compiling, None);
+        Ok(())
+    }
+
+    // Runs the program that was running before we started, if there was
+    // one. A failed compile leaves the previous build in place, so we can
+    // start it again.
+    async fn roll_back(
+        &self,
+        plc: &plc::SharedPlcStateMachine,
+        previous: Option<String>,
+        error: String,
+        user: &str,
+    ) {
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return self.set_phase(DeployPhase::Failed, Some(error)),
+        };
+
+        self.set_phase(DeployPhase::RollingBack, Some(error.clone()));
+        match plc.run_as(plc::PlcEvent::Run(previous), user).await {
+            Ok(_) => self.set_phase(DeployPhase::RolledBack, Some(error)),
+            Err(e) => self.set_phase(
+                DeployPhase::Failed,
+                Some(format!("{}; unable to roll back: {}", error, e)),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::logs::LogBuffer;
+    use super::super::runtime::SimulatedRuntime;
+    use super::super::schema::programs;
+    use super::super::settings::Settings;
+    use super::super::sqlite::DbConn;
+    use super::super::test_async_client;
+    use super::*;
+    use rocket::http::ContentType;
+    use rocket::local::asynchronous::Client;
+    use rocket::tokio::time;
+    use std::time::Instant;
+
+    use self::diesel::prelude::*;
+    use rocket_sync_db_pools::diesel;
+
+    // Creates a client with one program to deploy.
+    async fn client(runtime: &SimulatedRuntime) -> (Client, plc::SharedPlcStateMachine) {
+        let (client, plc) = test_async_client(runtime).await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        db.run(|conn| {
+            diesel::insert_into(programs::table)
+                .values((
+                    programs::name.eq("Blink"),
+                    programs::description.eq("Blinks a light"),
+                    programs::file.eq("blink.st"),
+                    programs::date_upload.eq(0),
+                ))
+                .execute(conn)
+        })
+        .await
+        .expect("program");
+        (client, plc)
+    }
+
+    // Waits for the deployment to reach the phase.
+    async fn wait_for(client: &Client, phase: DeployPhase) -> Deployment {
+        let deployments = client.rocket().state::<Deployments>().unwrap();
+        let end = Instant::now() + Duration::from_secs(5);
+        loop {
+            let deployment = deployments.current().expect("deployment");
+            if deployment.phase() == phase || Instant::now() > end {
+                return deployment;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn compiler(client: &Client, plc: &plc::SharedPlcStateMachine) -> sysinfo::Pid {
+        wait_for(client, DeployPhase::Compiling).await;
+        match plc.snapshot().state {
+            plc::PlcState::Compiling(pid) => pid,
+            state => panic!("expected compiling, got {:?}", state),
+        }
+    }
+
+    #[test]
+    fn test_rollback_target_only_while_deploying() {
+        let deployments = Deployments::new();
+        let compilations = Compilations::new(LogBuffer::new(10));
+        deployments
+            .begin(1, String::from("blink.st"), &compilations)
+            .expect("deployment");
+        deployments.set_previous(Some(String::from("blink.st")));
+        assert_eq!(deployments.rollback_target().as_deref(), Some("blink.st"));
+
+        deployments.set_phase(DeployPhase::Succeeded, None);
+        assert_eq!(deployments.rollback_target(), None);
+    }
+
+    #[rocket::async_test]
+    async fn test_deploy_compiles_then_runs() {
+        let runtime = SimulatedRuntime::new();
+        let (client, plc) = client(&runtime).await;
+        plc.run(plc::PlcEvent::Run(String::from("old.st")))
+            .await
+            .unwrap();
+
+        let response = client.put("/programs/1/actions/deploy").dispatch().await;
+        assert_eq!(response.status(), Status::Accepted);
+        assert_eq!(response.headers().get_one("Location"), Some("/state"));
+
+        let pid = compiler(&client, &plc).await;
+        let body = client.get("/state").dispatch().await.into_string().await;
+        assert!(body.unwrap().contains(r#""phase":"compiling""#));
+
+        runtime.exit(pid);
+        let deployment = wait_for(&client, DeployPhase::Succeeded).await;
+        assert_eq!(deployment.phase(), DeployPhase::Succeeded);
+        assert_matches!(plc.snapshot().state, plc::PlcState::Running(_));
+        assert_eq!(runtime.program(), Some(String::from("blink.st")));
+
+        // The program is recorded in the background once it compiles.
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let end = Instant::now() + Duration::from_secs(5);
+        while Settings::last_compiled(&db).await.unwrap().is_none() && Instant::now() < end {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let last = Settings::last_compiled(&db).await.unwrap().unwrap();
+        assert_eq!(last.program_id, 1);
+        assert_eq!(last.file.as_deref(), Some("blink.st"));
+    }
+
+    #[rocket::async_test]
+    async fn test_deploy_compile_fails_rolls_back() {
+        let runtime = SimulatedRuntime::new();
+        let (client, plc) = client(&runtime).await;
+        plc.run(plc::PlcEvent::Run(String::from("old.st")))
+            .await
+            .unwrap();
+
+        client.put("/programs/1/actions/deploy").dispatch().await;
+        let pid = compiler(&client, &plc).await;
+        runtime.exit_with_code(pid, 1);
+
+        let deployment = wait_for(&client, DeployPhase::RolledBack).await;
+        assert_eq!(deployment.phase(), DeployPhase::RolledBack);
+        assert_eq!(deployment.error(), Some("Compiling blink.st failed"));
+        assert_matches!(plc.snapshot().state, plc::PlcState::Running(_));
+        assert_eq!(runtime.program(), Some(String::from("old.st")));
+    }
+
+    // Stopping the program that was running is not a crash, so the
+    // supervisor leaves the deployment alone.
+    #[rocket::async_test]
+    async fn test_deploy_stopping_is_not_a_crash() {
+        // The program was running before the server started, so there is
+        // no program that the supervisor could restart.
+        let runtime = SimulatedRuntime::new();
+        runtime.launch_external();
+        let (client, plc) = client(&runtime).await;
+        plc.set_supervision(plc::SupervisionPolicy {
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::from_secs(60),
+            max_crashes: 5,
+            window: Duration::from_secs(300),
+        });
+        plc.run(plc::PlcEvent::NoOp).await.unwrap();
+        assert_matches!(plc.snapshot().state, plc::PlcState::Running(_));
+
+        client.put("/programs/1/actions/deploy").dispatch().await;
+        let pid = compiler(&client, &plc).await;
+        runtime.exit(pid);
+        let deployment = wait_for(&client, DeployPhase::Succeeded).await;
+        assert_eq!(deployment.phase(), DeployPhase::Succeeded);
+
+        // The journal is written in the background, so wait for the run.
+        let end = Instant::now() + Duration::from_secs(5);
+        let history = loop {
+            let response = client.get("/state/history").dispatch().await;
+            let history = response.into_string().await.unwrap();
+            if history.contains("Event: Run blink.st") || Instant::now() > end {
+                break history;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        };
+        assert!(history.contains("Event: Run blink.st"));
+        assert!(!history.contains("FAULTED"), "{}", history);
+        assert!(!history.contains(plc::SUPERVISOR_USER), "{}", history);
+        assert_eq!(plc.snapshot().fault, None);
+    }
+
+    #[rocket::async_test]
+    async fn test_deploy_compile_fails_without_previous_fails() {
+        let runtime = SimulatedRuntime::new();
+        let (client, plc) = client(&runtime).await;
+
+        client.put("/programs/1/actions/deploy").dispatch().await;
+        let pid = compiler(&client, &plc).await;
+        runtime.exit_with_code(pid, 1);
+
+        let deployment = wait_for(&client, DeployPhase::Failed).await;
+        assert_eq!(deployment.phase(), DeployPhase::Failed);
+        assert_eq!(plc.snapshot().state, plc::PlcState::Stopped);
+        assert_eq!(runtime.program(), None);
+    }
+
+    #[rocket::async_test]
+    async fn test_deploy_while_deploying_is_conflict() {
+        let (client, plc) = client(&SimulatedRuntime::new()).await;
+
+        client.put("/programs/1/actions/deploy").dispatch().await;
+        compiler(&client, &plc).await;
+        let response = client.put("/programs/1/actions/deploy").dispatch().await;
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    // Compiling or changing the state would take the PLC away from the
+    // deployment.
+    #[rocket::async_test]
+    async fn test_compile_and_set_state_while_deploying_are_conflicts() {
+        let runtime = SimulatedRuntime::new();
+        let (client, plc) = client(&runtime).await;
+
+        client.put("/programs/1/actions/deploy").dispatch().await;
+        let pid = compiler(&client, &plc).await;
+        let response = client.put("/programs/1/actions/compile").dispatch().await;
+        assert_eq!(response.status(), Status::Conflict);
+        let response = client
+            .put("/state")
+            .header(ContentType::JSON)
+            .body(r#"{"state": "STOPPED", "message": "stop"}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Conflict);
+        assert!(runtime.is_running(pid));
+
+        runtime.exit(pid);
+        wait_for(&client, DeployPhase::Succeeded).await;
+        let response = client
+            .put("/state")
+            .header(ContentType::JSON)
+            .body(r#"{"state": "STOPPED", "message": "stop"}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_deploy_unknown_program_is_not_found() {
+        let (client, _plc) = client(&SimulatedRuntime::new()).await;
+        let response = client.put("/programs/2/actions/deploy").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
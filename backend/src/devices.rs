@@ -1,8 +1,13 @@
+use rocket::http::Status;
 use rocket::response::Debug;
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
 use rocket::Build;
+use std::convert::TryFrom;
+use std::num::TryFromIntError;
 
+use super::pagination::{ListQuery, Page, PageResponse, Sort};
+use super::response::*;
 use super::schema::slave_dev;
 use super::sqlite::DbConn;
 
@@ -14,8 +19,8 @@ type Result<T, E = Debug<diesel::result::Error>> = std::result::Result<T, E>;
 #[derive(Deserialize, Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct ModbusRegisterDefinition {
-    start: u8,
-    size: u8,
+    start: u16,
+    size: u16,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -58,53 +63,94 @@ struct SlaveDev {
     hr_write_size: i32,
 }
 
-#[get("/devices")]
-async fn devices(db: DbConn) -> Result<Json<Vec<ModbusDevice>>> {
-    let programs: Vec<SlaveDev> = db.run(move |conn| slave_dev::table.load(conn)).await?;
-
-    let mut counter_di = 0;
-    let mut counter_do = 0;
-    let mut counter_ai = 0;
-    let mut counter_ao = 0;
-
-    for program in programs.iter() {
-        if program.di_size == 0 {
-        } else {
-            counter_di += program.di_size;
-        }
-
-        if program.coil_size == 0 {
-        } else {
-            counter_do += program.coil_size;
-        }
-
-        if program.ir_size + program.hr_read_size == 0 {
-        } else {
-            counter_ai += program.ir_size + program.hr_read_size;
-        }
-
-        if program.hr_write_size == 0 {
-        } else {
-            counter_ao += program.hr_write_size;
-        }
+impl SlaveDev {
+    // Gets a page of the devices whose name or type match the pattern, and
+    // the number of devices that match.
+    async fn list(
+        db: DbConn,
+        sort: Sort,
+        pattern: Option<String>,
+        limit: i64,
+        offset: i64,
+    ) -> std::result::Result<(Vec<SlaveDev>, i64), diesel::result::Error> {
+        db.run(move |conn| {
+            let matching = || {
+                let mut query = slave_dev::table.into_boxed();
+                if let Some(pattern) = &pattern {
+                    query = query.filter(
+                        slave_dev::dev_name
+                            .like(pattern.clone())
+                            .escape('\\')
+                            .or(slave_dev::dev_type.like(pattern.clone()).escape('\\')),
+                    );
+                }
+                query
+            };
+            let total = matching().count().get_result::<i64>(conn)?;
+            let query = match (sort.field(), sort.descending()) {
+                ("name", false) => matching().order(slave_dev::dev_name.asc()),
+                ("name", true) => matching().order(slave_dev::dev_name.desc()),
+                (_, false) => matching().order(slave_dev::dev_id.asc()),
+                (_, true) => matching().order(slave_dev::dev_id.desc()),
+            };
+            let devices = query
+                .then_order_by(slave_dev::dev_id.asc())
+                .limit(limit)
+                .offset(offset)
+                .load(conn)?;
+            Ok((devices, total))
+        })
+        .await
     }
+}
 
-    let di = ModbusRegisterDefinition { start: 0, size: 0 };
-    let ai = ModbusRegisterDefinition { start: 0, size: 0 };
-    let ao = ModbusRegisterDefinition { start: 0, size: 0 };
+// The registers of the device in the table. Modbus addresses are 16 bits,
+// so anything outside of that is not a register that we can describe.
+fn registers(
+    start: i32,
+    size: i32,
+) -> std::result::Result<ModbusRegisterDefinition, TryFromIntError> {
+    Ok(ModbusRegisterDefinition {
+        start: u16::try_from(start)?,
+        size: u16::try_from(size)?,
+    })
+}
 
-    let device = ModbusDevice {
-        id: String::from("id"),
-        name: String::from("world"),
-        di: di,
-        ai: ai,
-        ao: ao,
-    };
+impl TryFrom<SlaveDev> for ModbusDevice {
+    type Error = TryFromIntError;
 
-    let mut vec = Vec::new();
-    vec.push(device);
+    fn try_from(dev: SlaveDev) -> std::result::Result<Self, Self::Error> {
+        Ok(ModbusDevice {
+            id: dev.dev_id.map(|id| id.to_string()).unwrap_or_default(),
+            name: dev.dev_name,
+            di: registers(dev.di_start, dev.di_size)?,
+            ai: registers(dev.ir_start, dev.ir_size + dev.hr_read_size)?,
+            ao: registers(dev.hr_write_start, dev.hr_write_size)?,
+        })
+    }
+}
 
-    Ok(Json(vec))
+#[get("/devices?<list..>")]
+async fn devices(db: DbConn, list: ListQuery) -> PageResponse<ModbusDevice> {
+    let sort = list.sort(&["id", "name"], "id")?;
+    let offset = list.offset()?;
+    SlaveDev::list(db, sort, list.pattern(), list.limit(), offset)
+        .await
+        .map_err(|e| Error::from(e).to_response(Status::ImATeapot))
+        .and_then(|(devices, total)| {
+            let devices = devices
+                .into_iter()
+                .map(ModbusDevice::try_from)
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|_| {
+                    Error::response(
+                        Status::InternalServerError,
+                        "invalid_device",
+                        "A device has registers that are not Modbus addresses",
+                    )
+                })?;
+            Ok(Page::new(devices, offset, total))
+        })
 }
 
 #[post("/devices", format = "json", data = "<message>")]
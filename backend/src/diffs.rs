@@ -0,0 +1,296 @@
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::fs;
+use rocket::{Build, State};
+use similar::{ChangeTag, TextDiff};
+
+use super::program_files::ProgramFiles;
+use super::programs::{program_not_found, Program};
+use super::response::*;
+use super::revisions::{revision_not_found, ProgramRevision};
+use super::sqlite::DbConn;
+
+// The number of unchanged lines around each change.
+const CONTEXT_LINES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "lowercase")]
+pub enum DiffKind {
+    Context,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DiffLine {
+    kind: DiffKind,
+    // The line without the line ending.
+    text: String,
+}
+
+// A group of changes that are close together, the same as a hunk of a
+// unified diff. The line numbers start at 1.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Hunk {
+    #[serde(rename = "fromStart")]
+    from_start: usize,
+    #[serde(rename = "fromLines")]
+    from_lines: usize,
+    #[serde(rename = "toStart")]
+    to_start: usize,
+    #[serde(rename = "toLines")]
+    to_lines: usize,
+    lines: Vec<DiffLine>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ProgramDiff {
+    from: ProgramRevision,
+    to: ProgramRevision,
+    // The changes as a unified diff.
+    unified: String,
+    hunks: Vec<Hunk>,
+}
+
+// Which revisions to compare. Without a revision, we compare the newest
+// revision, or the revision before to for the same program.
+#[derive(FromForm)]
+pub struct DiffQuery {
+    from: Option<i32>,
+    to: Option<i32>,
+    // Compare with a revision of another program.
+    #[field(name = "toProgram")]
+    to_program: Option<i32>,
+}
+
+// Compares the text line by line.
+fn diff(from_name: &str, from: &str, to_name: &str, to: &str) -> (String, Vec<Hunk>) {
+    let diff = TextDiff::from_lines(from, to);
+    let unified = diff
+        .unified_diff()
+        .context_radius(CONTEXT_LINES)
+        .header(from_name, to_name)
+        .to_string();
+
+    let hunks = diff
+        .grouped_ops(CONTEXT_LINES)
+        .iter()
+        .map(|ops| {
+            let (first, last) = (&ops[0], &ops[ops.len() - 1]);
+            let lines = ops
+                .iter()
+                .flat_map(|op| diff.iter_changes(op))
+                .map(|change| DiffLine {
+                    kind: match change.tag() {
+                        ChangeTag::Equal => DiffKind::Context,
+                        ChangeTag::Insert => DiffKind::Added,
+                        ChangeTag::Delete => DiffKind::Removed,
+                    },
+                    text: change.value().trim_end_matches(['\r', '\n']).to_string(),
+                })
+                .collect();
+            Hunk {
+                from_start: first.old_range().start + 1,
+                from_lines: last.old_range().end - first.old_range().start,
+                to_start: first.new_range().start + 1,
+                to_lines: last.new_range().end - first.new_range().start,
+                lines,
+            }
+        })
+        .collect();
+    (unified, hunks)
+}
+
+// Finds the revision of the program, or the newest revision.
+async fn find_revision(
+    db: &DbConn,
+    id: i32,
+    revision: Option<i32>,
+) -> Result<ProgramRevision, Custom<Json<Error>>> {
+    Program::get(db, id).await.map_err(program_not_found)?;
+    db.run(move |conn| match revision {
+        Some(revision) => ProgramRevision::get(conn, id, revision),
+        None => ProgramRevision::latest(conn, id),
+    })
+    .await
+    .map_err(revision_not_found)
+}
+
+async fn read_source(
+    files: &ProgramFiles,
+    revision: &ProgramRevision,
+) -> Result<String, Custom<Json<Error>>> {
+    let missing = || {
+        Error::response(
+            Status::NotFound,
+            "not_found",
+            "The source of the program is missing",
+        )
+    };
+    let path = files.path(revision.file()).map_err(|_| missing())?;
+    let data = fs::read(path).await.map_err(|_| missing())?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+// Shows what changed between two revisions of the program, or between
+// the program and another program.
+#[get("/programs/<id>/diff?<query..>")]
+async fn get_diff(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    id: i32,
+    query: DiffQuery,
+) -> OkResponse<ProgramDiff> {
+    let to = find_revision(&db, query.to_program.unwrap_or(id), query.to).await?;
+    let from_revision = match (query.from, query.to_program) {
+        (Some(from), _) => Some(from),
+        (None, None) => Some(to.revision() - 1),
+        (None, Some(_)) => None,
+    };
+    let from = find_revision(&db, id, from_revision).await?;
+
+    let (unified, hunks) = diff(
+        from.file(),
+        &read_source(files, &from).await?,
+        to.file(),
+        &read_source(files, &to).await?,
+    );
+    Ok(Json(ProgramDiff {
+        from,
+        to,
+        unified,
+        hunks,
+    }))
+}
+
+pub fn mount(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
+    rocket.mount("/", routes![get_diff])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::runtime::SimulatedRuntime;
+    use super::super::test_client;
+    use super::*;
+    use rocket::http::ContentType;
+    use rocket::local::blocking::Client;
+    use rocket::serde::json::Value;
+
+    fn line(kind: DiffKind, text: &str) -> DiffLine {
+        DiffLine {
+            kind,
+            text: String::from(text),
+        }
+    }
+
+    #[test]
+    fn test_diff_unified_and_hunks() {
+        let from = "PROGRAM blink\nVAR\nEND_VAR\nlamp := NOT lamp;\nEND_PROGRAM\n";
+        let to = "PROGRAM blink\nVAR\nEND_VAR\nlamp := TRUE;\nEND_PROGRAM\n";
+        let (unified, hunks) = diff("a.st", from, "b.st", to);
+        assert_eq!(
+            unified,
+            "--- a.st\n+++ b.st\n@@ -1,5 +1,5 @@\n PROGRAM blink\n VAR\n END_VAR\n\
+             -lamp := NOT lamp;\n+lamp := TRUE;\n END_PROGRAM\n"
+        );
+        assert_eq!(
+            hunks,
+            vec![Hunk {
+                from_start: 1,
+                from_lines: 5,
+                to_start: 1,
+                to_lines: 5,
+                lines: vec![
+                    line(DiffKind::Context, "PROGRAM blink"),
+                    line(DiffKind::Context, "VAR"),
+                    line(DiffKind::Context, "END_VAR"),
+                    line(DiffKind::Removed, "lamp := NOT lamp;"),
+                    line(DiffKind::Added, "lamp := TRUE;"),
+                    line(DiffKind::Context, "END_PROGRAM"),
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_same_text_has_no_hunks() {
+        let (unified, hunks) = diff("a.st", "PROGRAM blink\n", "b.st", "PROGRAM blink\n");
+        assert_eq!(unified, "");
+        assert!(hunks.is_empty());
+    }
+
+    // Creates a client with two programs, the first with two revisions.
+    fn client() -> Client {
+        let (client, _plc) = test_client(&SimulatedRuntime::new());
+        for body in [
+            r#"{"name":"Blink","description":"","fileName":"blink.st","data":"PROGRAM blink\nEND_PROGRAM\n"}"#,
+            r#"{"name":"Flash","description":"","fileName":"flash.st","data":"PROGRAM flash\nEND_PROGRAM\n"}"#,
+        ] {
+            let status = client
+                .post("/programs")
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .status();
+            assert_eq!(status, Status::Created);
+        }
+        let status = client
+            .post("/programs/1/revisions?fileName=blink.st")
+            .header(ContentType::Plain)
+            .body("PROGRAM blink2\nEND_PROGRAM\n")
+            .dispatch()
+            .status();
+        assert_eq!(status, Status::Created);
+        client
+    }
+
+    #[test]
+    fn diff_revisions() {
+        let client = client();
+        let response = client.get("/programs/1/diff?from=1&to=2").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let diff = response.into_json::<Value>().unwrap();
+        assert_eq!(diff["from"]["revision"], 1);
+        assert_eq!(diff["to"]["revision"], 2);
+        let unified = diff["unified"].as_str().unwrap();
+        assert!(unified.contains("-PROGRAM blink\n+PROGRAM blink2\n"));
+        assert_eq!(diff["hunks"][0]["lines"][0]["kind"], "removed");
+        assert_eq!(diff["hunks"][0]["lines"][1]["text"], "PROGRAM blink2");
+
+        // Without revisions we compare the newest with the one before.
+        let response = client.get("/programs/1/diff").dispatch();
+        assert_eq!(response.into_json::<Value>().unwrap(), diff);
+    }
+
+    #[test]
+    fn diff_programs() {
+        let client = client();
+        let response = client.get("/programs/2/diff?toProgram=1&to=2").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let diff = response.into_json::<Value>().unwrap();
+        assert_eq!(diff["from"]["programId"], 2);
+        assert_eq!(diff["to"]["programId"], 1);
+        assert!(diff["unified"]
+            .as_str()
+            .unwrap()
+            .contains("-PROGRAM flash\n+PROGRAM blink2\n"));
+    }
+
+    #[test]
+    fn diff_missing_revision_is_not_found() {
+        let client = client();
+        let response = client.get("/programs/1/diff?from=7").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.get("/programs/1/diff?toProgram=7").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        // The first revision has nothing before it.
+        let response = client.get("/programs/2/diff").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
@@ -7,6 +7,7 @@ use std::time::Duration;
 
 use super::plc;
 use super::response::*;
+use super::users::Operator;
 
 const NUM_DRIVERS: usize = 12;
 
@@ -100,11 +101,12 @@ fn drivers() -> Json<Drivers> {
 #[post("/drivers?<selected>")]
 async fn select_driver(
     plc: &State<plc::SharedPlcStateMachine>,
+    operator: Operator,
     selected: String,
 ) -> AcceptedResponse {
     let event = plc::PlcEvent::SetHardware(selected);
 
-    plc.transition(event, Duration::from_secs(2))
+    plc.transition_as(event, Duration::from_secs(2), &operator.0)
         .await
         .map(|state| Ok(Accepted::<()>(None)))
         .map_err(|e| Error::response(Status::ImATeapot, "", ""))?
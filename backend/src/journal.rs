@@ -0,0 +1,281 @@
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::sync::mpsc;
+use rocket::Build;
+
+use super::plc;
+use super::response::*;
+use super::schema::state_journal;
+use super::sqlite::{BackgroundDb, DbConn};
+use super::state::AppState;
+
+use self::diesel::prelude::*;
+use rocket_sync_db_pools::diesel;
+
+// The number of entries in a page if the request doesn't say.
+const DEFAULT_PAGE_SIZE: i64 = 100;
+// The most entries we return in a page.
+const MAX_PAGE_SIZE: i64 = 1000;
+
+// A record of an event that the PLC state machine handled. We keep
+// these so that we can reconstruct what happened to the PLC, for
+// example overnight while nobody was watching.
+#[derive(Debug, Clone, Serialize, Queryable, Insertable)]
+#[serde(crate = "rocket::serde")]
+#[table_name = "state_journal"]
+pub struct JournalEntry {
+    #[serde(rename = "id")]
+    journal_id: Option<i32>,
+    // Seconds since the epoch.
+    #[serde(rename = "createdAt")]
+    date_created: i64,
+    // The user that sent the event.
+    user: String,
+    event: String,
+    #[serde(rename = "priorState")]
+    prior_state: String,
+    #[serde(rename = "resultingState")]
+    resulting_state: String,
+    // Why the event failed, if it did.
+    error: Option<String>,
+}
+
+impl From<plc::Transition> for JournalEntry {
+    fn from(transition: plc::Transition) -> Self {
+        // If the event failed, then the PLC remains in the prior state.
+        let (resulting_state, error) = match transition.result {
+            Ok(state) => (state, None),
+            Err(e) => (transition.prior, Some(e.to_string())),
+        };
+        JournalEntry {
+            journal_id: None,
+            date_created: transition.at.timestamp(),
+            user: transition.user,
+            event: transition.event,
+            prior_state: format!("{:?}", AppState::from(transition.prior)),
+            resulting_state: format!("{:?}", AppState::from(resulting_state)),
+            error,
+        }
+    }
+}
+
+// A page of the journal along with the total number of entries that
+// match the filter.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct JournalPage {
+    entries: Vec<JournalEntry>,
+    total: i64,
+    offset: i64,
+    limit: i64,
+}
+
+// Restricts the journal to the entries created in the time range. Both
+// ends are seconds since the epoch and are inclusive.
+#[derive(Debug, Clone, Copy, Default)]
+struct TimeRange {
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+impl TimeRange {
+    fn filter<'a>(
+        &self,
+        mut query: state_journal::BoxedQuery<'a, diesel::sqlite::Sqlite>,
+    ) -> state_journal::BoxedQuery<'a, diesel::sqlite::Sqlite> {
+        if let Some(from) = self.from {
+            query = query.filter(state_journal::date_created.ge(from));
+        }
+        if let Some(to) = self.to {
+            query = query.filter(state_journal::date_created.le(to));
+        }
+        query
+    }
+}
+
+impl JournalEntry {
+    fn insert_all(conn: &SqliteConnection, entries: &[JournalEntry]) -> QueryResult<usize> {
+        diesel::insert_into(state_journal::table)
+            .values(entries)
+            .execute(conn)
+    }
+
+    // Gets a page of the journal with the newest entries first.
+    async fn page(
+        db: DbConn,
+        range: TimeRange,
+        offset: i64,
+        limit: i64,
+    ) -> Result<JournalPage, diesel::result::Error> {
+        db.run(move |conn| {
+            let total = range
+                .filter(state_journal::table.into_boxed())
+                .count()
+                .get_result(conn)?;
+            let entries = range
+                .filter(state_journal::table.into_boxed())
+                .order(state_journal::journal_id.desc())
+                .offset(offset)
+                .limit(limit)
+                .load(conn)?;
+            Ok(JournalPage {
+                entries,
+                total,
+                offset,
+                limit,
+            })
+        })
+        .await
+    }
+}
+
+#[get("/state/history?<from>&<to>&<offset>&<limit>")]
+async fn get_history(
+    db: DbConn,
+    from: Option<i64>,
+    to: Option<i64>,
+    offset: Option<i64>,
+    limit: Option<i64>,
+) -> OkResponse<JournalPage> {
+    let offset = offset.unwrap_or(0);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if offset < 0 || !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(Error::response(
+            Status::BadRequest,
+            "invalid_page",
+            "The offset must not be negative and the limit must be between 1 and 1000",
+        ));
+    }
+
+    JournalEntry::page(db, TimeRange { from, to }, offset, limit)
+        .await
+        .map(|page| Ok(Json(page)))
+        .map_err(|e| Error::from(e).to_response(Status::InternalServerError))?
+}
+
+// Writes each transition of the PLC to the database once we have
+// launched (and so have a database).
+pub fn stage() -> AdHoc {
+    AdHoc::on_liftoff("State Journal", |rocket| {
+        Box::pin(async move {
+            let plc = match rocket.state::<plc::SharedPlcStateMachine>() {
+                Some(plc) => plc,
+                None => return,
+            };
+            let db = match BackgroundDb::new(rocket) {
+                Ok(db) => db,
+                Err(e) => {
+                    error!("Unable to write to the state journal: {}", e);
+                    return;
+                }
+            };
+
+            let (sender, mut receiver) = mpsc::unbounded_channel::<plc::Transition>();
+            plc.set_journal(sender);
+
+            rocket::tokio::spawn(async move {
+                while let Some(transition) = receiver.recv().await {
+                    // Write everything that is waiting together.
+                    let mut entries = vec![JournalEntry::from(transition)];
+                    while let Ok(transition) = receiver.try_recv() {
+                        entries.push(JournalEntry::from(transition));
+                    }
+                    if let Err(e) = db
+                        .run(move |conn| JournalEntry::insert_all(conn, &entries))
+                        .await
+                    {
+                        error!("Unable to write to the state journal: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
+
+pub fn mount(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
+    rocket.mount("/", routes![get_history])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::runtime::SimulatedRuntime;
+    use super::super::{test_client, test_plc, test_rocket};
+    use super::*;
+    use rocket::local::blocking::Client;
+    use std::time::{Duration, Instant};
+
+    // The journal is written in the background, so wait for the entries.
+    fn history(client: &Client, query: &str, total: usize) -> String {
+        let end = Instant::now() + Duration::from_secs(5);
+        loop {
+            let body = client
+                .get(format!("/state/history{}", query))
+                .dispatch()
+                .into_string()
+                .unwrap();
+            if body.contains(&format!(r#""total":{}"#, total)) || Instant::now() > end {
+                return body;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_history_records_transitions() {
+        let plc = test_plc(&SimulatedRuntime::new());
+        // Wait until we have discovered the PLC so that we know
+        // discovery happened before there was a journal. We still record
+        // it so that the journal starts with the state when we started.
+        while plc.snapshot().state != plc::PlcState::Stopped {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let client = Client::tracked(test_rocket(plc.clone())).expect("valid rocket instance");
+
+        rocket::async_test(async {
+            plc.run_as(plc::PlcEvent::Run(String::from("prog.st")), "operator1")
+                .await
+                .unwrap();
+            // Polling without a change is not recorded.
+            assert!(plc.run_as(plc::PlcEvent::NoOp, "operator1").await.is_ok());
+            assert!(plc
+                .run_as(plc::PlcEvent::Run(String::from("prog.st")), "operator2")
+                .await
+                .is_err());
+            plc.run_as(plc::PlcEvent::Stop, "operator3").await.unwrap();
+        });
+
+        let body = history(&client, "", 4);
+        assert!(body.contains(r#""total":4"#));
+        assert!(body.contains(r#""user":"system","event":"Event: NoOp","priorState":"INITIALIZING","resultingState":"STOPPED","error":null"#));
+        assert!(body.contains(r#""user":"operator1","event":"Event: Run prog.st","priorState":"STOPPED","resultingState":"RUNNING","error":null"#));
+        assert!(body.contains(r#""user":"operator2","event":"Event: Run prog.st","priorState":"RUNNING","resultingState":"RUNNING","error":"Invalid transition"#));
+        assert!(body.contains(r#""user":"operator3","event":"Event: Stop","priorState":"RUNNING","resultingState":"STOPPED""#));
+
+        // The newest entry is first.
+        let body = history(&client, "?offset=0&limit=1", 4);
+        assert!(body.contains(r#""user":"operator3""#));
+        assert!(!body.contains(r#""user":"operator2""#));
+
+        let body = history(&client, "?offset=2&limit=1", 4);
+        assert!(body.contains(r#""user":"operator1""#));
+
+        // Discovering the PLC is the oldest entry.
+        let body = history(&client, "?offset=3&limit=1", 4);
+        assert!(body.contains(r#""user":"system""#));
+
+        // Nothing happened in the distant past.
+        let body = history(&client, "?from=0&to=1000", 0);
+        assert!(body.contains(r#""total":0,"#));
+    }
+
+    #[test]
+    fn test_history_rejects_invalid_page() {
+        let (client, _plc) = test_client(&SimulatedRuntime::new());
+        let response = client.get("/state/history?limit=0").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client.get("/state/history?offset=-1").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,176 @@
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::Serialize;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use rocket::Shutdown;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+// The number of lines we keep for each log so that a client that
+// subscribes late can see what happened recently.
+const LOG_HISTORY_LINES: usize = 1000;
+
+// The stream that a line of output was written to.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LogLine {
+    stream: Stream,
+    line: String,
+}
+
+// Something that receives the output from a process as it runs.
+pub trait LogSink: Clone + Send + Sync + 'static {
+    // Adds a line of output.
+    fn append(&self, stream: Stream, line: String);
+
+    // Records that the process exited. The exit code is None if the
+    // process was terminated by a signal (or never ran).
+    fn finish(&self, exit_code: Option<i32>);
+}
+
+struct History {
+    lines: VecDeque<LogLine>,
+    capacity: usize,
+}
+
+// A bounded buffer of log lines that also pushes each new line to
+// anyone that is subscribed.
+#[derive(Clone)]
+pub struct LogBuffer {
+    history: Arc<Mutex<History>>,
+    sender: Sender<LogLine>,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        LogBuffer {
+            history: Arc::new(Mutex::new(History {
+                lines: VecDeque::with_capacity(capacity),
+                capacity,
+            })),
+            sender,
+        }
+    }
+
+    pub fn push(&self, stream: Stream, line: String) {
+        let line = LogLine { stream, line };
+
+        // We send while holding the lock so that a subscriber sees each
+        // line exactly once, either in the history or from the channel.
+        let mut history = self.history.lock().unwrap();
+        if history.lines.len() == history.capacity {
+            history.lines.pop_front();
+        }
+        history.lines.push_back(line.clone());
+
+        // It is not an error if nobody is listening.
+        let _ = self.sender.send(line);
+    }
+
+    // Returns the recent history and a receiver for any lines after that.
+    pub fn subscribe(&self) -> (Vec<LogLine>, Receiver<LogLine>) {
+        let history = self.history.lock().unwrap();
+        let lines = history.lines.iter().cloned().collect();
+        (lines, self.sender.subscribe())
+    }
+
+    // Returns the recent history as text.
+    pub fn text(&self) -> String {
+        let history = self.history.lock().unwrap();
+        let lines: Vec<&str> = history.lines.iter().map(|l| l.line.as_str()).collect();
+        lines.join("\n")
+    }
+}
+
+impl LogSink for LogBuffer {
+    fn append(&self, stream: Stream, line: String) {
+        self.push(stream, line);
+    }
+
+    fn finish(&self, exit_code: Option<i32>) {
+        let line = match exit_code {
+            Some(code) => format!("Process exited with code {}", code),
+            None => String::from("Process terminated"),
+        };
+        self.push(Stream::Stderr, line);
+    }
+}
+
+// The logs that we can stream to clients.
+#[derive(Clone)]
+pub struct LogStreams {
+    // Output of the running PLC.
+    pub runtime: LogBuffer,
+    // Output of the compiler for all compilations.
+    pub compiler: LogBuffer,
+}
+
+impl LogStreams {
+    pub fn new() -> Self {
+        LogStreams {
+            runtime: LogBuffer::new(LOG_HISTORY_LINES),
+            compiler: LogBuffer::new(LOG_HISTORY_LINES),
+        }
+    }
+}
+
+// Creates an event stream that first sends the history and then
+// each line as it is written.
+pub fn event_stream(buffer: &LogBuffer, mut shutdown: Shutdown) -> EventStream![] {
+    let (history, mut receiver) = buffer.subscribe();
+    EventStream! {
+        for line in history {
+            yield Event::json(&line);
+        }
+
+        loop {
+            let line = select! {
+                msg = receiver.recv() => match msg {
+                    Ok(line) => line,
+                    Err(RecvError::Closed) => break,
+                    // A slow client missed some lines, but it can keep going.
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+
+            yield Event::json(&line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_keeps_recent_history() {
+        let buffer = LogBuffer::new(2);
+        buffer.push(Stream::Stdout, String::from("one"));
+        buffer.push(Stream::Stdout, String::from("two"));
+        buffer.push(Stream::Stderr, String::from("three"));
+        assert_eq!(buffer.text(), "two\nthree");
+    }
+
+    #[test]
+    fn test_subscriber_receives_history_then_new_lines() {
+        let buffer = LogBuffer::new(10);
+        buffer.push(Stream::Stdout, String::from("before"));
+
+        let (history, mut receiver) = buffer.subscribe();
+        buffer.push(Stream::Stdout, String::from("after"));
+
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].line, "before");
+        assert_eq!(receiver.try_recv().expect("line").line, "after");
+    }
+}
@@ -9,19 +9,31 @@ extern crate diesel;
 extern crate matches;
 
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::{Header, Status};
+use rocket::http::{Header, Method, Status};
 use rocket::{Build, Request, Response, Rocket};
 use std::time::Duration;
 
+mod audit;
+mod compilations;
+mod deployments;
 mod devices;
+mod diffs;
 mod hardware;
+mod journal;
+mod logs;
+mod pagination;
 mod plc;
+mod plcopen;
+mod program_files;
 mod programs;
 mod response;
+mod revisions;
+mod runtime;
 mod schema;
 mod settings;
 mod sqlite;
 mod state;
+mod structured_text;
 mod users;
 mod variables;
 
@@ -36,8 +48,12 @@ impl Fairing for CORS {
         }
     }
 
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_status(Status::Ok);
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        // We don't have routes for preflight requests, so we answer them here.
+        // Other responses keep their status so that clients see errors.
+        if request.method() == Method::Options {
+            response.set_status(Status::Ok);
+        }
         response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
         response.set_header(Header::new(
             "Access-Control-Allow-Methods",
@@ -50,37 +66,52 @@ impl Fairing for CORS {
 
 #[launch]
 pub fn launch() -> _ {
-    // Create the shared state. We need this protected a mutex
-    // because both requests and our background process will need
-    // to access the state.
-    let state = plc::SharedPlcStateMachine::new();
-
-    let clone = state.clone();
-    tokio::spawn(async move {
-        // Every one second we will recheck the state by passing the
-        // NoOp event.
-        let mut interval = tokio::time::interval(Duration::from_secs(5));
-        loop {
-            {
-                let mut x = clone.sm.write().unwrap();
-                x.run(plc::PlcEvent::NoOp);
-            }
-            interval.tick().await;
-        }
-    });
+    // Create the shared state. The state machine runs in the background
+    // and both requests and the state machine itself send it events.
+    let grace_period = rocket::Config::figment()
+        .extract_inner::<u64>("plc_stop_grace_period")
+        .map(Duration::from_secs)
+        .unwrap_or(plc::DEFAULT_STOP_GRACE_PERIOD);
+    let logs = logs::LogStreams::new();
+    // The configuration is validated when we ignite, so if it is
+    // invalid, then we never use the default.
+    let config =
+        runtime::RuntimeConfig::from_figment(&rocket::Config::figment()).unwrap_or_default();
+    let runtime = runtime::ProcessRuntime::new(config, logs.runtime.clone());
+    let state = plc::SharedPlcStateMachine::new(Box::new(runtime), grace_period);
 
-    rocket(state)
+    // Only supervise the runtime if there is a policy for it.
+    if let Ok(config) =
+        rocket::Config::figment().extract_inner::<plc::SupervisionConfig>("plc_supervision")
+    {
+        state.set_supervision(plc::SupervisionPolicy::from(config));
+    }
+
+    rocket(state, logs).attach(runtime::stage())
 }
 
-pub fn rocket(state: plc::SharedPlcStateMachine) -> Rocket<Build> {
+pub fn rocket(state: plc::SharedPlcStateMachine, logs: logs::LogStreams) -> Rocket<Build> {
+    let compilations = compilations::Compilations::new(logs.compiler.clone());
     let mut rocket = rocket::build()
         .attach(CORS)
         .attach(sqlite::stage())
-        .manage(state);
+        .attach(journal::stage())
+        .attach(compilations::stage())
+        .attach(settings::stage())
+        .attach(program_files::stage())
+        .manage(state)
+        .manage(compilations)
+        .manage(deployments::Deployments::new())
+        .manage(logs);
 
+    rocket = audit::mount(rocket);
+    rocket = compilations::mount(rocket);
     rocket = devices::mount(rocket);
+    rocket = diffs::mount(rocket);
     rocket = hardware::mount(rocket);
+    rocket = journal::mount(rocket);
     rocket = programs::mount(rocket);
+    rocket = revisions::mount(rocket);
     rocket = settings::mount(rocket);
     rocket = state::mount(rocket);
     rocket = users::mount(rocket);
@@ -88,3 +119,89 @@ pub fn rocket(state: plc::SharedPlcStateMachine) -> Rocket<Build> {
 
     return rocket;
 }
+
+// Creates an instance for testing with its own empty database and
+// programs directory so that tests don't interfere with each other (or
+// with a real installation).
+#[cfg(test)]
+pub fn test_rocket(state: plc::SharedPlcStateMachine) -> Rocket<Build> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let name = format!(
+        "openplc-test-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    );
+    let path = std::env::temp_dir().join(format!("{}.db", name));
+    let _ = std::fs::remove_file(&path);
+    let programs_dir = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&programs_dir);
+
+    let figment = rocket::Config::figment()
+        .merge((
+            "databases.sqlite_logs.url",
+            path.to_string_lossy().to_string(),
+        ))
+        .merge(("programs_dir", programs_dir.to_string_lossy().to_string()));
+    rocket(state, logs::LogStreams::new()).configure(figment)
+}
+
+// The PLC state machine for a simulated runtime. The runtime is shared, so
+// tests can still make the simulated PLC do things.
+#[cfg(test)]
+pub fn test_plc(runtime: &runtime::SimulatedRuntime) -> plc::SharedPlcStateMachine {
+    plc::SharedPlcStateMachine::new(Box::new(runtime.clone()), plc::DEFAULT_STOP_GRACE_PERIOD)
+}
+
+// A client for an instance from test_rocket with the simulated runtime, and
+// the PLC state machine of the instance.
+#[cfg(test)]
+pub fn test_client(
+    runtime: &runtime::SimulatedRuntime,
+) -> (rocket::local::blocking::Client, plc::SharedPlcStateMachine) {
+    let plc = test_plc(runtime);
+    let client = rocket::local::blocking::Client::tracked(test_rocket(plc.clone()))
+        .expect("valid rocket instance");
+    (client, plc)
+}
+
+// Same as test_client for tests that are async.
+#[cfg(test)]
+pub async fn test_async_client(
+    runtime: &runtime::SimulatedRuntime,
+) -> (
+    rocket::local::asynchronous::Client,
+    plc::SharedPlcStateMachine,
+) {
+    let plc = test_plc(runtime);
+    let client = rocket::local::asynchronous::Client::tracked(test_rocket(plc.clone()))
+        .await
+        .expect("valid rocket instance");
+    (client, plc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::runtime::SimulatedRuntime;
+    use super::*;
+
+    #[test]
+    fn cors_answers_preflight_and_keeps_errors() {
+        let (client, _plc) = test_client(&SimulatedRuntime::new());
+
+        let response = client.options("/programs").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("Access-Control-Allow-Origin"),
+            Some("*")
+        );
+
+        let response = client.get("/programs/100").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(
+            response.headers().get_one("Access-Control-Allow-Origin"),
+            Some("*")
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use rocket::http::{Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, status::Custom, Responder};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+
+use super::response::*;
+
+// The limit when the client doesn't ask for one, so that clients that
+// don't follow the next links still get the whole list. SQLite reads a
+// negative limit as no limit.
+const UNLIMITED: i64 = -1;
+// The most items that we return in a page.
+pub const MAX_LIMIT: i64 = 500;
+
+// The query parameters for listing resources a page at a time:
+//
+// limit   the number of items in the page, or every item if not given
+// offset  the number of items to skip
+// cursor  where the page starts, from the next link of the page before
+// sort    the field to sort by, or -field to sort in descending order
+// q       text to search for
+#[derive(Debug, Default, FromForm)]
+pub struct ListQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+    cursor: Option<String>,
+    sort: Option<String>,
+    q: Option<String>,
+}
+
+// The field to sort a list by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sort {
+    field: &'static str,
+    descending: bool,
+}
+
+impl Sort {
+    pub fn field(&self) -> &'static str {
+        self.field
+    }
+
+    pub fn descending(&self) -> bool {
+        self.descending
+    }
+}
+
+impl ListQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .map(|limit| limit.clamp(1, MAX_LIMIT))
+            .unwrap_or(UNLIMITED)
+    }
+
+    // Where the page starts. The cursor is the offset of the page for
+    // now, but clients should only use the cursor that we give them.
+    pub fn offset(&self) -> Result<i64, Custom<Json<Error>>> {
+        let offset = match &self.cursor {
+            Some(cursor) => cursor.parse::<i64>().map_err(|_| {
+                Error::response(
+                    Status::BadRequest,
+                    "invalid_cursor",
+                    "The cursor is not from a list of this resource",
+                )
+            })?,
+            None => self.offset.unwrap_or(0),
+        };
+        Ok(offset.max(0))
+    }
+
+    // Finds the field to sort by from the fields that the resource can be
+    // sorted by, or the default if the client didn't ask for one.
+    pub fn sort(
+        &self,
+        fields: &[&'static str],
+        default: &str,
+    ) -> Result<Sort, Custom<Json<Error>>> {
+        let sort = self.sort.as_deref().unwrap_or(default);
+        let (name, descending) = match sort.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (sort, false),
+        };
+        fields
+            .iter()
+            .find(|field| **field == name)
+            .map(|field| Sort { field, descending })
+            .ok_or_else(|| {
+                Error::response(
+                    Status::BadRequest,
+                    "invalid_sort",
+                    "The list cannot be sorted by that field",
+                )
+            })
+    }
+
+    // The pattern to match the search text anywhere in a field with LIKE,
+    // where \ escapes the wildcards.
+    pub fn pattern(&self) -> Option<String> {
+        let q = self.q.as_deref().map(str::trim).filter(|q| !q.is_empty())?;
+        let escaped = q
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        Some(format!("%{}%", escaped))
+    }
+}
+
+// A page of a list. The body is the items, the same as when we returned
+// the whole list, so the total is in the X-Total-Count header and the link
+// to the next page is in the Link header.
+#[derive(Debug)]
+pub struct Page<T> {
+    items: Vec<T>,
+    offset: i64,
+    total: i64,
+}
+
+impl<T> Page<T> {
+    pub fn new(items: Vec<T>, offset: i64, total: i64) -> Self {
+        Page {
+            items,
+            offset,
+            total,
+        }
+    }
+
+    // Where the next page starts, if there is one.
+    fn next(&self) -> Option<i64> {
+        let next = self.offset + self.items.len() as i64;
+        (!self.items.is_empty() && next < self.total).then_some(next)
+    }
+}
+
+// The link to the same list starting at the cursor.
+fn next_link(request: &Request<'_>, cursor: i64) -> String {
+    let uri = request.uri();
+    let mut query = uri
+        .query()
+        .map(|query| {
+            query
+                .raw_segments()
+                .map(|segment| segment.as_str())
+                .filter(|segment| {
+                    let key = segment.split('=').next().unwrap_or_default();
+                    key != "cursor" && key != "offset"
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let cursor = format!("cursor={}", cursor);
+    query.push(&cursor);
+    format!("<{}?{}>; rel=\"next\"", uri.path(), query.join("&"))
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Page<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let next = self.next();
+        let total = self.total;
+        let mut response = Json(self.items).respond_to(request)?;
+        response.set_header(Header::new("X-Total-Count", total.to_string()));
+        if let Some(next) = next {
+            response.set_header(Header::new("Link", next_link(request, next)));
+        }
+        Ok(response)
+    }
+}
+
+pub type PageResponse<T> = std::result::Result<Page<T>, Custom<Json<Error>>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(sort: Option<&str>, q: Option<&str>) -> ListQuery {
+        ListQuery {
+            sort: sort.map(String::from),
+            q: q.map(String::from),
+            ..ListQuery::default()
+        }
+    }
+
+    #[test]
+    fn test_limit_is_clamped() {
+        assert_eq!(ListQuery::default().limit(), UNLIMITED);
+        let limit = |limit| {
+            ListQuery {
+                limit: Some(limit),
+                ..ListQuery::default()
+            }
+            .limit()
+        };
+        assert_eq!(limit(0), 1);
+        assert_eq!(limit(20), 20);
+        assert_eq!(limit(MAX_LIMIT + 1), MAX_LIMIT);
+    }
+
+    #[test]
+    fn test_cursor_takes_the_place_of_offset() {
+        let list = ListQuery {
+            offset: Some(5),
+            cursor: Some(String::from("10")),
+            ..ListQuery::default()
+        };
+        assert_eq!(list.offset().unwrap(), 10);
+        let list = ListQuery {
+            cursor: Some(String::from("abc")),
+            ..ListQuery::default()
+        };
+        assert_eq!(list.offset().unwrap_err().0, Status::BadRequest);
+    }
+
+    #[test]
+    fn test_sort() {
+        let fields = ["id", "name"];
+        assert_eq!(
+            query(None, None).sort(&fields, "id").unwrap(),
+            Sort {
+                field: "id",
+                descending: false
+            }
+        );
+        assert_eq!(
+            query(Some("-name"), None).sort(&fields, "id").unwrap(),
+            Sort {
+                field: "name",
+                descending: true
+            }
+        );
+        let error = query(Some("password"), None).sort(&fields, "id");
+        assert_eq!(error.unwrap_err().0, Status::BadRequest);
+    }
+
+    #[test]
+    fn test_pattern_escapes_wildcards() {
+        assert_eq!(query(None, None).pattern(), None);
+        assert_eq!(query(None, Some("  ")).pattern(), None);
+        assert_eq!(
+            query(None, Some("50%_off")).pattern(),
+            Some(String::from("%50\\%\\_off%"))
+        );
+    }
+
+    #[test]
+    fn test_next_page() {
+        assert_eq!(Page::new(vec![1, 2], 0, 5).next(), Some(2));
+        assert_eq!(Page::new(vec![3, 4], 2, 4).next(), None);
+        assert_eq!(Page::<i32>::new(vec![], 10, 4).next(), None);
+    }
+}
@@ -1,18 +1,25 @@
+use chrono::{DateTime, Utc};
+use rocket::serde::Deserialize;
+use rocket::tokio::select;
+use rocket::tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use rocket::tokio::sync::{oneshot, watch};
+use rocket::tokio::time::{self, MissedTickBehavior};
+use std::collections::VecDeque;
 use std::fmt;
-use std::process::Command;
-use std::process::Stdio;
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
-use sysinfo::{Pid, ProcessExt, System, SystemExt};
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command as TokioCommand;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sysinfo::Pid;
+
+use super::compilations::Compilation;
+use super::runtime::PlcRuntime;
 
 // The PLC is in one of the follow stated (where we start in
 // Initialize which can directly transition to any one of the
 // states.
 //
 // We model Stopping as state to respond to a user-based request
-// to stop a process.
+// to stop a process. The process is asked to stop and if it has not
+// exited after the grace period, then it is killed.
 //
 // +-----------+          +-----------+          +-----------+
 // |           |<---------|           |--------->|           |
@@ -26,53 +33,57 @@ use tokio::process::Command as TokioCommand;
 //       +--------------->| Stopping  |<---------------+
 //                        |           |
 //                        +-----------+
+//
+// If the PLC is supervised, then we restart the runtime when it exits
+// without being asked to stop. If it keeps crashing, then we give up
+// and the PLC is Faulted until the operator stops or runs it again.
 
-// Returns the command to start the PLC.
-fn plc_start_command() -> &'static str {
-    let command = if cfg!(unix) {
-        "openplc"
-    } else if cfg!(windows) {
-        "startplc.bat"
-    } else {
-        "unknown"
-    };
-    command
-}
+// How long we wait for a process to stop before killing it if the
+// grace period is not configured.
+pub const DEFAULT_STOP_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// How often we check whether the PLC state has changed on its own.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// How many transitions we keep until we have a journal.
+const MAX_UNJOURNALED: usize = 100;
+
+// The user that we record for events that the server sends on its own
+// (such as polling for the state).
+pub const SYSTEM_USER: &str = "system";
+
+// The user that we record for restarts after a crash.
+pub const SUPERVISOR_USER: &str = "supervisor";
 
-fn plc_process_name() -> &'static str {
-    let process = if cfg!(unix) {
-        "openplc"
-    } else if cfg!(windows) {
-        "openplc.exe"
-    } else {
-        "unknown"
-    };
-    process
+// The reasons that the PLC might fail to change state.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlcError {
+    // The event is not valid in the current state.
+    InvalidTransition(String),
+    // The PLC did not stop in time to handle the event.
+    Timeout,
+    // The runtime failed to carry out the event.
+    Runtime(String),
 }
 
-fn plc_compile_command() -> &'static str {
-    let command = if cfg!(unix) {
-        "compile"
-    } else if cfg!(windows) {
-        "compile.bat"
-    } else {
-        "unknown"
-    };
-    command
+impl fmt::Display for PlcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &*self {
+            PlcError::InvalidTransition(msg) => write!(f, "{}", msg),
+            PlcError::Timeout => write!(f, "Timed out waiting to stop"),
+            PlcError::Runtime(msg) => write!(f, "{}", msg),
+        }
+    }
 }
 
-fn plc_change_hardware_command() -> &'static str {
-    let command = if cfg!(unix) {
-        "change_hardware_layer.sh"
-    } else if cfg!(windows) {
-        "change_hardware_layer.bat"
-    } else {
-        "unknown"
-    };
-    command
+// Errors from the runtime are strings.
+impl From<String> for PlcError {
+    fn from(msg: String) -> Self {
+        PlcError::Runtime(msg)
+    }
 }
 
-type StateResult<T = PlcState, E = String> = std::result::Result<T, E>;
+pub type StateResult<T = PlcState, E = PlcError> = std::result::Result<T, E>;
 
 // The states of the PLC
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -85,6 +96,8 @@ pub enum PlcState {
     Stopping(Pid),
     Running(Pid),
     Compiling(Pid),
+    // The runtime crashed too many times so we stopped restarting it.
+    Faulted,
 }
 
 impl fmt::Display for PlcState {
@@ -95,6 +108,7 @@ impl fmt::Display for PlcState {
             PlcState::Stopping(pid) => write!(f, "State: Stopping {}", pid),
             PlcState::Running(pid) => write!(f, "State: Running {}", pid),
             PlcState::Compiling(pid) => write!(f, "State: Compiling {}", pid),
+            PlcState::Faulted => write!(f, "State: Faulted"),
         }
     }
 }
@@ -108,66 +122,199 @@ pub enum PlcEvent {
     // verifying the state. The NoOp event says "don't try to
     // change the state, but do verify that it hasn't changed."
     NoOp,
-    Compile(String),
+    // Compile the file, writing the compiler output into the log.
+    Compile(String, Compilation),
     SetHardware(String),
     Run(String),
     // TODO this should be a process ID
     Stop,
+    // Stop the compiler, but only if it is running the compilation
+    // with this job ID.
+    CancelCompile(u32),
 }
 
 impl fmt::Display for PlcEvent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &*self {
             PlcEvent::NoOp => write!(f, "Event: NoOp"),
-            PlcEvent::Compile(file) => write!(f, "Event: Compile {}", &file),
+            PlcEvent::Compile(file, _) => write!(f, "Event: Compile {}", &file),
             PlcEvent::SetHardware(name) => write!(f, "Event: Set Hardware {}", &name),
             PlcEvent::Run(file) => write!(f, "Event: Run {}", &file),
             PlcEvent::Stop => write!(f, "Event: Stop"),
+            PlcEvent::CancelCompile(job_id) => write!(f, "Event: Cancel compile {}", job_id),
         }
     }
 }
 
+// A record of an event that the state machine handled. We send these
+// to the journal so that we can reconstruct what happened to the PLC.
+#[derive(Debug, Clone)]
+pub struct Transition {
+    pub at: DateTime<Utc>,
+    // Who sent the event.
+    pub user: String,
+    pub event: String,
+    pub prior: PlcState,
+    pub result: StateResult,
+}
+
 impl PlcState {
-    pub fn next(self, event: PlcEvent) -> StateResult {
+    pub fn next(self, event: PlcEvent, runtime: &mut dyn PlcRuntime) -> StateResult {
         use self::PlcEvent::*;
         use self::PlcState::*;
 
         // Match the current state and the event. Many transitions are
         // only valid while stopped.
         match (self, event) {
-            (Initialize, NoOp) => discover(),
-            (Initialize, Stop) => discover(),
+            (Initialize, NoOp) => discover(runtime),
+            (Initialize, Stop) => discover(runtime),
 
             (Stopped, NoOp) => Ok(Stopped),
             (Stopped, Stop) => Ok(Stopped),
-            (Stopped, Compile(file)) => compile_program(file),
-            (Stopped, SetHardware(name)) => change_hardware(name),
-            (Stopped, Run(file)) => start_program(file),
+            (Stopped, Compile(file, log)) => compile_program(runtime, file, log),
+            (Stopped, SetHardware(name)) => change_hardware(runtime, name),
+            (Stopped, Run(file)) => start_program(runtime, file),
+
+            (Compiling(pid), NoOp) => is_proc_running(runtime, self, pid),
+            (Compiling(pid), Stop) => cancel_compile(runtime, pid),
 
-            (Compiling(pid), NoOp) => is_proc_running(pid),
-            (Compiling(pid), Stop) => stop_proc(pid),
+            (Running(pid), NoOp) => is_proc_running(runtime, self, pid),
+            (Running(pid), Stop) => stop_proc(runtime, pid),
 
-            (Running(pid), NoOp) => is_proc_running(pid),
-            (Running(pid), Stop) => stop_proc(pid),
+            (Stopping(pid), NoOp) => is_proc_running(runtime, self, pid),
+            (Stopping(pid), Stop) => is_proc_running(runtime, self, pid),
 
-            (Stopping(pid), NoOp) => is_proc_running(pid),
-            (Stopping(pid), Stop) => stop_proc(pid),
+            // Faulted behaves like stopped except that we stay faulted
+            // until the operator does something about it.
+            (Faulted, NoOp) => Ok(Faulted),
+            (Faulted, Stop) => Ok(Stopped),
+            (Faulted, Compile(file, log)) => compile_program(runtime, file, log),
+            (Faulted, SetHardware(name)) => change_hardware(runtime, name),
+            (Faulted, Run(file)) => start_program(runtime, file),
 
             // Catch-all for other transitions. These transitions
             // are not valid so we stay in our current state.
-            (state, event) => Err(format!(
+            (state, event) => Err(PlcError::InvalidTransition(format!(
                 "Invalid transition: event {} not valid in {}",
                 event, state
-            )),
+            ))),
+        }
+    }
+}
+
+// How we restart the runtime after it crashes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SupervisionPolicy {
+    // How long we wait before the first restart. We double the wait
+    // for each crash after that.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // We give up if the runtime crashes this many times within the window.
+    pub max_crashes: usize,
+    pub window: Duration,
+}
+
+// The supervision policy as it is configured (in seconds).
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SupervisionConfig {
+    initial_backoff: u64,
+    max_backoff: u64,
+    max_crashes: usize,
+    window: u64,
+}
+
+impl From<SupervisionConfig> for SupervisionPolicy {
+    fn from(config: SupervisionConfig) -> Self {
+        SupervisionPolicy {
+            initial_backoff: Duration::from_secs(config.initial_backoff),
+            max_backoff: Duration::from_secs(config.max_backoff),
+            max_crashes: config.max_crashes,
+            window: Duration::from_secs(config.window),
         }
     }
 }
 
+// Keeps track of the recent crashes so that we know when to restart.
+struct Supervisor {
+    policy: SupervisionPolicy,
+    crashes: VecDeque<Instant>,
+    // When we will next try to restart the runtime.
+    restart_at: Option<Instant>,
+}
+
+impl Supervisor {
+    fn new(policy: SupervisionPolicy) -> Self {
+        Supervisor {
+            policy,
+            crashes: VecDeque::new(),
+            restart_at: None,
+        }
+    }
+
+    // Records a crash and schedules the restart. Returns the number of
+    // crashes in the window if we should give up instead.
+    fn crashed(&mut self) -> Option<usize> {
+        let now = Instant::now();
+        while let Some(crash) = self.crashes.front() {
+            if now.duration_since(*crash) <= self.policy.window {
+                break;
+            }
+            self.crashes.pop_front();
+        }
+        self.crashes.push_back(now);
+
+        let count = self.crashes.len();
+        if count >= self.policy.max_crashes {
+            self.restart_at = None;
+            return Some(count);
+        }
+
+        let backoff = self
+            .policy
+            .initial_backoff
+            .checked_mul(1 << (count - 1).min(31))
+            .unwrap_or(self.policy.max_backoff)
+            .min(self.policy.max_backoff);
+        self.restart_at = Some(now + backoff);
+        None
+    }
+
+    fn restart_due(&self) -> bool {
+        matches!(self.restart_at, Some(at) if Instant::now() >= at)
+    }
+
+    // The operator took over, so we forget about the crashes.
+    fn reset(&mut self) {
+        self.crashes.clear();
+        self.restart_at = None;
+    }
+}
+
 // The PLC singleton instance. The PLC instance keeps track of what
 // is currently happening on the PLC (whether running, compiling or
 // nothing).
 pub struct PlcStateMachine {
     pub state: PlcState,
+    // The file of the program that was most recently compiled or run.
+    pub program: Option<String>,
+    // Why the PLC is faulted.
+    pub fault: Option<String>,
+    // The compilation that is in progress (if we are compiling).
+    compilation: Option<Compilation>,
+    runtime: Box<dyn PlcRuntime>,
+    // How long we give a process to exit after asking it to stop
+    // before we kill it.
+    grace_period: Duration,
+    // When we first asked the process to stop.
+    stop_requested: Option<Instant>,
+    // Where we send a record of each transition (if anywhere).
+    journal: Option<UnboundedSender<Transition>>,
+    // The transitions before we had a journal, such as discovering the
+    // PLC when we start up. We send these once we have a journal.
+    unjournaled: Vec<Transition>,
+    // Restarts the runtime if it crashes (if we supervise it).
+    supervisor: Option<Supervisor>,
 }
 
 impl PlcStateMachine {
@@ -175,21 +322,130 @@ impl PlcStateMachine {
     // beings in the initialize state (which implies unknown state).
     // As an example, the state is unknown because the PLC can run without
     // the web server.
-    fn new() -> Self {
+    fn new(runtime: Box<dyn PlcRuntime>, grace_period: Duration) -> Self {
         PlcStateMachine {
             state: PlcState::Initialize,
+            program: None,
+            fault: None,
+            compilation: None,
+            runtime,
+            grace_period,
+            stop_requested: None,
+            journal: None,
+            unjournaled: Vec::new(),
+            supervisor: None,
+        }
+    }
+
+    // Restarts the runtime according to the policy when it crashes.
+    fn set_supervision(&mut self, policy: SupervisionPolicy) {
+        self.supervisor = Some(Supervisor::new(policy));
+    }
+
+    // Sends a record of each transition to the journal, starting with the
+    // transitions that happened before we had a journal.
+    fn set_journal(&mut self, journal: UnboundedSender<Transition>) {
+        for transition in self.unjournaled.drain(..) {
+            let _ = journal.send(transition);
         }
+        self.journal = Some(journal);
     }
 
     // Receives events that can cause the PLD to transition to another state.
     // Events might originate from and event pump or based on a particular
     // request.
     pub fn run(&mut self, event: PlcEvent) -> StateResult {
-        println!("{}", event);
-        let result = self.state.next(event);
+        self.run_as(event, SYSTEM_USER)
+    }
+
+    // Same as run, but records that the user sent the event.
+    pub fn run_as(&mut self, event: PlcEvent, user: &str) -> StateResult {
+        // While polling, it may be time to restart after a crash. Any
+        // other event means that the operator has taken over.
+        let (event, user, restarting) = match event {
+            PlcEvent::NoOp => match self.due_restart() {
+                Some(file) => (PlcEvent::Run(file), SUPERVISOR_USER, true),
+                None => (PlcEvent::NoOp, user, false),
+            },
+            event => {
+                if let Some(supervisor) = self.supervisor.as_mut() {
+                    supervisor.reset();
+                }
+                (event, user, false)
+            }
+        };
+
+        let prior = self.state;
+        let description = event.to_string();
+        let polling = matches!(event, PlcEvent::NoOp);
+        let mut result = self.handle(event);
+
+        // Failing to restart counts as another crash.
+        if restarting {
+            if let Err(e) = &result {
+                let reason = format!("Unable to restart the PLC runtime: {}", e);
+                self.state = self.crashed(reason);
+                result = Ok(self.state);
+            }
+        }
+
+        // Polling happens constantly, so we only record a NoOp if
+        // something changed or went wrong.
+        if !polling || result != Ok(prior) {
+            self.record(user, description, prior, &result);
+        }
+        result
+    }
+
+    fn handle(&mut self, event: PlcEvent) -> StateResult {
+        let event = match event {
+            PlcEvent::CancelCompile(job_id) => self.cancel_compile(job_id)?,
+            event => event,
+        };
+        let program = match &event {
+            PlcEvent::Compile(file, _) | PlcEvent::Run(file) => Some(file.clone()),
+            _ => None,
+        };
+        let compilation = match &event {
+            PlcEvent::Compile(_, compilation) => Some(compilation.clone()),
+            _ => None,
+        };
+        let polling = matches!(event, PlcEvent::NoOp);
+        // Stopping the compiler means that the compilation is cancelled.
+        if let (PlcState::Compiling(_), PlcEvent::Stop) = (self.state, &event) {
+            if let Some(compilation) = &self.compilation {
+                compilation.cancel();
+            }
+        }
+        let prior = self.state;
+        let result = self
+            .state
+            .next(event, self.runtime.as_mut())
+            .and_then(|state| self.escalate_stop(state));
         match result {
             Ok(state) => {
-                self.state = state;
+                // The runtime crashed if it exited while we were only
+                // polling. A runtime that we asked to stop may exit right
+                // away, which is not a crash.
+                self.state = match (prior, state) {
+                    (PlcState::Running(pid), PlcState::Stopped) if polling => self.crashed(
+                        format!("The PLC runtime (process {}) exited unexpectedly", pid),
+                    ),
+                    _ => state,
+                };
+                if self.state != PlcState::Faulted {
+                    self.fault = None;
+                }
+                if program.is_some() {
+                    self.program = program;
+                }
+                match self.state {
+                    PlcState::Compiling(_) if compilation.is_some() => {
+                        self.compilation = compilation
+                    }
+                    PlcState::Compiling(_) | PlcState::Stopping(_) => {}
+                    _ => self.compilation = None,
+                }
                 return Ok(self.state);
             }
             Err(msg) => {
@@ -197,150 +453,275 @@ impl PlcStateMachine {
             }
         }
     }
-}
 
-fn discover() -> StateResult {
-    use self::PlcState::*;
+    // Cancelling a compilation is the same as stopping the compiler, but
+    // only if the compilation is the one that the compiler is running.
+    fn cancel_compile(&self, job_id: u32) -> StateResult<PlcEvent> {
+        match (self.state, &self.compilation) {
+            (PlcState::Compiling(_), Some(compilation)) if compilation.job_id() == job_id => {
+                Ok(PlcEvent::Stop)
+            }
+            _ => Err(PlcError::InvalidTransition(format!(
+                "Invalid transition: compilation {} is not running",
+                job_id
+            ))),
+        }
+    }
 
-    // Try to determine if openplc application is running.
-    let s = System::new_all();
-    for process in s.processes_by_exact_name(plc_process_name()) {
-        return Ok(Running(process.pid()));
+    // Returns the program to restart if a restart is due.
+    fn due_restart(&self) -> Option<String> {
+        match (&self.supervisor, self.state) {
+            (Some(supervisor), PlcState::Stopped) if supervisor.restart_due() => {
+                self.program.clone()
+            }
+            _ => None,
+        }
     }
 
-    Ok(Stopped)
-}
+    // Handles the runtime crashing and returns the state that we are now
+    // in. Without supervision, a crash simply means that we have stopped.
+    fn crashed(&mut self, reason: String) -> PlcState {
+        warn!("{}", reason);
+        let supervisor = match self.supervisor.as_mut() {
+            Some(supervisor) => supervisor,
+            None => return PlcState::Stopped,
+        };
 
-fn is_proc_running(pid: Pid) -> StateResult {
-    use self::PlcState::*;
-    let s = System::new_all();
-    if let Some(process) = s.process(pid) {
-        return Ok(Running(pid));
-    }
-    Ok(Stopped)
-}
+        if self.program.is_none() {
+            supervisor.reset();
+            self.fault = Some(format!("{}; there is no program to restart", reason));
+            return PlcState::Faulted;
+        }
 
-fn stop_proc(pid: Pid) -> StateResult {
-    use self::PlcState::*;
-    Ok(Stopping(pid))
-}
+        match supervisor.crashed() {
+            Some(count) => {
+                self.fault = Some(format!(
+                    "{}; gave up after {} crashes in {} seconds",
+                    reason,
+                    count,
+                    supervisor.policy.window.as_secs()
+                ));
+                PlcState::Faulted
+            }
+            None => PlcState::Stopped,
+        }
+    }
 
-fn start_program(file: String) -> StateResult {
-    use self::PlcState::*;
-    let mut start = Command::new(plc_start_command());
-    match start.output() {
-        Ok(output) => {
-            // Although we started a process, we don't know that
-            // what we started was the PLC process directly. So,
-            // we still need to find the process.
-            match find_process_pid(plc_process_name()) {
-                Ok(pid) => return Ok(Running(pid)),
-                Err(e) => return Err(e.to_string()),
+    // Sends the transition to the journal, or keeps it until we have a
+    // journal.
+    fn record(&mut self, user: &str, event: String, prior: PlcState, result: &StateResult) {
+        let transition = Transition {
+            at: Utc::now(),
+            user: String::from(user),
+            event,
+            prior,
+            result: result.clone(),
+        };
+        match &self.journal {
+            // It is not an error if the journal is no longer listening.
+            Some(journal) => {
+                let _ = journal.send(transition);
             }
+            None if self.unjournaled.len() < MAX_UNJOURNALED => self.unjournaled.push(transition),
+            None => {}
         }
-        Err(e) => return Err(e.to_string()),
     }
-}
 
-fn find_process_pid(file: &'static str) -> Result<Pid, &'static str> {
-    let s = System::new_all();
-    for process in s.processes_by_exact_name(plc_process_name()) {
-        return Ok(process.pid());
+    fn snapshot(&self) -> PlcSnapshot {
+        let started = match self.state {
+            PlcState::Running(pid) => self
+                .runtime
+                .start_time(pid)
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+            _ => None,
+        };
+        PlcSnapshot {
+            state: self.state,
+            program: self.program.clone(),
+            fault: self.fault.clone(),
+            started,
+        }
     }
-    Err("no such process")
-}
 
-fn compile_program(file: String) -> StateResult {
-    println!("Compile {}", file);
-    use self::PlcState::*;
-    let mut start = Command::new(plc_compile_command());
-    match start.output() {
-        Ok(output) => {
-            // Although we started a process, we don't know that
-            // what we started was the PLC process directly. So,
-            // we still need to find the process.
-            match find_process_pid(plc_process_name()) {
-                Ok(pid) => return Ok(Running(pid)),
-                Err(e) => return Err(e.to_string()),
+    // While stopping, we give the process the grace period to exit on
+    // its own. After that, we kill the process. In either case, we only
+    // consider the PLC stopped once the process is gone.
+    fn escalate_stop(&mut self, state: PlcState) -> StateResult {
+        use self::PlcState::*;
+
+        let pid = match state {
+            Stopping(pid) => pid,
+            _ => {
+                self.stop_requested = None;
+                return Ok(state);
             }
+        };
+
+        let requested = *self.stop_requested.get_or_insert_with(Instant::now);
+        if requested.elapsed() >= self.grace_period {
+            warn!("Process {} did not stop, killing", pid);
+            self.runtime.kill(pid)?;
         }
-        Err(e) => {
-            println!("Failed to start {}", e);
-            return Err(e.to_string());
-        }
+
+        is_proc_running(self.runtime.as_mut(), state, pid)
     }
 }
 
-fn change_hardware(name: String) -> StateResult {
-    println!("Change Hardware {}", name);
-
+fn discover(runtime: &mut dyn PlcRuntime) -> StateResult {
     use self::PlcState::*;
-    let status = Command::new(plc_start_command())
-        .arg(&name)
-        .current_dir("/bin")
-        .status();
 
-    match status {
-        Ok(status) => return Ok(Stopped),
-        Err(e) => return Err(e.to_string()),
+    // Try to determine if openplc application is running.
+    match runtime.discover()? {
+        Some(pid) => Ok(Running(pid)),
+        None => Ok(Stopped),
     }
 }
 
-async fn run_command(cmd: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut cmd = TokioCommand::new(cmd);
+// Checks if the process for the current state is still alive. If it
+// is, then we remain in the current state, otherwise we have stopped.
+fn is_proc_running(runtime: &mut dyn PlcRuntime, state: PlcState, pid: Pid) -> StateResult {
+    use self::PlcState::*;
+    if runtime.is_alive(pid) {
+        return Ok(state);
+    }
+    Ok(Stopped)
+}
 
-    // Specify that we want the command's standard output piped back to us.
-    // By default, standard input/output/error will be inherited from the
-    // current process (for example, this means that standard input will
-    // come from the keyboard and standard output/error will go directly to
-    // the terminal if this process is invoked from the command line).
-    cmd.stdout(Stdio::piped());
+fn stop_proc(runtime: &mut dyn PlcRuntime, pid: Pid) -> StateResult {
+    use self::PlcState::*;
+    runtime.stop(pid)?;
+    Ok(Stopping(pid))
+}
 
-    let mut child = cmd.spawn().expect("failed to spawn command");
+// The compiler runs other programs and nothing needs to clean up after
+// it, so we kill all of them rather than asking the compiler to stop.
+fn cancel_compile(runtime: &mut dyn PlcRuntime, pid: Pid) -> StateResult {
+    use self::PlcState::*;
+    runtime.kill_tree(pid)?;
+    Ok(Stopping(pid))
+}
 
-    let stdout = child
-        .stdout
-        .take()
-        .expect("child did not have a handle to stdout");
+fn start_program(runtime: &mut dyn PlcRuntime, file: String) -> StateResult {
+    use self::PlcState::*;
+    runtime.start(&file).map(Running).map_err(PlcError::Runtime)
+}
 
-    let mut reader = BufReader::new(stdout).lines();
+fn compile_program(runtime: &mut dyn PlcRuntime, file: String, log: Compilation) -> StateResult {
+    use self::PlcState::*;
+    runtime
+        .compile(&file, log.clone())
+        .map(|pid| {
+            log.start();
+            Compiling(pid)
+        })
+        .map_err(PlcError::Runtime)
+}
 
-    // Ensure the child process is spawned in the runtime so it can
-    // make progress on its own while we await for any output.
-    tokio::spawn(async move {
-        let status = child
-            .wait()
-            .await
-            .expect("child process encountered an error");
+fn change_hardware(runtime: &mut dyn PlcRuntime, name: String) -> StateResult {
+    use self::PlcState::*;
+    runtime
+        .change_hardware(&name)
+        .map(|_| Stopped)
+        .map_err(PlcError::Runtime)
+}
 
-        println!("child status was: {}", status);
-    });
+// What we know about the PLC after the most recent event. Reading the
+// snapshot never waits for the state machine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlcSnapshot {
+    pub state: PlcState,
+    pub program: Option<String>,
+    pub fault: Option<String>,
+    // When the PLC started running.
+    started: Option<SystemTime>,
+}
 
-    while let Some(line) = reader.next_line().await? {
-        println!("Line: {}", line);
+impl PlcSnapshot {
+    // Returns how long the PLC has been running, or None if it is
+    // not running.
+    pub fn uptime(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(self.started?).ok()
     }
+}
 
-    Ok(())
+// The requests that we send to the task that owns the state machine.
+enum Request {
+    Run {
+        event: PlcEvent,
+        user: String,
+        reply: oneshot::Sender<StateResult>,
+    },
+    Transition {
+        event: PlcEvent,
+        timeout: Duration,
+        user: String,
+        reply: oneshot::Sender<StateResult>,
+    },
+    SetJournal(UnboundedSender<Transition>),
+    SetSupervision(SupervisionPolicy),
 }
 
-// Provides a sharable object that mediates access to the PLC state machine.
-#[derive(Clone)]
-pub struct SharedPlcStateMachine {
-    pub sm: Arc<RwLock<PlcStateMachine>>,
+// The task that owns the state machine. Requests are handled one at a
+// time in the order they are received, so a request that is waiting for
+// the PLC to stop holds up the requests behind it.
+struct PlcActor {
+    sm: PlcStateMachine,
+    requests: UnboundedReceiver<Request>,
+    snapshot: watch::Sender<PlcSnapshot>,
 }
 
-impl SharedPlcStateMachine {
-    pub fn new() -> Self {
-        use self::*;
-        SharedPlcStateMachine {
-            sm: Arc::new(RwLock::new(PlcStateMachine::new())),
+impl PlcActor {
+    async fn run(mut self) {
+        // We regularly recheck the state by sending the NoOp event.
+        let mut poll = time::interval(POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            select! {
+                request = self.requests.recv() => match request {
+                    Some(request) => self.handle(request).await,
+                    // Everyone is gone so there is nothing left to do.
+                    None => break,
+                },
+                _ = poll.tick() => {
+                    let _ = self.sm.run(PlcEvent::NoOp);
+                    self.publish();
+                }
+            }
         }
     }
 
-    pub async fn transition(&self, event: PlcEvent, timeout: Duration) -> StateResult {
+    async fn handle(&mut self, request: Request) {
+        match request {
+            Request::Run { event, user, reply } => {
+                let result = self.sm.run_as(event, &user);
+                self.publish();
+                // It is not an error if the requester stopped waiting.
+                let _ = reply.send(result);
+            }
+            Request::Transition {
+                event,
+                timeout,
+                user,
+                reply,
+            } => {
+                let result = self.transition(event, timeout, &user).await;
+                self.publish();
+                let _ = reply.send(result);
+            }
+            Request::SetJournal(journal) => self.sm.set_journal(journal),
+            Request::SetSupervision(policy) => self.sm.set_supervision(policy),
+        }
+    }
+
+    // Stops the PLC (waiting for it to stop) and then sends the event.
+    async fn transition(&mut self, event: PlcEvent, timeout: Duration, user: &str) -> StateResult {
         use self::PlcEvent::*;
         use self::PlcState::*;
 
+        // Stopping may need to wait out the grace period before the
+        // process is killed, so we always wait at least that long.
+        let timeout = timeout.max(self.sm.grace_period + Duration::from_secs(1));
         let poll_end = Instant::now() + timeout;
 
         // We will sleep no less that every 100 ms (or the timeout)
@@ -349,55 +730,745 @@ impl SharedPlcStateMachine {
 
         // Long poll where we wait for the PLC to stop.
         while Instant::now() < poll_end {
-            // Try to get write access to the PLC - that's the only way
-            // that we would be able to guarantee that we can transition
-            // using the event.
-            if let Ok(mut plc) = self.sm.write() {
-                plc.run(Stop);
-                if plc.state == Stopped {
-                    return plc.run(event);
-                }
+            self.sm.run_as(Stop, user)?;
+            if self.sm.state == Stopped {
+                return self.sm.run_as(event, user);
             }
 
             // If we didn't return, then we are not in the stopped state.
-            // Sleep until our next attempt.
-            tokio::time::sleep(sleep_for).await;
+            // Let everyone see that we are stopping and sleep until our
+            // next attempt.
+            self.publish();
+            time::sleep(sleep_for).await;
         }
 
         // We give one last attempt to stop directly. We don't care what
         // the return value is here.
-        if let Ok(mut plc) = self.sm.write() {
-            plc.run(Stop);
-            if plc.state == Stopped {
-                return plc.run(event);
-            }
+        let _ = self.sm.run_as(Stop, user);
+        if self.sm.state == Stopped {
+            return self.sm.run_as(event, user);
         }
 
-        return Err("Timed out waiting to stop".to_string());
+        Err(PlcError::Timeout)
+    }
+
+    fn publish(&self) {
+        let snapshot = self.sm.snapshot();
+        if *self.snapshot.borrow() != snapshot {
+            // It is not an error if nobody is looking.
+            let _ = self.snapshot.send(snapshot);
+        }
+    }
+}
+
+// Provides a sharable object that mediates access to the PLC state machine.
+//
+// The state machine is owned by a single task and we send it events
+// through a channel. The state machine runs OS commands that may block,
+// so the task has its own thread (and its own tokio runtime for the
+// processes that it starts) rather than tying up the Rocket workers.
+#[derive(Clone)]
+pub struct SharedPlcStateMachine {
+    requests: UnboundedSender<Request>,
+    snapshot: watch::Receiver<PlcSnapshot>,
+}
+
+impl SharedPlcStateMachine {
+    pub fn new(runtime: Box<dyn PlcRuntime>, grace_period: Duration) -> Self {
+        let sm = PlcStateMachine::new(runtime, grace_period);
+        let (sender, requests) = mpsc::unbounded_channel();
+        let (snapshot, receiver) = watch::channel(sm.snapshot());
+        let actor = PlcActor {
+            sm,
+            requests,
+            snapshot,
+        };
+
+        thread::Builder::new()
+            .name(String::from("plc"))
+            .spawn(move || {
+                rocket::tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("create PLC runtime")
+                    .block_on(actor.run())
+            })
+            .expect("start PLC thread");
+
+        SharedPlcStateMachine {
+            requests: sender,
+            snapshot: receiver,
+        }
+    }
+
+    // Returns what we know about the PLC without waiting for any events
+    // that are in progress.
+    pub fn snapshot(&self) -> PlcSnapshot {
+        self.snapshot.borrow().clone()
+    }
+
+    // Sends a record of each transition from now on to the journal.
+    pub fn set_journal(&self, journal: UnboundedSender<Transition>) {
+        let _ = self.requests.send(Request::SetJournal(journal));
+    }
+
+    // Restarts the runtime according to the policy when it crashes.
+    pub fn set_supervision(&self, policy: SupervisionPolicy) {
+        let _ = self.requests.send(Request::SetSupervision(policy));
+    }
+
+    // Sends the event to the state machine.
+    pub async fn run(&self, event: PlcEvent) -> StateResult {
+        self.run_as(event, SYSTEM_USER).await
+    }
+
+    // Same as run, but records that the user sent the event.
+    pub async fn run_as(&self, event: PlcEvent, user: &str) -> StateResult {
+        let (reply, response) = oneshot::channel();
+        self.request(
+            Request::Run {
+                event,
+                user: String::from(user),
+                reply,
+            },
+            response,
+        )
+        .await
+    }
+
+    pub async fn transition(&self, event: PlcEvent, timeout: Duration) -> StateResult {
+        self.transition_as(event, timeout, SYSTEM_USER).await
+    }
+
+    // Same as transition, but records that the user sent the events.
+    pub async fn transition_as(
+        &self,
+        event: PlcEvent,
+        timeout: Duration,
+        user: &str,
+    ) -> StateResult {
+        let (reply, response) = oneshot::channel();
+        self.request(
+            Request::Transition {
+                event,
+                timeout,
+                user: String::from(user),
+                reply,
+            },
+            response,
+        )
+        .await
+    }
+
+    async fn request(
+        &self,
+        request: Request,
+        response: oneshot::Receiver<StateResult>,
+    ) -> StateResult {
+        let stopped = || PlcError::Runtime(String::from("The PLC state machine is not running"));
+        self.requests.send(request).map_err(|_| stopped())?;
+        response.await.map_err(|_| stopped())?
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::compilations::CompileStatus;
+    use super::super::logs::LogBuffer;
+    use super::super::runtime::SimulatedRuntime;
     use super::*;
 
+    fn simulated() -> (PlcStateMachine, SimulatedRuntime) {
+        let runtime = SimulatedRuntime::new();
+        let sm = PlcStateMachine::new(Box::new(runtime.clone()), Duration::from_secs(3600));
+        (sm, runtime)
+    }
+
+    fn stopped() -> (PlcStateMachine, SimulatedRuntime) {
+        let (mut sm, runtime) = simulated();
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        (sm, runtime)
+    }
+
+    fn running() -> (PlcStateMachine, SimulatedRuntime, Pid) {
+        let (mut sm, runtime) = stopped();
+        let pid = match sm.run(PlcEvent::Run(String::from("prog.st"))) {
+            Ok(PlcState::Running(pid)) => pid,
+            other => panic!("expected running, got {:?}", other),
+        };
+        (sm, runtime, pid)
+    }
+
+    fn compile_event() -> PlcEvent {
+        PlcEvent::Compile(
+            String::from("prog.st"),
+            Compilation::new(1, 1, String::from("prog.st"), LogBuffer::new(10)),
+        )
+    }
+
+    fn compiling() -> (PlcStateMachine, SimulatedRuntime, Pid) {
+        let (mut sm, runtime) = stopped();
+        let pid = match sm.run(compile_event()) {
+            Ok(PlcState::Compiling(pid)) => pid,
+            other => panic!("expected compiling, got {:?}", other),
+        };
+        (sm, runtime, pid)
+    }
+
     #[test]
     fn test_initial_state_initialize() {
-        let sm = PlcStateMachine::new();
+        let (sm, _runtime) = simulated();
         assert_matches!(sm.state, PlcState::Initialize);
     }
 
     #[test]
     fn test_initial_state_noop_then_stopped() {
-        let mut sm = PlcStateMachine::new();
+        let (mut sm, _runtime) = simulated();
         sm.run(PlcEvent::NoOp).expect("success changing state");
         assert_matches!(sm.state, PlcState::Stopped);
     }
 
     #[test]
     fn test_initial_state_stop_then_stopped() {
-        let mut sm = PlcStateMachine::new();
+        let (mut sm, _runtime) = simulated();
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+    }
+
+    #[test]
+    fn test_initial_state_discovers_running() {
+        let (mut sm, runtime) = simulated();
+        let pid = runtime.launch_external();
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_eq!(sm.state, PlcState::Running(pid));
+    }
+
+    #[test]
+    fn test_initial_state_run_is_invalid() {
+        let (mut sm, _runtime) = simulated();
+        assert!(sm.run(PlcEvent::Run(String::from("prog.st"))).is_err());
+        assert_matches!(sm.state, PlcState::Initialize);
+    }
+
+    #[test]
+    fn test_stopped_noop_and_stop_remain_stopped() {
+        let (mut sm, _runtime) = stopped();
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+    }
+
+    #[test]
+    fn test_stopped_run_then_running() {
+        let (sm, runtime, _pid) = running();
+        assert_matches!(sm.state, PlcState::Running(_));
+        assert_eq!(runtime.program(), Some(String::from("prog.st")));
+    }
+
+    #[test]
+    fn test_running_has_program_and_uptime() {
+        let (sm, _runtime, _pid) = running();
+        assert_eq!(sm.program, Some(String::from("prog.st")));
+        assert!(sm.snapshot().uptime().is_some());
+    }
+
+    #[test]
+    fn test_stopped_has_no_uptime() {
+        let (sm, _runtime) = stopped();
+        assert_eq!(sm.program, None);
+        assert_eq!(sm.snapshot().uptime(), None);
+    }
+
+    #[test]
+    fn test_stopped_run_fails_remains_stopped() {
+        let (mut sm, runtime) = stopped();
+        runtime.fail_next("no such process");
+        assert!(sm.run(PlcEvent::Run(String::from("prog.st"))).is_err());
+        assert_matches!(sm.state, PlcState::Stopped);
+    }
+
+    #[test]
+    fn test_stopped_compile_then_compiling() {
+        let (sm, _runtime, _pid) = compiling();
+        assert_matches!(sm.state, PlcState::Compiling(_));
+    }
+
+    #[test]
+    fn test_stopped_set_hardware_remains_stopped() {
+        let (mut sm, runtime) = stopped();
+        sm.run(PlcEvent::SetHardware(String::from("rpi")))
+            .expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+        assert_eq!(runtime.hardware(), Some(String::from("rpi")));
+    }
+
+    #[test]
+    fn test_compiling_noop_remains_compiling() {
+        let (mut sm, _runtime, pid) = compiling();
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_eq!(sm.state, PlcState::Compiling(pid));
+    }
+
+    #[test]
+    fn test_compiling_complete_then_stopped() {
+        let (mut sm, runtime, pid) = compiling();
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+    }
+
+    #[test]
+    fn test_compiling_writes_log() {
+        let (mut sm, runtime) = stopped();
+        let log = Compilation::new(1, 7, String::from("prog.st"), LogBuffer::new(10));
+        let pid = match sm.run(PlcEvent::Compile(String::from("prog.st"), log.clone())) {
+            Ok(PlcState::Compiling(pid)) => pid,
+            other => panic!("expected compiling, got {:?}", other),
+        };
+        assert!(!log.is_finished());
+
+        runtime.exit_with_code(pid, 2);
+        let compile_log = log.log();
+        assert!(log.is_finished());
+        assert_eq!(compile_log.exit_code(), Some(2));
+        assert_eq!(compile_log.data(), "Compiling prog.st");
+    }
+
+    #[test]
+    fn test_compile_fails_finishes_log() {
+        let (mut sm, runtime) = stopped();
+        let log = Compilation::new(1, 7, String::from("prog.st"), LogBuffer::new(10));
+        runtime.fail_next("compiler not found");
+        assert!(sm
+            .run(PlcEvent::Compile(String::from("prog.st"), log.clone()))
+            .is_err());
+        assert!(log.is_finished());
+        assert_eq!(log.log().data(), "compiler not found");
+    }
+
+    #[test]
+    fn test_compiling_stop_then_stopped() {
+        let (mut sm, runtime, pid) = compiling();
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+        assert!(!runtime.is_running(pid));
+    }
+
+    #[test]
+    fn test_compiling_stop_kills_compiler_then_stopped() {
+        let (mut sm, runtime, pid) = compiling();
+        // We don't ask the compiler to stop, so it can't ignore us.
+        runtime.ignore_stop();
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert_eq!(sm.state, PlcState::Stopped);
+        assert!(!runtime.is_running(pid));
+    }
+
+    #[test]
+    fn test_compiling_cancel_compile_then_cancelled() {
+        let (mut sm, runtime) = stopped();
+        let log = Compilation::new(3, 7, String::from("prog.st"), LogBuffer::new(10));
+        let pid = match sm.run(PlcEvent::Compile(String::from("prog.st"), log.clone())) {
+            Ok(PlcState::Compiling(pid)) => pid,
+            other => panic!("expected compiling, got {:?}", other),
+        };
+
+        sm.run(PlcEvent::CancelCompile(3))
+            .expect("success changing state");
+        assert_eq!(sm.state, PlcState::Stopped);
+        assert!(!runtime.is_running(pid));
+        assert_eq!(log.log().status(), CompileStatus::Cancelled);
+        assert!(log.log().data().ends_with("Compilation cancelled"));
+    }
+
+    #[test]
+    fn test_cancel_compile_other_job_is_invalid() {
+        let (mut sm, _runtime, pid) = compiling();
+        assert!(sm.run(PlcEvent::CancelCompile(2)).is_err());
+        assert_eq!(sm.state, PlcState::Compiling(pid));
+    }
+
+    #[test]
+    fn test_cancel_compile_after_finished_is_invalid() {
+        let (mut sm, runtime, pid) = compiling();
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert!(sm.run(PlcEvent::CancelCompile(1)).is_err());
+        assert_eq!(sm.state, PlcState::Stopped);
+    }
+
+    #[test]
+    fn test_compiling_run_is_invalid() {
+        let (mut sm, _runtime, pid) = compiling();
+        assert!(sm.run(PlcEvent::Run(String::from("prog.st"))).is_err());
+        assert_eq!(sm.state, PlcState::Compiling(pid));
+    }
+
+    #[test]
+    fn test_running_noop_remains_running() {
+        let (mut sm, _runtime, pid) = running();
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_eq!(sm.state, PlcState::Running(pid));
+    }
+
+    #[test]
+    fn test_running_exits_then_stopped() {
+        let (mut sm, runtime, pid) = running();
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+    }
+
+    #[test]
+    fn test_running_stop_then_stopped() {
+        let (mut sm, runtime, pid) = running();
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+        assert!(!runtime.is_running(pid));
+    }
+
+    #[test]
+    fn test_running_stop_ignored_then_stopping() {
+        let (mut sm, runtime, pid) = running();
+        runtime.ignore_stop();
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert_eq!(sm.state, PlcState::Stopping(pid));
+        assert!(runtime.is_running(pid));
+    }
+
+    #[test]
+    fn test_running_stop_fails_remains_running() {
+        let (mut sm, runtime, pid) = running();
+        runtime.fail_next("permission denied");
+        assert!(sm.run(PlcEvent::Stop).is_err());
+        assert_eq!(sm.state, PlcState::Running(pid));
+    }
+
+    #[test]
+    fn test_running_compile_is_invalid() {
+        let (mut sm, _runtime, pid) = running();
+        assert!(sm.run(compile_event()).is_err());
+        assert_eq!(sm.state, PlcState::Running(pid));
+    }
+
+    #[test]
+    fn test_stopping_noop_remains_stopping() {
+        let (mut sm, runtime, pid) = running();
+        runtime.ignore_stop();
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_eq!(sm.state, PlcState::Stopping(pid));
+    }
+
+    #[test]
+    fn test_stopping_exits_then_stopped() {
+        let (mut sm, runtime, pid) = running();
+        runtime.ignore_stop();
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+    }
+
+    #[test]
+    fn test_stopping_grace_period_expires_then_killed() {
+        let runtime = SimulatedRuntime::new();
+        let mut sm = PlcStateMachine::new(Box::new(runtime.clone()), Duration::from_secs(0));
+        let pid = runtime.launch_external();
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        runtime.ignore_stop();
+
         sm.run(PlcEvent::Stop).expect("success changing state");
         assert_matches!(sm.state, PlcState::Stopped);
+        assert!(!runtime.is_running(pid));
+    }
+
+    #[test]
+    fn test_stopping_kill_fails_reports_error() {
+        let runtime = SimulatedRuntime::new();
+        let mut sm = PlcStateMachine::new(Box::new(runtime.clone()), Duration::from_secs(0));
+        let pid = runtime.launch_external();
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        runtime.ignore_stop();
+
+        runtime.fail_next("operation not permitted");
+        assert!(sm.run(PlcEvent::Stop).is_err());
+        assert_eq!(sm.state, PlcState::Running(pid));
+        assert!(runtime.is_running(pid));
+    }
+
+    #[test]
+    fn test_stopping_set_hardware_is_invalid() {
+        let (mut sm, runtime, pid) = running();
+        runtime.ignore_stop();
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert!(sm.run(PlcEvent::SetHardware(String::from("rpi"))).is_err());
+        assert_eq!(sm.state, PlcState::Stopping(pid));
+    }
+
+    fn supervised(
+        backoff: Duration,
+        max_crashes: usize,
+    ) -> (PlcStateMachine, SimulatedRuntime, Pid) {
+        let (mut sm, runtime, pid) = running();
+        sm.set_supervision(SupervisionPolicy {
+            initial_backoff: backoff,
+            max_backoff: Duration::from_secs(3600),
+            max_crashes,
+            window: Duration::from_secs(3600),
+        });
+        (sm, runtime, pid)
+    }
+
+    #[test]
+    fn test_supervised_crash_then_restarted() {
+        let (mut sm, runtime, pid) = supervised(Duration::ZERO, 3);
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+
+        let restarted = match sm.run(PlcEvent::NoOp) {
+            Ok(PlcState::Running(pid)) => pid,
+            other => panic!("expected running, got {:?}", other),
+        };
+        assert_ne!(restarted, pid);
+        assert!(runtime.is_running(restarted));
+        assert_eq!(runtime.program(), Some(String::from("prog.st")));
+    }
+
+    #[test]
+    fn test_supervised_crash_waits_for_backoff() {
+        let (mut sm, runtime, pid) = supervised(Duration::from_secs(3600), 3);
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+    }
+
+    #[test]
+    fn test_supervised_operator_stop_not_restarted() {
+        let (mut sm, runtime, pid) = supervised(Duration::ZERO, 3);
+        runtime.ignore_stop();
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+        assert_eq!(sm.fault, None);
+    }
+
+    #[test]
+    fn test_supervised_operator_stop_exits_at_once_not_restarted() {
+        let (mut sm, runtime, pid) = supervised(Duration::ZERO, 3);
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+        assert!(!runtime.is_running(pid));
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+        assert_eq!(sm.fault, None);
+    }
+
+    #[test]
+    fn test_supervised_stop_discovered_exits_at_once_not_faulted() {
+        let runtime = SimulatedRuntime::new();
+        let pid = runtime.launch_external();
+        let mut sm = PlcStateMachine::new(Box::new(runtime.clone()), Duration::from_secs(3600));
+        sm.set_supervision(SupervisionPolicy {
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::from_secs(3600),
+            max_crashes: 3,
+            window: Duration::from_secs(3600),
+        });
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_eq!(sm.state, PlcState::Running(pid));
+
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+        assert_eq!(sm.fault, None);
+    }
+
+    #[test]
+    fn test_supervised_crash_loop_then_faulted() {
+        let (mut sm, runtime, pid) = supervised(Duration::ZERO, 2);
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        let restarted = match sm.run(PlcEvent::NoOp) {
+            Ok(PlcState::Running(pid)) => pid,
+            other => panic!("expected running, got {:?}", other),
+        };
+
+        runtime.exit(restarted);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Faulted);
+        assert!(sm
+            .fault
+            .as_ref()
+            .unwrap()
+            .contains("gave up after 2 crashes"));
+
+        // We stay faulted until the operator does something.
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Faulted);
+
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Stopped);
+        assert_eq!(sm.fault, None);
+    }
+
+    #[test]
+    fn test_supervised_restart_fails_counts_as_crash() {
+        let (mut sm, runtime, pid) = supervised(Duration::ZERO, 2);
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        runtime.fail_next("no such file");
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Faulted);
+        assert!(sm.fault.as_ref().unwrap().contains("no such file"));
+    }
+
+    #[test]
+    fn test_supervised_crash_without_program_then_faulted() {
+        let runtime = SimulatedRuntime::new();
+        let pid = runtime.launch_external();
+        let mut sm = PlcStateMachine::new(Box::new(runtime.clone()), Duration::from_secs(3600));
+        sm.set_supervision(SupervisionPolicy {
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            max_crashes: 3,
+            window: Duration::from_secs(3600),
+        });
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Faulted);
+        assert!(sm.fault.as_ref().unwrap().contains("no program to restart"));
+    }
+
+    #[test]
+    fn test_faulted_run_then_running() {
+        let (mut sm, runtime, pid) = supervised(Duration::ZERO, 1);
+        runtime.exit(pid);
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        assert_matches!(sm.state, PlcState::Faulted);
+        sm.run(PlcEvent::Run(String::from("prog.st")))
+            .expect("success changing state");
+        assert_matches!(sm.state, PlcState::Running(_));
+        assert_eq!(sm.fault, None);
+    }
+
+    #[test]
+    fn test_supervisor_backoff_doubles_up_to_max() {
+        let mut supervisor = Supervisor::new(SupervisionPolicy {
+            initial_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(30),
+            max_crashes: 5,
+            window: Duration::from_secs(3600),
+        });
+        let waits: Vec<u64> = (0..3)
+            .map(|_| {
+                assert_eq!(supervisor.crashed(), None);
+                let wait = supervisor.restart_at.unwrap() - Instant::now();
+                // Round up since some time has passed.
+                (wait.as_millis() as u64).div_ceil(1000)
+            })
+            .collect();
+        assert_eq!(waits, vec![10, 20, 30]);
+        assert_eq!(supervisor.crashed(), None);
+        assert_eq!(supervisor.crashed(), Some(5));
+        assert_eq!(supervisor.restart_at, None);
+    }
+
+    #[test]
+    fn test_transitions_sent_to_journal() {
+        let (mut sm, _runtime) = simulated();
+        let (sender, mut receiver) = rocket::tokio::sync::mpsc::unbounded_channel();
+        sm.set_journal(sender);
+
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        // Polling without a change is not recorded.
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        sm.run_as(PlcEvent::Run(String::from("prog.st")), "operator1")
+            .expect("success changing state");
+        assert!(sm.run_as(compile_event(), "operator1").is_err());
+
+        let discovered = receiver.try_recv().expect("transition");
+        assert_eq!(discovered.user, SYSTEM_USER);
+        assert_eq!(discovered.prior, PlcState::Initialize);
+        assert_eq!(discovered.result, Ok(PlcState::Stopped));
+
+        let run = receiver.try_recv().expect("transition");
+        assert_eq!(run.user, "operator1");
+        assert_eq!(run.event, "Event: Run prog.st");
+        assert_eq!(run.prior, PlcState::Stopped);
+        assert_matches!(run.result, Ok(PlcState::Running(_)));
+
+        let compile = receiver.try_recv().expect("transition");
+        assert_matches!(compile.result, Err(PlcError::InvalidTransition(_)));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_transitions_before_journal_are_kept() {
+        let (mut sm, _runtime) = simulated();
+        sm.run(PlcEvent::NoOp).expect("success changing state");
+        sm.run_as(PlcEvent::Run(String::from("prog.st")), "operator1")
+            .expect("success changing state");
+
+        let (sender, mut receiver) = rocket::tokio::sync::mpsc::unbounded_channel();
+        sm.set_journal(sender);
+        let discovered = receiver.try_recv().expect("transition");
+        assert_eq!(discovered.prior, PlcState::Initialize);
+        let run = receiver.try_recv().expect("transition");
+        assert_eq!(run.user, "operator1");
+        assert!(receiver.try_recv().is_err());
+
+        sm.run(PlcEvent::Stop).expect("success changing state");
+        assert_eq!(
+            receiver.try_recv().expect("transition").event,
+            "Event: Stop"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_shared_transition_stops_then_runs() {
+        let runtime = SimulatedRuntime::new();
+        runtime.launch_external();
+        let shared = SharedPlcStateMachine::new(Box::new(runtime.clone()), Duration::from_secs(0));
+        let state = shared
+            .transition(
+                PlcEvent::Run(String::from("prog.st")),
+                Duration::from_secs(1),
+            )
+            .await
+            .expect("success changing state");
+        assert_matches!(state, PlcState::Running(_));
+        assert_eq!(runtime.program(), Some(String::from("prog.st")));
+        assert_eq!(shared.snapshot().state, state);
+    }
+
+    #[rocket::async_test]
+    async fn test_shared_requests_handled_in_order() {
+        let runtime = SimulatedRuntime::new();
+        let shared = SharedPlcStateMachine::new(Box::new(runtime.clone()), Duration::from_secs(0));
+        shared
+            .run(PlcEvent::NoOp)
+            .await
+            .expect("success changing state");
+
+        // The run is queued behind the transition so it sees the
+        // result of the transition.
+        let (first, second) = rocket::tokio::join!(
+            shared.transition_as(
+                PlcEvent::Run(String::from("first.st")),
+                Duration::from_secs(1),
+                "operator1"
+            ),
+            shared.run_as(PlcEvent::Run(String::from("second.st")), "operator2"),
+        );
+        assert_matches!(first, Ok(PlcState::Running(_)));
+        assert_matches!(second, Err(PlcError::InvalidTransition(_)));
+        assert_eq!(runtime.program(), Some(String::from("first.st")));
     }
 }
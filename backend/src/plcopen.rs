@@ -0,0 +1,712 @@
+use rocket::serde::Serialize;
+use roxmltree::{Document, Node};
+
+use super::structured_text::Diagnostic;
+
+// Imports projects in PLCopen TC6 XML, the format that most IEC 61131-3
+// editors export. The compiler only knows Structured Text, so we convert
+// the project into Structured Text and keep what the project defines as
+// the metadata of the program.
+
+const NAMESPACES: [&str; 2] = [
+    "http://www.plcopen.org/xml/tc6_0201",
+    "http://www.plcopen.org/xml/tc6_0200",
+];
+
+// The sections of the interface of a POU and the Structured Text keyword
+// for each one.
+const SECTIONS: [(&str, &str); 7] = [
+    ("inputVars", "VAR_INPUT"),
+    ("outputVars", "VAR_OUTPUT"),
+    ("inOutVars", "VAR_IN_OUT"),
+    ("externalVars", "VAR_EXTERNAL"),
+    ("globalVars", "VAR_GLOBAL"),
+    ("localVars", "VAR"),
+    ("tempVars", "VAR_TEMP"),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Variable {
+    name: String,
+    #[serde(rename = "type")]
+    data_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(rename = "initialValue", skip_serializing_if = "Option::is_none")]
+    initial_value: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct VariableSection {
+    // The Structured Text keyword, such as VAR_INPUT.
+    section: &'static str,
+    constant: bool,
+    retain: bool,
+    variables: Vec<Variable>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DataType {
+    name: String,
+    // The Structured Text definition of the type.
+    definition: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Pou {
+    name: String,
+    // The Structured Text keyword, such as FUNCTION_BLOCK.
+    #[serde(rename = "pouType")]
+    pou_type: &'static str,
+    #[serde(rename = "returnType", skip_serializing_if = "Option::is_none")]
+    return_type: Option<String>,
+    variables: Vec<VariableSection>,
+    #[serde(skip)]
+    body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Instance {
+    name: String,
+    #[serde(rename = "typeName")]
+    type_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Task {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<String>,
+    priority: String,
+    instances: Vec<Instance>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Resource {
+    name: String,
+    globals: Vec<VariableSection>,
+    tasks: Vec<Task>,
+    // The instances that don't belong to a task.
+    instances: Vec<Instance>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Configuration {
+    name: String,
+    globals: Vec<VariableSection>,
+    resources: Vec<Resource>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Project {
+    #[serde(rename = "dataTypes")]
+    data_types: Vec<DataType>,
+    pous: Vec<Pou>,
+    configurations: Vec<Configuration>,
+}
+
+// Whether the source looks like XML rather than Structured Text, which
+// can't start with a <.
+pub fn is_xml(source: &str) -> bool {
+    source
+        .trim_start_matches('\u{feff}')
+        .trim_start()
+        .starts_with('<')
+}
+
+// Reads the project from the document, reporting each problem at the
+// position of the element that has the problem.
+struct Reader<'a, 'input> {
+    doc: &'a Document<'input>,
+}
+
+type ReadResult<T> = Result<T, Diagnostic>;
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn child<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'static str) -> Option<Node<'a, 'input>> {
+    children(node, name).next()
+}
+
+fn first_element<'a, 'input>(node: Node<'a, 'input>) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.is_element())
+}
+
+impl<'a, 'input> Reader<'a, 'input> {
+    fn error(&self, node: Node, message: String) -> Diagnostic {
+        let pos = self.doc.text_pos_at(node.range().start);
+        Diagnostic::new(pos.row as usize, pos.col as usize, message)
+    }
+
+    fn attribute(&self, node: Node, name: &str) -> ReadResult<String> {
+        node.attribute(name).map(String::from).ok_or_else(|| {
+            self.error(
+                node,
+                format!("<{}> needs a {} attribute", node.tag_name().name(), name),
+            )
+        })
+    }
+
+    fn child(&self, node: Node<'a, 'input>, name: &'static str) -> ReadResult<Node<'a, 'input>> {
+        child(node, name).ok_or_else(|| {
+            self.error(
+                node,
+                format!("<{}> needs a <{}>", node.tag_name().name(), name),
+            )
+        })
+    }
+
+    // Converts a type, such as <BOOL/> or <derived name="TON"/>, into the
+    // Structured Text for the type.
+    fn data_type(&self, node: Node<'a, 'input>) -> ReadResult<String> {
+        let node = first_element(node)
+            .ok_or_else(|| self.error(node, String::from("The type is empty")))?;
+        match node.tag_name().name() {
+            "derived" => self.attribute(node, "name"),
+            name @ ("string" | "wstring") => Ok(match node.attribute("length") {
+                Some(length) => format!("{}[{}]", name.to_uppercase(), length),
+                None => name.to_uppercase(),
+            }),
+            "array" => {
+                let dimensions = children(node, "dimension")
+                    .map(|dimension| {
+                        Ok(format!(
+                            "{}..{}",
+                            self.attribute(dimension, "lower")?,
+                            self.attribute(dimension, "upper")?
+                        ))
+                    })
+                    .collect::<ReadResult<Vec<_>>>()?;
+                let base = self.data_type(self.child(node, "baseType")?)?;
+                Ok(format!("ARRAY [{}] OF {}", dimensions.join(", "), base))
+            }
+            name if name.chars().all(|c| c.is_ascii_uppercase() || c == '_') => {
+                Ok(String::from(name))
+            }
+            name => Err(self.error(node, format!("The type {} is not supported", name))),
+        }
+    }
+
+    // Converts a value, such as <simpleValue value="1"/>, into Structured
+    // Text.
+    fn value(&self, node: Node<'a, 'input>) -> ReadResult<String> {
+        let node = first_element(node)
+            .ok_or_else(|| self.error(node, String::from("The value is empty")))?;
+        match node.tag_name().name() {
+            "simpleValue" => self.attribute(node, "value"),
+            "arrayValue" => {
+                let values = children(node, "value")
+                    .map(|value| {
+                        let text = self.value(value)?;
+                        Ok(match value.attribute("repetitionValue") {
+                            Some(count) => format!("{}({})", count, text),
+                            None => text,
+                        })
+                    })
+                    .collect::<ReadResult<Vec<_>>>()?;
+                Ok(format!("[{}]", values.join(", ")))
+            }
+            "structValue" => {
+                let values = children(node, "value")
+                    .map(|value| {
+                        Ok(format!(
+                            "{} := {}",
+                            self.attribute(value, "member")?,
+                            self.value(value)?
+                        ))
+                    })
+                    .collect::<ReadResult<Vec<_>>>()?;
+                Ok(format!("({})", values.join(", ")))
+            }
+            name => Err(self.error(node, format!("The value {} is not supported", name))),
+        }
+    }
+
+    fn variable(&self, node: Node<'a, 'input>) -> ReadResult<Variable> {
+        Ok(Variable {
+            name: self.attribute(node, "name")?,
+            data_type: self.data_type(self.child(node, "type")?)?,
+            address: node.attribute("address").map(String::from),
+            initial_value: child(node, "initialValue")
+                .map(|value| self.value(value))
+                .transpose()?,
+        })
+    }
+
+    fn section(
+        &self,
+        node: Node<'a, 'input>,
+        section: &'static str,
+    ) -> ReadResult<VariableSection> {
+        Ok(VariableSection {
+            section,
+            constant: node.attribute("constant") == Some("true"),
+            retain: node.attribute("retain") == Some("true"),
+            variables: children(node, "variable")
+                .map(|variable| self.variable(variable))
+                .collect::<ReadResult<_>>()?,
+        })
+    }
+
+    // Reads the sections of variables within the node in the order that
+    // they are in the file.
+    fn sections(&self, node: Node<'a, 'input>) -> ReadResult<Vec<VariableSection>> {
+        node.children()
+            .filter_map(|child| {
+                SECTIONS
+                    .iter()
+                    .find(|(name, _)| child.is_element() && child.tag_name().name() == *name)
+                    .map(|(_, section)| self.section(child, section))
+            })
+            .collect()
+    }
+
+    fn type_definition(&self, node: Node<'a, 'input>) -> ReadResult<DataType> {
+        let name = self.attribute(node, "name")?;
+        let base = self.child(node, "baseType")?;
+        let definition = match first_element(base).map(|n| (n.tag_name().name(), n)) {
+            Some(("struct", members)) => {
+                let members = children(members, "variable")
+                    .map(|member| {
+                        let member = self.variable(member)?;
+                        Ok(format!("    {};\n", declaration(&member)))
+                    })
+                    .collect::<ReadResult<String>>()?;
+                format!("STRUCT\n{}  END_STRUCT", members)
+            }
+            Some(("enum", values)) => {
+                let values = self.child(values, "values")?;
+                let names = children(values, "value")
+                    .map(|value| self.attribute(value, "name"))
+                    .collect::<ReadResult<Vec<_>>>()?;
+                format!("({})", names.join(", "))
+            }
+            _ => self.data_type(base)?,
+        };
+        let definition = match child(node, "initialValue") {
+            Some(value) => format!("{} := {}", definition, self.value(value)?),
+            None => definition,
+        };
+        Ok(DataType { name, definition })
+    }
+
+    fn pou(&self, node: Node<'a, 'input>) -> ReadResult<Pou> {
+        let name = self.attribute(node, "name")?;
+        let pou_type = match self.attribute(node, "pouType")?.as_str() {
+            "program" => "PROGRAM",
+            "functionBlock" => "FUNCTION_BLOCK",
+            "function" => "FUNCTION",
+            pou_type => {
+                return Err(self.error(node, format!("The POU type {} is not supported", pou_type)))
+            }
+        };
+        let interface = child(node, "interface");
+        let return_type = interface
+            .and_then(|interface| child(interface, "returnType"))
+            .map(|return_type| self.data_type(return_type))
+            .transpose()?;
+        if pou_type == "FUNCTION" && return_type.is_none() {
+            return Err(self.error(node, format!("The function {} has no return type", name)));
+        }
+
+        let body = self.child(node, "body")?;
+        let language = first_element(body)
+            .ok_or_else(|| self.error(body, format!("The POU {} has no body", name)))?;
+        if language.tag_name().name() != "ST" {
+            return Err(self.error(
+                language,
+                format!(
+                    "The POU {} is in {}, but only ST can be imported",
+                    name,
+                    language.tag_name().name()
+                ),
+            ));
+        }
+        // Editors put the code in an XHTML paragraph, so we ignore the
+        // whitespace around the paragraph.
+        let body = language
+            .descendants()
+            .filter(|node| node.is_text())
+            .filter_map(|node| node.text())
+            .filter(|text| !text.trim().is_empty())
+            .collect::<String>();
+
+        Ok(Pou {
+            name,
+            pou_type,
+            return_type,
+            variables: match interface {
+                Some(interface) => self.sections(interface)?,
+                None => Vec::new(),
+            },
+            body,
+        })
+    }
+
+    fn instance(&self, node: Node) -> ReadResult<Instance> {
+        Ok(Instance {
+            name: self.attribute(node, "name")?,
+            type_name: self.attribute(node, "typeName")?,
+        })
+    }
+
+    fn task(&self, node: Node<'a, 'input>) -> ReadResult<Task> {
+        Ok(Task {
+            name: self.attribute(node, "name")?,
+            interval: node.attribute("interval").map(String::from),
+            priority: self.attribute(node, "priority")?,
+            instances: children(node, "pouInstance")
+                .map(|instance| self.instance(instance))
+                .collect::<ReadResult<_>>()?,
+        })
+    }
+
+    fn resource(&self, node: Node<'a, 'input>) -> ReadResult<Resource> {
+        Ok(Resource {
+            name: self.attribute(node, "name")?,
+            globals: children(node, "globalVars")
+                .map(|globals| self.section(globals, "VAR_GLOBAL"))
+                .collect::<ReadResult<_>>()?,
+            tasks: children(node, "task")
+                .map(|task| self.task(task))
+                .collect::<ReadResult<_>>()?,
+            instances: children(node, "pouInstance")
+                .map(|instance| self.instance(instance))
+                .collect::<ReadResult<_>>()?,
+        })
+    }
+
+    fn configuration(&self, node: Node<'a, 'input>) -> ReadResult<Configuration> {
+        Ok(Configuration {
+            name: self.attribute(node, "name")?,
+            globals: children(node, "globalVars")
+                .map(|globals| self.section(globals, "VAR_GLOBAL"))
+                .collect::<ReadResult<_>>()?,
+            resources: children(node, "resource")
+                .map(|resource| self.resource(resource))
+                .collect::<ReadResult<_>>()?,
+        })
+    }
+
+    fn project(&self) -> ReadResult<Project> {
+        let root = self.doc.root_element();
+        if root.tag_name().name() != "project"
+            || !NAMESPACES.contains(&root.tag_name().namespace().unwrap_or_default())
+        {
+            return Err(self.error(root, String::from("The XML is not a PLCopen TC6 project")));
+        }
+
+        let types = self.child(root, "types")?;
+        let data_types = child(types, "dataTypes")
+            .map(|data_types| {
+                children(data_types, "dataType")
+                    .map(|data_type| self.type_definition(data_type))
+                    .collect::<ReadResult<Vec<_>>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let pous = child(types, "pous")
+            .map(|pous| {
+                children(pous, "pou")
+                    .map(|pou| self.pou(pou))
+                    .collect::<ReadResult<Vec<_>>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+        if pous.is_empty() {
+            return Err(self.error(types, String::from("The project has no POUs")));
+        }
+        let configurations = child(root, "instances")
+            .and_then(|instances| child(instances, "configurations"))
+            .map(|configurations| {
+                children(configurations, "configuration")
+                    .map(|configuration| self.configuration(configuration))
+                    .collect::<ReadResult<Vec<_>>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Project {
+            data_types,
+            pous,
+            configurations,
+        })
+    }
+}
+
+fn declaration(variable: &Variable) -> String {
+    let mut text = variable.name.clone();
+    if let Some(address) = &variable.address {
+        text += &format!(" AT {}", address);
+    }
+    text += &format!(" : {}", variable.data_type);
+    if let Some(value) = &variable.initial_value {
+        text += &format!(" := {}", value);
+    }
+    text
+}
+
+fn write_sections(text: &mut String, sections: &[VariableSection], indent: &str) {
+    for section in sections {
+        *text += &format!("{}{}", indent, section.section);
+        if section.constant {
+            *text += " CONSTANT";
+        }
+        if section.retain {
+            *text += " RETAIN";
+        }
+        *text += "\n";
+        for variable in &section.variables {
+            *text += &format!("{}  {};\n", indent, declaration(variable));
+        }
+        *text += &format!("{}END_VAR\n", indent);
+    }
+}
+
+fn write_instance(text: &mut String, instance: &Instance, task: Option<&str>) {
+    *text += &format!("    PROGRAM {}", instance.name);
+    if let Some(task) = task {
+        *text += &format!(" WITH {}", task);
+    }
+    *text += &format!(" : {};\n", instance.type_name);
+}
+
+impl Project {
+    // Writes the project as Structured Text for the compiler.
+    pub fn to_structured_text(&self) -> String {
+        let mut text = String::new();
+        if !self.data_types.is_empty() {
+            text += "TYPE\n";
+            for data_type in &self.data_types {
+                text += &format!("  {} : {};\n", data_type.name, data_type.definition);
+            }
+            text += "END_TYPE\n\n";
+        }
+
+        for pou in &self.pous {
+            text += &format!("{} {}", pou.pou_type, pou.name);
+            if let Some(return_type) = &pou.return_type {
+                text += &format!(" : {}", return_type);
+            }
+            text += "\n";
+            write_sections(&mut text, &pou.variables, "  ");
+            for line in pou.body.trim_matches('\n').lines() {
+                text += format!("  {}", line).trim_end();
+                text += "\n";
+            }
+            text += &format!("END_{}\n\n", pou.pou_type);
+        }
+
+        for configuration in &self.configurations {
+            text += &format!("CONFIGURATION {}\n", configuration.name);
+            write_sections(&mut text, &configuration.globals, "  ");
+            for resource in &configuration.resources {
+                text += &format!("  RESOURCE {} ON PLC\n", resource.name);
+                write_sections(&mut text, &resource.globals, "    ");
+                for task in &resource.tasks {
+                    text += &format!("    TASK {}(", task.name);
+                    if let Some(interval) = &task.interval {
+                        text += &format!("INTERVAL := {},", interval);
+                    }
+                    text += &format!("PRIORITY := {});\n", task.priority);
+                }
+                for task in &resource.tasks {
+                    for instance in &task.instances {
+                        write_instance(&mut text, instance, Some(&task.name));
+                    }
+                }
+                for instance in &resource.instances {
+                    write_instance(&mut text, instance, None);
+                }
+                text += "  END_RESOURCE\n";
+            }
+            text += "END_CONFIGURATION\n";
+        }
+        text
+    }
+}
+
+// Reads the PLCopen XML project, or returns where the project is not
+// valid.
+pub fn parse(source: &str) -> Result<Project, Diagnostic> {
+    let doc = Document::parse(source.trim_start_matches('\u{feff}')).map_err(|e| {
+        let pos = e.pos();
+        Diagnostic::new(pos.row as usize, pos.col as usize, e.to_string())
+    })?;
+    Reader { doc: &doc }.project()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::structured_text;
+    use super::*;
+
+    // A project like the ones that the OpenPLC editor exports.
+    pub const BLINK: &str = r#"<?xml version='1.0' encoding='utf-8'?>
+<project xmlns="http://www.plcopen.org/xml/tc6_0201" xmlns:xhtml="http://www.w3.org/1999/xhtml">
+  <fileHeader companyName="Unknown" productName="Unnamed" productVersion="1" creationDateTime="2022-06-01T10:00:00"/>
+  <contentHeader name="Blink" modificationDateTime="2022-06-01T10:00:00">
+    <coordinateInfo/>
+  </contentHeader>
+  <types>
+    <dataTypes>
+      <dataType name="Mode">
+        <baseType>
+          <enum><values><value name="Slow"/><value name="Fast"/></values></enum>
+        </baseType>
+      </dataType>
+    </dataTypes>
+    <pous>
+      <pou name="blink" pouType="program">
+        <interface>
+          <localVars>
+            <variable name="lamp" address="%QX0.0">
+              <type><BOOL/></type>
+              <initialValue><simpleValue value="FALSE"/></initialValue>
+            </variable>
+            <variable name="timer">
+              <type><derived name="TON"/></type>
+            </variable>
+            <variable name="counts">
+              <type>
+                <array>
+                  <dimension lower="1" upper="3"/>
+                  <baseType><INT/></baseType>
+                </array>
+              </type>
+              <initialValue>
+                <arrayValue>
+                  <value><simpleValue value="1"/></value>
+                  <value repetitionValue="2"><simpleValue value="0"/></value>
+                </arrayValue>
+              </initialValue>
+            </variable>
+          </localVars>
+          <localVars constant="true">
+            <variable name="period"><type><TIME/></type></variable>
+          </localVars>
+        </interface>
+        <body>
+          <ST>
+            <xhtml:p><![CDATA[timer(IN := NOT timer.Q, PT := T#1s);
+IF timer.Q THEN
+  lamp := NOT lamp;
+END_IF;]]></xhtml:p>
+          </ST>
+        </body>
+      </pou>
+    </pous>
+  </types>
+  <instances>
+    <configurations>
+      <configuration name="Config0">
+        <resource name="Res0">
+          <task name="task0" priority="0" interval="T#20ms">
+            <pouInstance name="instance0" typeName="blink"/>
+          </task>
+        </resource>
+      </configuration>
+    </configurations>
+  </instances>
+</project>
+"#;
+
+    #[test]
+    fn test_is_xml() {
+        assert!(is_xml("\u{feff}  <?xml version='1.0'?>"));
+        assert!(!is_xml("PROGRAM blink END_PROGRAM"));
+    }
+
+    #[test]
+    fn test_parse_project() {
+        let project = parse(BLINK).unwrap();
+        assert_eq!(project.pous.len(), 1);
+        let blink = &project.pous[0];
+        assert_eq!(blink.name, "blink");
+        assert_eq!(blink.pou_type, "PROGRAM");
+        assert_eq!(blink.variables.len(), 2);
+        assert!(blink.variables[1].constant);
+        assert_eq!(
+            blink.variables[0].variables[0],
+            Variable {
+                name: String::from("lamp"),
+                data_type: String::from("BOOL"),
+                address: Some(String::from("%QX0.0")),
+                initial_value: Some(String::from("FALSE")),
+            }
+        );
+
+        let resource = &project.configurations[0].resources[0];
+        assert_eq!(resource.tasks[0].interval.as_deref(), Some("T#20ms"));
+        assert_eq!(resource.tasks[0].instances[0].type_name, "blink");
+    }
+
+    #[test]
+    fn test_to_structured_text() {
+        let text = parse(BLINK).unwrap().to_structured_text();
+        assert_eq!(
+            text,
+            "TYPE
+  Mode : (Slow, Fast);
+END_TYPE
+
+PROGRAM blink
+  VAR
+    lamp AT %QX0.0 : BOOL := FALSE;
+    timer : TON;
+    counts : ARRAY [1..3] OF INT := [1, 2(0)];
+  END_VAR
+  VAR CONSTANT
+    period : TIME;
+  END_VAR
+  timer(IN := NOT timer.Q, PT := T#1s);
+  IF timer.Q THEN
+    lamp := NOT lamp;
+  END_IF;
+END_PROGRAM
+
+CONFIGURATION Config0
+  RESOURCE Res0 ON PLC
+    TASK task0(INTERVAL := T#20ms,PRIORITY := 0);
+    PROGRAM instance0 WITH task0 : blink;
+  END_RESOURCE
+END_CONFIGURATION
+"
+        );
+        assert_eq!(structured_text::validate(&text), vec![]);
+    }
+
+    fn error(source: &str) -> String {
+        format!("{:?}", parse(source).unwrap_err())
+    }
+
+    #[test]
+    fn test_invalid_projects() {
+        assert!(error("<project").contains("line: 1"));
+        assert!(error("<project/>").contains("not a PLCopen TC6 project"));
+        let ladder = BLINK.replace("<ST>", "<LD>").replace("</ST>", "</LD>");
+        let message = error(&ladder);
+        assert!(message.contains("only ST can be imported"));
+        assert!(message.contains("line: 46"));
+        let unnamed = BLINK.replace(
+            r#"<pou name="blink" pouType="program">"#,
+            "<pou pouType=\"program\">",
+        );
+        assert!(error(&unnamed).contains("<pou> needs a name attribute"));
+    }
+}
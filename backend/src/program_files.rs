@@ -0,0 +1,258 @@
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::fs::TempFile;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The directory for the program files if the configuration doesn't say.
+// This is the same directory that the original web server used.
+const DEFAULT_PROGRAMS_DIR: &str = "st_files";
+// The extension for a file that doesn't have one.
+const DEFAULT_EXTENSION: &str = "st";
+
+// The directory where we store the source of each program that is
+// uploaded. The programs table has the name of the file within this
+// directory.
+#[derive(Debug, Clone)]
+pub struct ProgramFiles {
+    dir: PathBuf,
+    // How many revisions of each program we keep (if we don't keep all).
+    keep_revisions: Option<usize>,
+}
+
+// Checks that the name is only a file name so that nobody can read or
+// write a file outside of the programs directory.
+fn is_plain_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !name.starts_with('.')
+        && !name.contains(['/', '\\'])
+}
+
+impl ProgramFiles {
+    pub fn new(dir: PathBuf) -> Self {
+        ProgramFiles {
+            dir,
+            keep_revisions: None,
+        }
+    }
+
+    // Reads the programs_dir and keep_program_revisions settings, using
+    // the defaults if they are not configured.
+    pub fn from_figment(figment: &Figment) -> Self {
+        let dir = figment
+            .extract_inner::<PathBuf>("programs_dir")
+            .unwrap_or_else(|_| PathBuf::from(DEFAULT_PROGRAMS_DIR));
+        ProgramFiles {
+            keep_revisions: figment.extract_inner("keep_program_revisions").ok(),
+            ..ProgramFiles::new(dir)
+        }
+    }
+
+    pub fn keep_revisions(&self) -> Option<usize> {
+        self.keep_revisions
+    }
+
+    // Gets the path to the stored file, rejecting any name that is not
+    // within the programs directory.
+    pub fn path(&self, file: &str) -> io::Result<PathBuf> {
+        if !is_plain_file_name(file) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a valid file name", file),
+            ));
+        }
+        Ok(self.dir.join(file))
+    }
+
+    // Writes the program into a new file and returns the name of the file.
+    pub fn save(&self, name: &str, data: &str) -> io::Result<String> {
+        let (file, mut f) = self.create(name)?;
+        f.write_all(data.as_bytes())?;
+        Ok(file)
+    }
+
+    // Same as save, but for a program that was uploaded as a file.
+    pub async fn save_upload(&self, name: &str, upload: &mut TempFile<'_>) -> io::Result<String> {
+        let (file, _) = self.create(name)?;
+        if let Err(e) = upload.copy_to(self.dir.join(&file)).await {
+            let _ = self.remove(&file);
+            return Err(e);
+        }
+        Ok(file)
+    }
+
+    // Creates a new empty file and returns the name of the file. The name
+    // is based on the name that was uploaded but is unique, so we never
+    // overwrite the file of another program.
+    fn create(&self, name: &str) -> io::Result<(String, File)> {
+        // The uploaded name must be valid even though we don't use it
+        // directly.
+        let path = self.path(name)?;
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("program");
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or(DEFAULT_EXTENSION);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+
+        fs::create_dir_all(&self.dir)?;
+        let mut attempt = 0;
+        loop {
+            let file = match attempt {
+                0 => format!("{}-{}.{}", stem, millis, extension),
+                n => format!("{}-{}-{}.{}", stem, millis, n, extension),
+            };
+            // Creating the file fails if it already exists so that two
+            // uploads at the same time can't pick the same name.
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.dir.join(&file))
+            {
+                Ok(f) => return Ok((file, f)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Returns the SHA-256 hash of the contents of the file as hex.
+    pub fn hash(&self, file: &str) -> io::Result<String> {
+        let data = fs::read(self.path(file)?)?;
+        Ok(format!("{:x}", Sha256::digest(&data)))
+    }
+
+    // Removes the file. A file that is already gone is not an error.
+    pub fn remove(&self, file: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(file)?) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    // Removes the files that nothing refers to any more. Whatever referred
+    // to them is already gone, so we only log the files that we can't
+    // remove.
+    pub fn remove_unused<I: IntoIterator<Item = String>>(&self, files: I) {
+        for file in files {
+            if let Err(e) = self.remove(&file) {
+                warn!("Unable to remove the program file {}: {}", file, e);
+            }
+        }
+    }
+}
+
+// Finds the programs directory once we know the configuration, creating
+// the directory if it doesn't exist yet.
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Program Files", |rocket| async {
+        let files = ProgramFiles::from_figment(rocket.figment());
+        match fs::create_dir_all(&files.dir) {
+            Ok(_) => Ok(rocket.manage(files)),
+            Err(e) => {
+                error!(
+                    "Unable to create the programs directory {}: {}",
+                    files.dir.display(),
+                    e
+                );
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(name: &str) -> ProgramFiles {
+        let dir = std::env::temp_dir().join(format!(
+            "openplc-program-files-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        ProgramFiles::new(dir)
+    }
+
+    #[test]
+    fn test_save_writes_unique_files() {
+        let files = files("unique");
+        let first = files.save("blink.st", "PROGRAM blink").unwrap();
+        let second = files.save("blink.st", "PROGRAM blink2").unwrap();
+        assert_ne!(first, second);
+        assert!(first.starts_with("blink-") && first.ends_with(".st"));
+        assert_eq!(
+            fs::read_to_string(files.path(&first).unwrap()).unwrap(),
+            "PROGRAM blink"
+        );
+        assert_eq!(
+            fs::read_to_string(files.path(&second).unwrap()).unwrap(),
+            "PROGRAM blink2"
+        );
+    }
+
+    #[test]
+    fn test_save_rejects_path_traversal() {
+        let files = files("traversal");
+        for name in [
+            "../blink.st",
+            "/etc/passwd",
+            "dir/blink.st",
+            "..",
+            ".hidden",
+            "",
+        ] {
+            let error = files.save(name, "").unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput, "{}", name);
+        }
+        assert!(files.path("..\\blink.st").is_err());
+        assert!(files.remove("../blink.st").is_err());
+    }
+
+    #[test]
+    fn test_hash_is_sha256_of_contents() {
+        let files = files("hash");
+        let file = files.save("blink.st", "abc").unwrap();
+        assert_eq!(
+            files.hash(&file).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_remove_deletes_file() {
+        let files = files("remove");
+        let file = files.save("blink.st", "").unwrap();
+        files.remove(&file).unwrap();
+        assert!(!files.path(&file).unwrap().exists());
+        // Removing it again is fine.
+        files.remove(&file).unwrap();
+    }
+
+    #[test]
+    fn test_remove_unused_carries_on_after_errors() {
+        let files = files("remove-unused");
+        let first = files.save("blink.st", "").unwrap();
+        let second = files.save("blink.st", "").unwrap();
+        files.remove_unused(vec![
+            first.clone(),
+            String::from("../blink.st"),
+            second.clone(),
+        ]);
+        assert!(!files.path(&first).unwrap().exists());
+        assert!(!files.path(&second).unwrap().exists());
+    }
+}
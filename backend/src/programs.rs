@@ -1,14 +1,30 @@
-use rocket::http::Status;
-use rocket::response::{status::Accepted, status::Created, status::NoContent, Debug};
-use rocket::serde::json::Json;
-use rocket::serde::{Deserialize, Serialize};
+use chrono::{TimeZone, Utc};
+use rocket::data::Capped;
+use rocket::form::Form;
+use rocket::fs::TempFile;
+use rocket::http::{Header, Status};
+use rocket::response::{status::Created, status::Custom, status::NoContent, Debug};
+use rocket::serde::json::{self, Json, Value};
+use rocket::serde::{Deserialize, Serialize, Serializer};
+use rocket::tokio::fs::{self, File};
 use rocket::{Build, State};
+use std::io::{self, ErrorKind};
+use std::path::Path;
 use std::time::Duration;
 
+use super::compilations::{Compilations, CompileLog, StoredCompilation};
+use super::deployments::{deploying, Deployment, Deployments};
+use super::logs::{LogSink, Stream};
+use super::pagination::{ListQuery, Page, PageResponse, Sort};
 use super::plc;
+use super::plcopen;
+use super::program_files::ProgramFiles;
 use super::response::*;
+use super::revisions::{revision_file, FilesInUse, ProgramRevision};
 use super::schema::programs;
 use super::sqlite::DbConn;
+use super::structured_text::{self, Diagnostic, Outline};
+use super::users::Operator;
 
 use self::diesel::prelude::*;
 use rocket_sync_db_pools::diesel;
@@ -20,12 +36,24 @@ type Result<T, E = Debug<diesel::result::Error>> = std::result::Result<T, E>;
 pub struct InsertableProgram {
     name: String,
     description: String,
-    // The name of the file on disk
+    // The name of the file that was uploaded. We store the file with a
+    // name based on this one.
     #[serde(rename = "fileName")]
     file: String,
+    // The contents of the file.
     data: String,
 }
 
+// The details of a program that we can change without uploading the
+// program again. We don't change what isn't given.
+#[derive(Debug, Clone, Deserialize, AsChangeset)]
+#[serde(crate = "rocket::serde")]
+#[table_name = "programs"]
+pub struct ProgramChanges {
+    name: Option<String>,
+    description: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Queryable, Insertable)]
 #[serde(crate = "rocket::serde")]
 #[table_name = "programs"]
@@ -37,111 +65,731 @@ pub struct Program {
     description: String,
     #[serde(rename = "fileName")]
     file: String,
-    #[serde(skip_deserializing)]
-    #[serde(rename = "createdAt")]
-    // TODO use chrono::{DateTime, Utc};
-    // TODO how to do this as DateTime<Utc>,
-    date_upload: i32,
+    // Seconds since the epoch when the program was uploaded.
+    #[serde(rename = "createdAt", serialize_with = "as_date_time")]
+    date_upload: i64,
+    // Seconds since the epoch when the metadata last changed (if it has).
+    #[serde(rename = "updatedAt", serialize_with = "as_optional_date_time")]
+    date_updated: Option<i64>,
+    // What we know about the program if it was imported, as JSON.
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "as_json")]
+    metadata: Option<String>,
+    // The outline of the source as JSON, which we return separately.
+    #[serde(skip)]
+    outline: Option<String>,
+    // The name of the file that was uploaded, which we download the source
+    // as. Programs from the original web server don't have one.
+    #[serde(skip)]
+    source_name: Option<String>,
+}
+
+// We store times as seconds since the epoch (the same as the original
+// web server), but return them as RFC 3339 dates.
+pub fn as_date_time<S: Serializer>(secs: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    Utc.timestamp(*secs, 0).serialize(serializer)
+}
+
+fn as_optional_date_time<S: Serializer>(
+    secs: &Option<i64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    secs.map(|secs| Utc.timestamp(secs, 0))
+        .serialize(serializer)
+}
+
+fn as_json<S: Serializer>(text: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    text.as_deref()
+        .and_then(|text| json::from_str::<Value>(text).ok())
+        .serialize(serializer)
 }
 
 impl Program {
-    fn from_insertable(program: InsertableProgram) -> Program {
+    // Creates the program for an upload that we stored in the file.
+    fn new(name: String, description: String, source: StoredSource) -> Program {
         Program {
             prog_id: None,
-            name: program.name,
-            description: program.description,
-            file: program.file,
-            // TODO fix the time
-            date_upload: 0,
+            name,
+            description,
+            file: source.file,
+            date_upload: Utc::now().timestamp(),
+            date_updated: None,
+            metadata: source.metadata,
+            outline: source.outline,
+            source_name: Some(source.name),
         }
     }
 
-    async fn create(db: DbConn, program: Program) -> Result<Program, diesel::result::Error> {
+    // Inserts the program with its file as the first revision.
+    async fn create(
+        db: DbConn,
+        program: Program,
+        hash: String,
+    ) -> Result<Program, diesel::result::Error> {
         db.run(move |conn| {
-            match diesel::insert_into(programs::table)
-                .values(&program)
-                .execute(conn)
-            {
-                Ok(_) => programs::table
-                    .filter(programs::name.eq(program.name))
-                    .limit(1)
-                    .first::<Program>(conn),
-                Err(e) => Err(e),
-            }
+            conn.transaction(|| {
+                diesel::insert_into(programs::table)
+                    .values(&program)
+                    .execute(conn)?;
+                let program = programs::table
+                    .order(programs::prog_id.desc())
+                    .first::<Program>(conn)?;
+                if let Some(id) = program.prog_id {
+                    ProgramRevision::insert(conn, id, program.file.clone(), hash)?;
+                }
+                Ok(program)
+            })
         })
         .await
     }
 
-    async fn get(db: DbConn, id: i32) -> Result<Program, diesel::result::Error> {
+    pub fn id(&self) -> Option<i32> {
+        self.prog_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    // Finds the program that has a revision with the file. Programs
+    // from before there were revisions only know their own file.
+    pub async fn find_by_file(db: DbConn, file: String) -> Result<Program, diesel::result::Error> {
+        db.run(
+            move |conn| match ProgramRevision::find_program(conn, &file) {
+                Ok(id) => programs::table.find(id).first::<Program>(conn),
+                Err(_) => programs::table
+                    .filter(programs::file.eq(file))
+                    .order(programs::prog_id.desc())
+                    .first::<Program>(conn),
+            },
+        )
+        .await
+    }
+
+    pub async fn get(db: &DbConn, id: i32) -> Result<Program, diesel::result::Error> {
         db.run(move |conn| programs::table.find(id).first::<Program>(conn))
             .await
     }
 
-    async fn all(db: DbConn) -> Result<Vec<Program>, diesel::result::Error> {
-        db.run(move |conn| programs::table.load(conn)).await
+    // Gets a page of the programs whose name or description match the
+    // pattern, and the number of programs that match.
+    async fn list(
+        db: DbConn,
+        sort: Sort,
+        pattern: Option<String>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<Program>, i64), diesel::result::Error> {
+        db.run(move |conn| {
+            let matching = || {
+                let mut query = programs::table.into_boxed();
+                if let Some(pattern) = &pattern {
+                    query = query.filter(
+                        programs::name
+                            .like(pattern.clone())
+                            .escape('\\')
+                            .or(programs::description.like(pattern.clone()).escape('\\')),
+                    );
+                }
+                query
+            };
+            let total = matching().count().get_result::<i64>(conn)?;
+            let query = match (sort.field(), sort.descending()) {
+                ("name", false) => matching().order(programs::name.asc()),
+                ("name", true) => matching().order(programs::name.desc()),
+                ("createdAt", false) => matching().order(programs::date_upload.asc()),
+                ("createdAt", true) => matching().order(programs::date_upload.desc()),
+                ("updatedAt", false) => matching().order(programs::date_updated.asc()),
+                ("updatedAt", true) => matching().order(programs::date_updated.desc()),
+                (_, false) => matching().order(programs::prog_id.asc()),
+                (_, true) => matching().order(programs::prog_id.desc()),
+            };
+            // Programs uploaded in the same second are in the order that
+            // they were uploaded.
+            let query = match sort.descending() {
+                false => query.then_order_by(programs::prog_id.asc()),
+                true => query.then_order_by(programs::prog_id.desc()),
+            };
+            let programs = query.limit(limit).offset(offset).load(conn)?;
+            Ok((programs, total))
+        })
+        .await
     }
 
-    async fn delete(db: DbConn, id: i32) -> Result<i32, diesel::result::Error> {
+    // Whether a program other than this one has the name.
+    fn name_taken(conn: &SqliteConnection, id: i32, name: &str) -> QueryResult<bool> {
+        programs::table
+            .filter(programs::name.eq(name))
+            .filter(programs::prog_id.ne(id))
+            .count()
+            .get_result::<i64>(conn)
+            .map(|count| count > 0)
+    }
+
+    // Changes the program unless another program already has the new
+    // name, in which case we change nothing and return None.
+    async fn update(
+        db: &DbConn,
+        id: i32,
+        changes: ProgramChanges,
+    ) -> Result<Option<Program>, diesel::result::Error> {
         db.run(move |conn| {
-            match diesel::delete(programs::table)
-                .filter(programs::prog_id.eq(id))
-                .execute(conn)
-            {
-                Ok(a) => {
-                    if a == 1 {
-                        Ok(id)
-                    } else {
-                        Err(diesel::result::Error::NotFound)
+            // Take the write lock before we check the name, so that nothing
+            // else can take the name before we change it.
+            conn.immediate_transaction(|| {
+                if let Some(name) = &changes.name {
+                    if Program::name_taken(conn, id, name)? {
+                        return Ok(None);
                     }
                 }
-                Err(e) => Err(e),
-            }
+                let updated = diesel::update(programs::table.find(id))
+                    .set((
+                        &changes,
+                        programs::date_updated.eq(Some(Utc::now().timestamp())),
+                    ))
+                    .execute(conn)?;
+                match updated {
+                    1 => programs::table.find(id).first::<Program>(conn).map(Some),
+                    _ => Err(diesel::result::Error::NotFound),
+                }
+            })
+        })
+        .await
+    }
+
+    // Deletes the program and its revisions and returns the files of the
+    // revisions.
+    async fn delete(db: DbConn, id: i32) -> Result<Vec<String>, diesel::result::Error> {
+        db.run(move |conn| {
+            // Take the write lock before we read the revisions, so that we
+            // wait for other writers rather than fail.
+            conn.immediate_transaction(|| {
+                let files = ProgramRevision::delete_all(conn, id)?;
+                StoredCompilation::delete_all(conn, id)?;
+                match diesel::delete(programs::table)
+                    .filter(programs::prog_id.eq(id))
+                    .execute(conn)?
+                {
+                    1 => Ok(files),
+                    _ => Err(diesel::result::Error::NotFound),
+                }
+            })
         })
         .await
     }
 }
 
-#[get("/programs")]
-async fn get_programs(db: DbConn) -> OkResponse<Vec<Program>> {
-    Program::all(db)
+// Lists the programs a page at a time, the newest first unless the client
+// sorts by id, name, createdAt or updatedAt.
+#[get("/programs?<list..>")]
+async fn get_programs(db: DbConn, list: ListQuery) -> PageResponse<Program> {
+    let sort = list.sort(&["id", "name", "createdAt", "updatedAt"], "-createdAt")?;
+    let offset = list.offset()?;
+    Program::list(db, sort, list.pattern(), list.limit(), offset)
         .await
-        .map(|programs| Ok(Json(programs)))
-        .map_err(|e| Error::from(e).to_response(Status::ImATeapot))?
+        .map(|(programs, total)| Page::new(programs, offset, total))
+        .map_err(|e| Error::from(e).to_response(Status::ImATeapot))
+}
+
+// The source of a program as a file to download.
+#[derive(Responder)]
+#[response(content_type = "text/plain")]
+pub struct ProgramSource {
+    file: File,
+    disposition: Header<'static>,
+}
+
+impl ProgramSource {
+    fn new(file: File, name: &str) -> Self {
+        // The name can't contain anything that would end the parameter.
+        let name = name.replace(['"', '\\'], "_");
+        ProgramSource {
+            file,
+            disposition: Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", name),
+            ),
+        }
+    }
+}
+
+// A program uploaded as a multipart form.
+#[derive(FromForm)]
+pub struct ProgramUpload<'r> {
+    name: String,
+    #[field(default = "")]
+    description: String,
+    file: Capped<TempFile<'r>>,
+}
+
+// The information about a program that is uploaded as the request body.
+#[derive(FromForm)]
+pub struct ProgramQuery {
+    name: String,
+    #[field(default = "")]
+    description: String,
+    #[field(name = "fileName")]
+    file: String,
+}
+
+// The limits on the size of uploads are the file and data-form limits
+// in the configuration.
+pub fn too_large() -> Custom<Json<Error>> {
+    Error::response(
+        Status::PayloadTooLarge,
+        "too_large",
+        "The program is larger than the upload limit",
+    )
+}
+
+pub fn storage_error(e: io::Error) -> Custom<Json<Error>> {
+    match e.kind() {
+        ErrorKind::InvalidInput => Error::response(
+            Status::BadRequest,
+            "invalid_file_name",
+            "The file name must be a file name without a directory",
+        ),
+        _ => Error::response(
+            Status::InternalServerError,
+            "storage",
+            "Unable to store the program file",
+        ),
+    }
+}
+
+// Rejects a program with syntax errors before the compiler sees it.
+pub fn check_syntax(data: &str) -> std::result::Result<(), Custom<Json<Error>>> {
+    let diagnostics = structured_text::validate(data);
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::invalid_program(diagnostics))
+    }
+}
+
+// Gets the Structured Text for the program, converting a PLCopen XML
+// project into Structured Text. For a project, we also return what the
+// project defines as JSON.
+pub fn import_source(
+    data: &str,
+) -> std::result::Result<(String, Option<String>), Custom<Json<Error>>> {
+    if !plcopen::is_xml(data) {
+        check_syntax(data)?;
+        return Ok((String::from(data), None));
+    }
+    let project = plcopen::parse(data).map_err(|d| Error::invalid_program(vec![d]))?;
+    let source = project.to_structured_text();
+    check_syntax(&source)?;
+    Ok((source, json::serde_json::to_string(&project).ok()))
+}
+
+// The name for the Structured Text of an uploaded file.
+fn source_name(name: &str, imported: bool) -> String {
+    if imported {
+        Path::new(name)
+            .with_extension("st")
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        String::from(name)
+    }
+}
+
+// The Structured Text that we stored for an upload and what we know
+// about it.
+pub struct StoredSource {
+    pub file: String,
+    // The name of the file that was uploaded, with the .st extension if we
+    // converted it to Structured Text.
+    pub name: String,
+    // What the project defines if we imported it, as JSON.
+    pub metadata: Option<String>,
+    // The outline of the Structured Text as JSON.
+    pub outline: Option<String>,
+}
+
+impl StoredSource {
+    fn new(file: String, name: String, metadata: Option<String>, source: &str) -> Self {
+        StoredSource {
+            file,
+            name,
+            metadata,
+            outline: json::serde_json::to_string(&structured_text::outline(source)).ok(),
+        }
+    }
+}
+
+// Stores the Structured Text for the program that was uploaded as the
+// name and returns the stored file and what we know about the program.
+pub fn save_source(
+    files: &ProgramFiles,
+    name: &str,
+    data: &str,
+) -> std::result::Result<StoredSource, Custom<Json<Error>>> {
+    let (source, metadata) = import_source(data)?;
+    let name = source_name(name, metadata.is_some());
+    let file = files.save(&name, &source).map_err(storage_error)?;
+    Ok(StoredSource::new(file, name, metadata, &source))
+}
+
+// Same as save_source for a program that we already stored as the file.
+// We replace the file if we convert the program and don't keep the file if
+// the program is rejected.
+pub fn import_stored(
+    files: &ProgramFiles,
+    name: &str,
+    file: String,
+) -> std::result::Result<StoredSource, Custom<Json<Error>>> {
+    let result = files
+        .path(&file)
+        .and_then(std::fs::read)
+        .map_err(storage_error)
+        .and_then(|data| {
+            let data = String::from_utf8_lossy(&data);
+            if plcopen::is_xml(&data) {
+                save_source(files, name, &data)
+            } else {
+                check_syntax(&data)
+                    .map(|_| StoredSource::new(file.clone(), String::from(name), None, &data))
+            }
+        });
+    if !matches!(&result, Ok(stored) if stored.file == file) {
+        let _ = files.remove(&file);
+    }
+    result
+}
+
+// Checks the syntax of the program without storing it.
+#[post("/programs/actions/validate", format = "json", data = "<program>")]
+fn validate_program(program: Json<InsertableProgram>) -> Json<Vec<Diagnostic>> {
+    Json(structured_text::validate(&program.data))
 }
 
 #[post("/programs", format = "json", data = "<program>")]
-async fn create_program(db: DbConn, program: Json<InsertableProgram>) -> CreatedResponse<Program> {
-    let program = Program::from_insertable(program.into_inner());
-    Program::create(db, program)
+async fn create_program(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    program: Json<InsertableProgram>,
+) -> CreatedResponse<Program> {
+    let program = program.into_inner();
+    let source = save_source(files, &program.file, &program.data)?;
+    let program = Program::new(program.name, program.description, source);
+    insert_program(db, files, program).await
+}
+
+#[post("/programs", format = "multipart/form-data", data = "<upload>")]
+async fn upload_program(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    mut upload: Form<ProgramUpload<'_>>,
+) -> CreatedResponse<Program> {
+    if !upload.file.is_complete() {
+        return Err(too_large());
+    }
+    // Rocket gives us the name without any directory or extension.
+    let name = format!("{}.st", upload.file.name().unwrap_or("program"));
+    let file = files
+        .save_upload(&name, &mut upload.file)
         .await
-        .map(|p| Ok(Created::new("/").body(Json(p))))
-        .map_err(|e| Error::from(e).to_response(Status::ImATeapot))?
+        .map_err(storage_error)?;
+    let source = import_stored(files, &name, file)?;
+    let upload = upload.into_inner();
+    let program = Program::new(upload.name, upload.description, source);
+    insert_program(db, files, program).await
 }
 
-#[delete("/programs/<id>")]
-async fn delete_program(db: DbConn, id: i32) -> NoContentResponse {
-    Program::delete(db, id)
+#[post("/programs?<query..>", format = "text/plain", data = "<data>")]
+async fn upload_program_text(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    query: ProgramQuery,
+    data: Capped<TempFile<'_>>,
+) -> CreatedResponse<Program> {
+    upload_program_body(db, files, query, data).await
+}
+
+#[post("/programs?<query..>", format = "binary", data = "<data>")]
+async fn upload_program_binary(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    query: ProgramQuery,
+    data: Capped<TempFile<'_>>,
+) -> CreatedResponse<Program> {
+    upload_program_body(db, files, query, data).await
+}
+
+// PLCopen XML projects that are uploaded as the request body.
+#[post("/programs?<query..>", format = "xml", data = "<data>")]
+async fn upload_program_xml(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    query: ProgramQuery,
+    data: Capped<TempFile<'_>>,
+) -> CreatedResponse<Program> {
+    upload_program_body(db, files, query, data).await
+}
+
+async fn upload_program_body(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    query: ProgramQuery,
+    mut data: Capped<TempFile<'_>>,
+) -> CreatedResponse<Program> {
+    if !data.is_complete() {
+        return Err(too_large());
+    }
+    let file = files
+        .save_upload(&query.file, &mut data)
         .await
-        .map(|r| Ok(NoContent))
-        .map_err(|e| Error::from(e).to_response(Status::ImATeapot))?
+        .map_err(storage_error)?;
+    let source = import_stored(files, &query.file, file)?;
+    let program = Program::new(query.name, query.description, source);
+    insert_program(db, files, program).await
+}
+
+async fn insert_program(
+    db: DbConn,
+    files: &ProgramFiles,
+    program: Program,
+) -> CreatedResponse<Program> {
+    let file = program.file.clone();
+    let hash = match files.hash(&file) {
+        Ok(hash) => hash,
+        Err(e) => {
+            let _ = files.remove(&file);
+            return Err(storage_error(e));
+        }
+    };
+    Program::create(db, program, hash)
+        .await
+        .map(|p| {
+            let location = format!("/programs/{}", p.id().unwrap_or_default());
+            Ok(Created::new(location).body(Json(p)))
+        })
+        .map_err(|e| {
+            // Nothing refers to the file, so don't keep it.
+            let _ = files.remove(&file);
+            Error::from(e).to_response(Status::ImATeapot)
+        })?
+}
+
+pub fn program_not_found(e: diesel::result::Error) -> Custom<Json<Error>> {
+    match e {
+        diesel::result::Error::NotFound => {
+            Error::response(Status::NotFound, "not_found", "The program does not exist")
+        }
+        e => Error::from(e).to_response(Status::InternalServerError),
+    }
+}
+
+#[get("/programs/<id>")]
+async fn get_program(db: DbConn, id: i32) -> OkResponse<Program> {
+    Program::get(&db, id)
+        .await
+        .map(Json)
+        .map_err(program_not_found)
+}
+
+// Changes the name or description of the program. The name must be unique.
+#[patch("/programs/<id>", format = "json", data = "<changes>")]
+async fn patch_program(db: DbConn, id: i32, changes: Json<ProgramChanges>) -> OkResponse<Program> {
+    let changes = changes.into_inner();
+    Program::get(&db, id).await.map_err(program_not_found)?;
+    Program::update(&db, id, changes)
+        .await
+        .map_err(program_not_found)?
+        .map(Json)
+        .ok_or_else(|| {
+            Error::response(
+                Status::Conflict,
+                "name_taken",
+                "Another program already has the name",
+            )
+        })
+}
+
+// Downloads the Structured Text of the program: the program as it was
+// uploaded, or what we converted a PLCopen XML project to. We name it after
+// the file that was uploaded rather than the file that we stored.
+#[get("/programs/<id>/source")]
+async fn get_program_source(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    id: i32,
+) -> std::result::Result<ProgramSource, Custom<Json<Error>>> {
+    let program = Program::get(&db, id).await.map_err(program_not_found)?;
+    let missing = || {
+        Error::response(
+            Status::NotFound,
+            "not_found",
+            "The source of the program is missing",
+        )
+    };
+    let path = files.path(&program.file).map_err(|_| missing())?;
+    let file = File::open(path).await.map_err(|_| missing())?;
+    let name = program.source_name.as_deref().unwrap_or(&program.file);
+    Ok(ProgramSource::new(file, name))
+}
+
+// Shows the POUs, tasks and I/O of the program, or of a revision of the
+// program.
+#[get("/programs/<id>/outline?<revision>")]
+async fn get_program_outline(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    id: i32,
+    revision: Option<i32>,
+) -> OkResponse<Outline> {
+    let program = Program::get(&db, id).await.map_err(program_not_found)?;
+    let stored = program
+        .outline
+        .as_deref()
+        .and_then(|outline| json::from_str::<Outline>(outline).ok());
+    if let (Some(outline), None) = (stored, revision) {
+        return Ok(Json(outline));
+    }
+
+    // We didn't store the outline for older revisions.
+    let file = revision_file(&db, program, revision).await?;
+    let missing = || {
+        Error::response(
+            Status::NotFound,
+            "not_found",
+            "The source of the program is missing",
+        )
+    };
+    let path = files.path(&file).map_err(|_| missing())?;
+    let data = fs::read(path).await.map_err(|_| missing())?;
+    Ok(Json(structured_text::outline(&String::from_utf8_lossy(
+        &data,
+    ))))
+}
+
+// Deletes the program and its revisions. We keep the files that the PLC
+// needs, such as the program that is running.
+#[delete("/programs/<id>")]
+async fn delete_program(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    in_use: FilesInUse,
+    id: i32,
+) -> NoContentResponse {
+    let program = Program::get(&db, id).await.map_err(program_not_found)?;
+    let mut removed = Program::delete(db, id).await.map_err(program_not_found)?;
+    if !removed.contains(&program.file) {
+        removed.push(program.file);
+    }
+
+    files.remove_unused(removed.into_iter().filter(|file| !in_use.contains(file)));
+    Ok(NoContent)
 }
 
-#[put("/programs/<id>/actions/compile")]
+// Compiles the newest revision of the program unless we are asked for a
+// particular revision.
+#[put("/programs/<id>/actions/compile?<revision>")]
 async fn compile_program(
     plc: &State<plc::SharedPlcStateMachine>,
+    compilations: &State<Compilations>,
+    deployments: &State<Deployments>,
+    operator: Operator,
+    db: DbConn,
+    id: i32,
+    revision: Option<i32>,
+) -> AcceptedAtResponse<CompileLog> {
+    if deployments.in_progress() {
+        return Err(deploying());
+    }
+    let program = Program::get(&db, id).await.map_err(program_not_found)?;
+    let file = revision_file(&db, program, revision).await?;
+
+    let log = compilations.begin(id, file.clone());
+    let event = plc::PlcEvent::Compile(file, log.clone());
+    plc.transition_as(event, Duration::from_secs(2), &operator.0)
+        .await
+        .map_err(|e| {
+            // The compiler never started so record why in the log.
+            log.append(Stream::Stderr, e.to_string());
+            log.finish(None);
+            Error::from_plc(e)
+        })?;
+
+    let compile_log = log.log();
+    Ok(AcceptedAt::new(
+        format!("/compilations/{}", compile_log.job_id()),
+        compile_log,
+    ))
+}
+
+// Stops the PLC, compiles the program and then runs it. The deployment
+// continues in the background and its phase is part of the state. As for
+// compiling, we can deploy any revision of the program.
+#[put("/programs/<id>/actions/deploy?<revision>")]
+async fn deploy_program(
+    plc: &State<plc::SharedPlcStateMachine>,
+    compilations: &State<Compilations>,
+    deployments: &State<Deployments>,
+    operator: Operator,
+    db: DbConn,
+    id: i32,
+    revision: Option<i32>,
+) -> AcceptedAtResponse<Deployment> {
+    let program = Program::get(&db, id).await.map_err(program_not_found)?;
+    let file = revision_file(&db, program, revision).await?;
+
+    let (deployment, compilation) = deployments
+        .begin(id, file.clone(), compilations)
+        .ok_or_else(deploying)?;
+
+    rocket::tokio::spawn(deployments.inner().clone().deploy(
+        plc.inner().clone(),
+        compilation,
+        file,
+        operator.0,
+    ));
+
+    Ok(AcceptedAt::new(String::from("/state"), deployment))
+}
+
+#[get("/programs/<id>/compileLogs")]
+async fn program_compile_logs(
     db: DbConn,
+    compilations: &State<Compilations>,
     id: i32,
-) -> AcceptedResponse {
-    let program = Program::get(db, id)
+) -> OkResponse<CompileLog> {
+    compilations
+        .find_history(&db, id)
         .await
-        // TODO this should be an error
-        .map_err(|e| Error::from(e).to_response(Status::ImATeapot))?;
+        .map_err(|e| Error::from(e).to_response(Status::InternalServerError))?
+        .into_iter()
+        .next()
+        .map(Json)
+        .ok_or_else(|| {
+            Error::response(
+                Status::NotFound,
+                "not_found",
+                "The program has not been compiled",
+            )
+        })
+}
 
-    plc.transition(plc::PlcEvent::Compile(program.file), Duration::from_secs(2))
+#[get("/programs/<id>/compilations")]
+async fn program_compilations(
+    db: DbConn,
+    compilations: &State<Compilations>,
+    id: i32,
+) -> OkResponse<Vec<CompileLog>> {
+    compilations
+        .find_history(&db, id)
         .await
-        .map(|_| Ok(Accepted::<()>(None)))
-        // TODO this should be an error
-        .map_err(|e| Error::response(Status::ImATeapot, "", ""))?
+        .map(Json)
+        .map_err(|e| Error::from(e).to_response(Status::InternalServerError))
 }
 
 pub fn mount(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
@@ -149,27 +797,636 @@ pub fn mount(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
         "/",
         routes![
             get_programs,
+            get_program,
+            patch_program,
+            get_program_source,
+            get_program_outline,
             create_program,
+            validate_program,
+            upload_program,
+            upload_program_text,
+            upload_program_binary,
+            upload_program_xml,
             delete_program,
             compile_program,
+            deploy_program,
+            program_compile_logs,
+            program_compilations,
         ],
     )
 }
 
 #[cfg(test)]
 mod test {
-    use super::super::plc::SharedPlcStateMachine;
-    use super::super::rocket;
+    use super::super::plc::{PlcEvent, SharedPlcStateMachine};
+    use super::super::plcopen::tests::BLINK;
+    use super::super::program_files::ProgramFiles;
+    use super::super::runtime::SimulatedRuntime;
+    use super::super::{test_client, test_plc, test_rocket};
+    use chrono::Utc;
     //use super::main::rocket;
-    use rocket::http::Status;
+    use rocket::http::{ContentType, Status};
     use rocket::local::blocking::Client;
+    use rocket::serde::json::Value;
+
+    fn client() -> Client {
+        client_with(&SimulatedRuntime::new())
+    }
+
+    fn client_with(runtime: &SimulatedRuntime) -> Client {
+        let (client, _plc) = test_client(runtime);
+        let status = client
+            .post("/programs")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Blink","description":"","fileName":"blink.st","data":""}"#)
+            .dispatch()
+            .status();
+        assert_eq!(status, Status::Created);
+        client
+    }
+
+    fn stored_file(client: &Client, file: &str) -> std::path::PathBuf {
+        let files = client.rocket().state::<ProgramFiles>().unwrap();
+        files.path(file).unwrap()
+    }
+
+    #[test]
+    fn create_program_stores_file() {
+        let client = client();
+        let response = client
+            .post("/programs")
+            .header(ContentType::JSON)
+            .body(
+                r#"{"name":"Flash","description":"","fileName":"blink.st","data":"PROGRAM flash END_PROGRAM"}"#,
+            )
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        assert_eq!(response.headers().get_one("Location"), Some("/programs/2"));
+        let program = response.into_json::<Value>().unwrap();
+        assert_eq!(program["id"], 2);
+        let file = program["fileName"].as_str().unwrap();
+        assert!(file.starts_with("blink-"));
+        assert_eq!(
+            std::fs::read_to_string(stored_file(&client, file)).unwrap(),
+            "PROGRAM flash END_PROGRAM"
+        );
+
+        let response = client
+            .delete(format!("/programs/{}", program["id"]))
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert!(!stored_file(&client, file).exists());
+    }
+
+    #[test]
+    fn delete_unknown_program_is_not_found() {
+        let client = client();
+        let response = client.delete("/programs/7").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn delete_program_keeps_running_file() {
+        let runtime = SimulatedRuntime::new();
+        let client = client_with(&runtime);
+        let plc = client.rocket().state::<SharedPlcStateMachine>().unwrap();
+        let program = client.get("/programs/1").dispatch().into_json::<Value>();
+        let file = String::from(program.unwrap()["fileName"].as_str().unwrap());
+        rocket::async_test(plc.run(PlcEvent::Run(file.clone()))).unwrap();
+
+        let response = client.delete("/programs/1").dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert!(stored_file(&client, &file).exists());
+        assert_eq!(runtime.program(), Some(file));
+    }
+
+    #[test]
+    fn get_program_and_source() {
+        let client = client();
+        let response = client
+            .post("/programs?name=Flash&fileName=flash.st")
+            .header(ContentType::Plain)
+            .body("PROGRAM flash\nEND_PROGRAM\n")
+            .dispatch();
+        let program = response.into_json::<Value>().unwrap();
+        let id = program["id"].as_i64().unwrap();
+        let file = program["fileName"].as_str().unwrap();
+
+        // The program was uploaded just now.
+        let created_at = program["createdAt"].as_str().unwrap();
+        let created_at = chrono::DateTime::parse_from_rfc3339(created_at).unwrap();
+        assert!((Utc::now().timestamp() - created_at.timestamp()).abs() < 60);
+
+        let response = client.get(format!("/programs/{}", id)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_json::<Value>().unwrap(), program);
+
+        // We store the file under a name of our own, but download it as the
+        // name that was uploaded.
+        assert_ne!(file, "flash.st");
+        let response = client.get(format!("/programs/{}/source", id)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::Plain));
+        assert_eq!(
+            response.headers().get_one("Content-Disposition"),
+            Some(r#"attachment; filename="flash.st""#)
+        );
+        assert_eq!(
+            response.into_string().unwrap(),
+            "PROGRAM flash\nEND_PROGRAM\n"
+        );
+    }
+
+    #[test]
+    fn get_missing_program_is_not_found() {
+        let client = client();
+        let response = client.get("/programs/7").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.get("/programs/7/source").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        // The client adds a program with a file, so take the file away.
+        let file = client
+            .get("/programs/1")
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap()["fileName"]
+            .as_str()
+            .map(String::from)
+            .unwrap();
+        std::fs::remove_file(stored_file(&client, &file)).unwrap();
+        let response = client.get("/programs/1/source").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn list_programs_a_page_at_a_time() {
+        let client = client();
+        for name in ["Flash", "Traffic lights"] {
+            let body = format!(
+                r#"{{"name":"{}","description":"A light","fileName":"light.st","data":""}}"#,
+                name
+            );
+            let status = client
+                .post("/programs")
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .status();
+            assert_eq!(status, Status::Created);
+        }
+
+        let response = client.get("/programs?limit=2&sort=id").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("X-Total-Count"), Some("3"));
+        assert_eq!(
+            response.headers().get_one("Link"),
+            Some(r#"</programs?limit=2&sort=id&cursor=2>; rel="next""#)
+        );
+        let programs = response.into_json::<Value>().unwrap();
+        assert_eq!(programs[0]["name"], "Blink");
+        assert_eq!(programs[1]["name"], "Flash");
+
+        let response = client.get("/programs?limit=2&sort=id&cursor=2").dispatch();
+        assert_eq!(response.headers().get_one("Link"), None);
+        let programs = response.into_json::<Value>().unwrap();
+        assert_eq!(programs.as_array().unwrap().len(), 1);
+        assert_eq!(programs[0]["name"], "Traffic lights");
+
+        // The newest first unless we ask otherwise.
+        let programs = client.get("/programs").dispatch().into_json::<Value>();
+        assert_eq!(programs.unwrap()[0]["name"], "Traffic lights");
+        let programs = client.get("/programs?sort=-name").dispatch();
+        assert_eq!(programs.into_json::<Value>().unwrap()[1]["name"], "Flash");
+
+        // Without a limit, the rest of the list.
+        let response = client.get("/programs?sort=id&offset=1").dispatch();
+        assert_eq!(response.headers().get_one("Link"), None);
+        let programs = response.into_json::<Value>().unwrap();
+        assert_eq!(programs.as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn search_programs() {
+        let client = client();
+        let status = client
+            .post("/programs")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Flash","description":"Flashes 100% of the time","fileName":"flash.st","data":""}"#)
+            .dispatch()
+            .status();
+        assert_eq!(status, Status::Created);
+
+        let response = client.get("/programs?q=flash").dispatch();
+        assert_eq!(response.headers().get_one("X-Total-Count"), Some("1"));
+        let response = client.get("/programs?q=100%25").dispatch();
+        let programs = response.into_json::<Value>().unwrap();
+        assert_eq!(programs[0]["name"], "Flash");
+        // The % is what we search for, not a wildcard.
+        let response = client.get("/programs?q=1%25%25").dispatch();
+        assert_eq!(response.headers().get_one("X-Total-Count"), Some("0"));
+
+        let response = client.get("/programs?sort=password").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn patch_program() {
+        let client = client();
+        let response = client
+            .patch("/programs/1")
+            .header(ContentType::JSON)
+            .body(r#"{"description":"Blinks the lamp"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let program = response.into_json::<Value>().unwrap();
+        assert_eq!(program["name"], "Blink");
+        assert_eq!(program["description"], "Blinks the lamp");
+        assert!(program["updatedAt"].is_string());
+
+        // Keeping the same name is not a conflict.
+        let response = client
+            .patch("/programs/1")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Blink"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .patch("/programs/1")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Flash"}"#)
+            .dispatch();
+        assert_eq!(response.into_json::<Value>().unwrap()["name"], "Flash");
+    }
+
+    #[test]
+    fn patch_program_name_taken() {
+        let client = client();
+        let status = client
+            .post("/programs")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Flash","description":"","fileName":"flash.st","data":""}"#)
+            .dispatch()
+            .status();
+        assert_eq!(status, Status::Created);
+
+        let response = client
+            .patch("/programs/2")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Blink","description":"Flashes"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        assert_eq!(response.into_json::<Value>().unwrap()["code"], "name_taken");
+        let program = client.get("/programs/2").dispatch();
+        assert_eq!(program.into_json::<Value>().unwrap()["description"], "");
+
+        let response = client
+            .patch("/programs/7")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Other"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn upload_program_as_form() {
+        let client = client();
+        let body = [
+            "--BOUNDARY",
+            r#"Content-Disposition: form-data; name="name""#,
+            "",
+            "Flash",
+            "--BOUNDARY",
+            r#"Content-Disposition: form-data; name="file"; filename="flash.st""#,
+            "Content-Type: text/plain",
+            "",
+            "PROGRAM flash END_PROGRAM",
+            "--BOUNDARY--",
+            "",
+        ]
+        .join("\r\n");
+        let response = client
+            .post("/programs")
+            .header(ContentType::with_params(
+                "multipart",
+                "form-data",
+                ("boundary", "BOUNDARY"),
+            ))
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let program = response.into_json::<Value>().unwrap();
+        assert_eq!(program["name"], "Flash");
+        assert_eq!(program["description"], "");
+        assert_eq!(program["updatedAt"], Value::Null);
+        let file = program["fileName"].as_str().unwrap();
+        assert!(file.starts_with("flash-") && file.ends_with(".st"));
+        assert_eq!(
+            std::fs::read_to_string(stored_file(&client, file)).unwrap(),
+            "PROGRAM flash END_PROGRAM"
+        );
+    }
+
+    #[test]
+    fn upload_program_as_body() {
+        let client = client();
+        for content_type in [ContentType::Plain, ContentType::Binary] {
+            let response = client
+                .post("/programs?name=Flash&description=Flashes&fileName=flash.st")
+                .header(content_type)
+                .body("PROGRAM flash END_PROGRAM")
+                .dispatch();
+            assert_eq!(response.status(), Status::Created);
+            let program = response.into_json::<Value>().unwrap();
+            assert_eq!(program["description"], "Flashes");
+            let file = program["fileName"].as_str().unwrap();
+            assert_eq!(
+                std::fs::read_to_string(stored_file(&client, file)).unwrap(),
+                "PROGRAM flash END_PROGRAM"
+            );
+        }
+
+        let response = client
+            .post("/programs?name=Evil&fileName=../evil.st")
+            .header(ContentType::Plain)
+            .body("PROGRAM evil END_PROGRAM")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn upload_program_over_limit() {
+        let rocket = test_rocket(test_plc(&SimulatedRuntime::new()));
+        let figment = rocket.figment().clone().merge(("limits.file", 8));
+        let client = Client::tracked(rocket.configure(figment)).expect("valid rocket instance");
+        let response = client
+            .post("/programs?name=Flash&fileName=flash.st")
+            .header(ContentType::Plain)
+            .body("PROGRAM flash END_PROGRAM")
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        let body = client.get("/programs").dispatch().into_string().unwrap();
+        assert!(!body.contains("Flash"));
+    }
+
+    #[test]
+    fn create_program_rejects_syntax_errors() {
+        let client = client();
+        let response = client
+            .post("/programs")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Broken","description":"","fileName":"broken.st","data":"PROGRAM broken\nIF x THEN\nEND_PROGRAM"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let error = response.into_json::<Value>().unwrap();
+        assert_eq!(error["code"], "invalid_program");
+        assert_eq!(error["diagnostics"][0]["line"], 3);
+        assert_eq!(error["diagnostics"][0]["column"], 1);
+
+        let response = client
+            .post("/programs?name=Broken&fileName=broken.st")
+            .header(ContentType::Plain)
+            .body("PROGRAM broken")
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body = client.get("/programs").dispatch().into_string().unwrap();
+        assert!(!body.contains("Broken"));
+        let files = client.rocket().state::<ProgramFiles>().unwrap();
+        let stored = std::fs::read_dir(files.path("broken.st").unwrap().parent().unwrap())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.file_name().to_string_lossy().starts_with("broken"));
+        assert!(!stored);
+    }
+
+    #[test]
+    fn validate_program() {
+        let client = client();
+        let response = client
+            .post("/programs/actions/validate")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Blink","description":"","fileName":"blink.st","data":"PROGRAM blink\nx := 1\ny := 2;\nEND_PROGRAM"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let diagnostics = response.into_json::<Value>().unwrap();
+        assert_eq!(diagnostics[0]["line"], 3);
+        assert_eq!(diagnostics[0]["message"], "Expected ';'");
+
+        let response = client
+            .post("/programs/actions/validate")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Blink","description":"","fileName":"blink.st","data":"PROGRAM blink END_PROGRAM"}"#)
+            .dispatch();
+        assert_eq!(response.into_string().unwrap(), "[]");
+        let body = client.get("/programs").dispatch().into_string().unwrap();
+        assert_eq!(body.matches(r#""name""#).count(), 1);
+    }
+
+    #[test]
+    fn get_program_outline() {
+        let client = client();
+        let status = client
+            .post("/programs/1/revisions?fileName=blink.st")
+            .header(ContentType::Plain)
+            .body("PROGRAM blink\nVAR\n  lamp AT %QX0.0 : BOOL;\nEND_VAR\nEND_PROGRAM\n")
+            .dispatch()
+            .status();
+        assert_eq!(status, Status::Created);
+
+        let response = client.get("/programs/1/outline").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let outline = response.into_json::<Value>().unwrap();
+        assert_eq!(outline["pous"][0]["name"], "blink");
+        assert_eq!(outline["locatedVariables"][0]["name"], "lamp");
+        assert_eq!(outline["locatedVariables"][0]["address"], "%QX0.0");
+        assert_eq!(outline["locatedVariables"][0]["area"], "output");
+        assert_eq!(outline["locatedVariables"][0]["type"], "BOOL");
+
+        // The first revision was empty.
+        let response = client.get("/programs/1/outline?revision=1").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let outline = response.into_json::<Value>().unwrap();
+        assert_eq!(outline["pous"].as_array().unwrap().len(), 0);
+
+        let response = client.get("/programs/1/outline?revision=7").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.get("/programs/7/outline").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn import_plcopen_project() {
+        let client = client();
+        let response = client
+            .post("/programs?name=Blink&fileName=blink.xml")
+            .header(ContentType::XML)
+            .body(BLINK)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let program = response.into_json::<Value>().unwrap();
+        let file = program["fileName"].as_str().unwrap();
+        assert!(file.starts_with("blink-") && file.ends_with(".st"));
+        let source = std::fs::read_to_string(stored_file(&client, file)).unwrap();
+        assert!(source.contains("PROGRAM blink\n"));
+        assert!(source.contains("TASK task0(INTERVAL := T#20ms,PRIORITY := 0);"));
+
+        // We download what we converted the project to.
+        let response = client.get("/programs/2/source").dispatch();
+        assert_eq!(
+            response.headers().get_one("Content-Disposition"),
+            Some(r#"attachment; filename="blink.st""#)
+        );
+        assert_eq!(response.into_string().unwrap(), source);
+
+        let metadata = &program["metadata"];
+        assert_eq!(metadata["pous"][0]["name"], "blink");
+        assert_eq!(metadata["pous"][0]["pouType"], "PROGRAM");
+        let task = &metadata["configurations"][0]["resources"][0]["tasks"][0];
+        assert_eq!(task["interval"], "T#20ms");
+        assert_eq!(task["instances"][0]["typeName"], "blink");
+
+        // Only the Structured Text is stored.
+        let dir = stored_file(&client, file).parent().unwrap().to_path_buf();
+        let xml = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.file_name().to_string_lossy().ends_with(".xml"));
+        assert!(!xml);
+    }
+
+    #[test]
+    fn import_invalid_plcopen_project() {
+        let client = client();
+        let ladder = BLINK.replace("<ST>", "<LD>").replace("</ST>", "</LD>");
+        let body = rocket::serde::json::serde_json::to_string(&rocket::serde::json::json!({
+            "name": "Ladder",
+            "description": "",
+            "fileName": "ladder.xml",
+            "data": ladder,
+        }))
+        .unwrap();
+        let response = client
+            .post("/programs")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let error = response.into_json::<Value>().unwrap();
+        assert_eq!(error["diagnostics"][0]["line"], 46);
+
+        let response = client
+            .post("/programs?name=Broken&fileName=broken.xml")
+            .header(ContentType::XML)
+            .body("<project>")
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn create_program_rejects_path_traversal() {
+        let client = client();
+        let response = client
+            .post("/programs")
+            .header(ContentType::JSON)
+            .body(r#"{"name":"Evil","description":"","fileName":"../../etc/passwd","data":""}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let body = client.get("/programs").dispatch().into_string().unwrap();
+        assert!(!body.contains("Evil"));
+    }
 
     #[test]
     fn compile_program() {
-        let state = SharedPlcStateMachine::new();
-        let client = Client::tracked(rocket(state)).expect("valid rocket instance");
+        let client = client();
         let response = client.put("/programs/1/actions/compile").dispatch();
-        assert_eq!(response.status(), Status::ImATeapot);
-        assert_eq!(response.into_string().unwrap(), "Hello, world!");
+        assert_eq!(response.status(), Status::Accepted);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("/compilations/1")
+        );
+        let body = response.into_string().unwrap();
+        assert!(body.contains(r#""jobId":1"#));
+        assert!(body.contains(r#""status":"running""#));
+
+        let response = client.get("/compilations/1").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.into_string().unwrap().contains(r#""programId":1"#));
+    }
+
+    #[test]
+    fn compile_missing_program_is_not_found() {
+        let client = client();
+        let response = client.put("/programs/7/actions/compile").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.into_json::<Value>().unwrap()["code"], "not_found");
+    }
+
+    #[test]
+    fn compile_program_runtime_fails() {
+        let runtime = SimulatedRuntime::new();
+        let client = client_with(&runtime);
+        runtime.fail_next("the compiler is missing");
+        let response = client.put("/programs/1/actions/compile").dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+        let error = response.into_json::<Value>().unwrap();
+        assert_eq!(error["code"], "runtime");
+        assert_eq!(error["detail"], "the compiler is missing");
+    }
+
+    #[test]
+    fn program_compilations() {
+        let client = client();
+        client.put("/programs/1/actions/compile").dispatch();
+        client.delete("/compilations/1").dispatch();
+        client.put("/programs/1/actions/compile").dispatch();
+
+        // The newest compilation is first.
+        let body = client
+            .get("/programs/1/compilations")
+            .dispatch()
+            .into_string()
+            .unwrap();
+        let second = body.find(r#""jobId":2"#).expect("second compilation");
+        let first = body.find(r#""jobId":1"#).expect("first compilation");
+        assert!(second < first);
+        assert!(body.contains(r#""status":"cancelled""#));
+
+        let body = client
+            .get("/programs/2/compilations")
+            .dispatch()
+            .into_string();
+        assert_eq!(body.unwrap(), "[]");
+
+        let response = client.get("/compilations/3").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn cancel_compilation() {
+        let client = client();
+        client.put("/programs/1/actions/compile").dispatch();
+
+        let response = client.delete("/compilations/1").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains(r#""status":"cancelled""#));
+        assert!(body.contains("Compilation cancelled"));
+
+        let response = client.get("/programs/1/compileLogs").dispatch();
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains(r#""status":"cancelled""#));
+
+        // Once cancelled, the compilation is no longer running.
+        let response = client.delete("/compilations/1").dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    #[test]
+    fn cancel_unknown_compilation() {
+        let client = client();
+        let response = client.delete("/compilations/7").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
     }
 }
@@ -1,14 +1,23 @@
 use diesel::result::Error as DieselError;
-use rocket::http::Status;
+use rocket::http::{Header, Status};
 use rocket::response::{status::Accepted, status::Created, status::Custom, status::NoContent};
 use rocket::serde::json::Json;
 use rocket::serde::Serialize;
 
+use super::plc::PlcError;
+use super::structured_text::Diagnostic;
+
 #[derive(Debug, Clone, Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct Error {
     code: &'static str,
     title: &'static str,
+    // Specific information about this occurrence of the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    // The problems with a program that we can't accept.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Error {
@@ -16,6 +25,8 @@ impl Error {
         Error {
             code: code,
             title: title,
+            detail: None,
+            diagnostics: Vec::new(),
         }
     }
 
@@ -23,9 +34,54 @@ impl Error {
         Error {
             code: "database",
             title: "help",
+            detail: None,
+            diagnostics: Vec::new(),
         }
     }
 
+    // Converts an error changing the PLC state into a response.
+    pub fn from_plc(error: PlcError) -> Custom<Json<Error>> {
+        let (status, code, title) = match &error {
+            PlcError::InvalidTransition(_) => (
+                Status::Conflict,
+                "invalid_transition",
+                "The PLC cannot do that in its current state",
+            ),
+            PlcError::Timeout => (
+                Status::ServiceUnavailable,
+                "timeout",
+                "Timed out waiting for the PLC to stop",
+            ),
+            PlcError::Runtime(_) => (
+                Status::InternalServerError,
+                "runtime",
+                "The PLC runtime failed",
+            ),
+        };
+        Custom(
+            status,
+            Json(Error {
+                code,
+                title,
+                detail: Some(error.to_string()),
+                diagnostics: Vec::new(),
+            }),
+        )
+    }
+
+    // The program has syntax errors, so the client needs to fix it.
+    pub fn invalid_program(diagnostics: Vec<Diagnostic>) -> Custom<Json<Error>> {
+        Custom(
+            Status::UnprocessableEntity,
+            Json(Error {
+                code: "invalid_program",
+                title: "The program is not valid Structured Text",
+                detail: None,
+                diagnostics,
+            }),
+        )
+    }
+
     pub fn to_response(self, status: Status) -> Custom<Json<Error>> {
         Custom(status, Json(self))
     }
@@ -53,3 +109,23 @@ pub type NoContentResponse = std::result::Result<NoContent, Custom<Json<Error>>>
 //
 // Typical uses are changing the state of the PLC.
 pub type AcceptedResponse = std::result::Result<Accepted<()>, Custom<Json<Error>>>;
+
+// Accepted with a description of the work that was started and the
+// location of a resource that the client can poll to follow the work.
+#[derive(Responder)]
+#[response(status = 202)]
+pub struct AcceptedAt<R> {
+    body: R,
+    location: Header<'static>,
+}
+
+impl<T: Serialize> AcceptedAt<Json<T>> {
+    pub fn new(location: String, body: T) -> Self {
+        AcceptedAt {
+            body: Json(body),
+            location: Header::new("Location", location),
+        }
+    }
+}
+
+pub type AcceptedAtResponse<T> = std::result::Result<AcceptedAt<Json<T>>, Custom<Json<Error>>>;
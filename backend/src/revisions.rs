@@ -0,0 +1,690 @@
+use chrono::Utc;
+use rocket::data::Capped;
+use rocket::form::Form;
+use rocket::fs::TempFile;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{status::Created, status::Custom};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Build, State};
+use std::convert::Infallible;
+
+use super::deployments::Deployments;
+use super::plc::SharedPlcStateMachine;
+use super::program_files::ProgramFiles;
+use super::programs::{
+    as_date_time, import_stored, program_not_found, save_source, storage_error, too_large, Program,
+    StoredSource,
+};
+use super::response::*;
+use super::schema::{program_revisions, programs};
+use super::settings::Settings;
+use super::sqlite::DbConn;
+
+use self::diesel::prelude::*;
+use rocket_sync_db_pools::diesel;
+
+type QueryResult<T> = std::result::Result<T, diesel::result::Error>;
+
+// Each upload of a program is a revision. The program refers to the file
+// of its newest revision, but we keep the older revisions so that we can
+// go back to them.
+#[derive(Debug, Clone, Serialize, Queryable, Insertable)]
+#[serde(crate = "rocket::serde")]
+#[table_name = "program_revisions"]
+pub struct ProgramRevision {
+    #[serde(skip)]
+    revision_id: Option<i32>,
+    #[serde(rename = "programId")]
+    prog_id: i32,
+    // Counts up from 1 for each program.
+    revision: i32,
+    #[serde(rename = "fileName")]
+    file: String,
+    // The SHA-256 hash of the contents of the file.
+    hash: String,
+    #[serde(rename = "createdAt", serialize_with = "as_date_time")]
+    date_upload: i64,
+}
+
+impl ProgramRevision {
+    // Adds the file as the next revision of the program.
+    pub fn insert(
+        conn: &SqliteConnection,
+        prog_id: i32,
+        file: String,
+        hash: String,
+    ) -> QueryResult<ProgramRevision> {
+        let latest = program_revisions::table
+            .filter(program_revisions::prog_id.eq(prog_id))
+            .select(diesel::dsl::max(program_revisions::revision))
+            .first::<Option<i32>>(conn)?;
+        let revision = ProgramRevision {
+            revision_id: None,
+            prog_id,
+            revision: latest.unwrap_or(0) + 1,
+            file,
+            hash,
+            date_upload: Utc::now().timestamp(),
+        };
+        diesel::insert_into(program_revisions::table)
+            .values(&revision)
+            .execute(conn)?;
+        ProgramRevision::get(conn, prog_id, revision.revision)
+    }
+
+    pub fn get(
+        conn: &SqliteConnection,
+        prog_id: i32,
+        revision: i32,
+    ) -> QueryResult<ProgramRevision> {
+        program_revisions::table
+            .filter(program_revisions::prog_id.eq(prog_id))
+            .filter(program_revisions::revision.eq(revision))
+            .first(conn)
+    }
+
+    pub fn latest(conn: &SqliteConnection, prog_id: i32) -> QueryResult<ProgramRevision> {
+        program_revisions::table
+            .filter(program_revisions::prog_id.eq(prog_id))
+            .order(program_revisions::revision.desc())
+            .first(conn)
+    }
+
+    pub fn revision(&self) -> i32 {
+        self.revision
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    // Gets the revisions of the program, newest first.
+    pub fn all(conn: &SqliteConnection, prog_id: i32) -> QueryResult<Vec<ProgramRevision>> {
+        program_revisions::table
+            .filter(program_revisions::prog_id.eq(prog_id))
+            .order(program_revisions::revision.desc())
+            .load(conn)
+    }
+
+    // Finds the program that has a revision with the file.
+    pub fn find_program(conn: &SqliteConnection, file: &str) -> QueryResult<i32> {
+        program_revisions::table
+            .filter(program_revisions::file.eq(file))
+            .select(program_revisions::prog_id)
+            .first(conn)
+    }
+
+    // Removes all but the newest revisions of the program and returns the
+    // files that nothing refers to any more. The revisions with files that
+    // are in use are kept no matter how old they are.
+    pub fn prune(
+        conn: &SqliteConnection,
+        prog_id: i32,
+        keep: usize,
+        in_use: &[String],
+    ) -> QueryResult<Vec<String>> {
+        // We always keep the newest revision because the program uses it.
+        let old = ProgramRevision::all(conn, prog_id)?
+            .into_iter()
+            .skip(keep.max(1))
+            .filter(|r| !in_use.contains(&r.file))
+            .collect::<Vec<_>>();
+        diesel::delete(program_revisions::table)
+            .filter(program_revisions::revision_id.eq_any(old.iter().filter_map(|r| r.revision_id)))
+            .execute(conn)?;
+        Ok(old.into_iter().map(|r| r.file).collect())
+    }
+
+    // Removes every revision of the program and returns their files.
+    pub fn delete_all(conn: &SqliteConnection, prog_id: i32) -> QueryResult<Vec<String>> {
+        let files = program_revisions::table
+            .filter(program_revisions::prog_id.eq(prog_id))
+            .select(program_revisions::file)
+            .load::<String>(conn)?;
+        diesel::delete(program_revisions::table)
+            .filter(program_revisions::prog_id.eq(prog_id))
+            .execute(conn)?;
+        Ok(files)
+    }
+}
+
+// A new revision with the contents of the file in the request.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct InsertableRevision {
+    #[serde(rename = "fileName")]
+    file: String,
+    data: String,
+}
+
+// A new revision uploaded as a multipart form.
+#[derive(FromForm)]
+pub struct RevisionUpload<'r> {
+    file: Capped<TempFile<'r>>,
+}
+
+// The name of the file for a revision that is uploaded as the request
+// body.
+#[derive(FromForm)]
+pub struct RevisionQuery {
+    #[field(name = "fileName")]
+    file: String,
+}
+
+// The files that the PLC needs, so we must not remove their revisions:
+// the program that is running or was compiled last (including the one that
+// we start in run mode), and the program that a deployment goes back to if
+// the new program doesn't start.
+pub struct FilesInUse(Vec<String>);
+
+impl FilesInUse {
+    pub fn contains(&self, file: &str) -> bool {
+        self.0.iter().any(|in_use| in_use == file)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for FilesInUse {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let rocket = request.rocket();
+        let plc = rocket
+            .state::<SharedPlcStateMachine>()
+            .and_then(|plc| plc.snapshot().program);
+        let rollback = rocket
+            .state::<Deployments>()
+            .and_then(|deployments| deployments.rollback_target());
+        let compiled = match request.guard::<DbConn>().await {
+            Outcome::Success(db) => Settings::last_compiled(&db)
+                .await
+                .ok()
+                .flatten()
+                .and_then(|last| last.file),
+            _ => None,
+        };
+        let files = plc.into_iter().chain(rollback).chain(compiled).collect();
+        Outcome::Success(FilesInUse(files))
+    }
+}
+
+// The revision that an upload made, or the newest revision if the upload
+// is the same as it.
+#[derive(Responder)]
+pub enum RevisionResponse {
+    Created(Created<Json<ProgramRevision>>),
+    #[response(status = 200)]
+    Unchanged(Json<ProgramRevision>),
+}
+
+type RevisionResult = std::result::Result<RevisionResponse, Custom<Json<Error>>>;
+
+pub fn revision_not_found(e: diesel::result::Error) -> Custom<Json<Error>> {
+    match e {
+        diesel::result::Error::NotFound => Error::response(
+            Status::NotFound,
+            "not_found",
+            "The program revision does not exist",
+        ),
+        e => Error::from(e).to_response(Status::InternalServerError),
+    }
+}
+
+// Finds the file of a revision of the program, or the newest revision if
+// we weren't asked for a particular revision.
+pub async fn revision_file(
+    db: &DbConn,
+    program: Program,
+    revision: Option<i32>,
+) -> std::result::Result<String, Custom<Json<Error>>> {
+    let (id, revision) = match (program.id(), revision) {
+        (Some(id), Some(revision)) => (id, revision),
+        _ => return Ok(program.file().to_string()),
+    };
+    db.run(move |conn| ProgramRevision::get(conn, id, revision))
+        .await
+        .map(|revision| revision.file)
+        .map_err(revision_not_found)
+}
+
+#[get("/programs/<id>/revisions")]
+async fn get_revisions(db: DbConn, id: i32) -> OkResponse<Vec<ProgramRevision>> {
+    Program::get(&db, id).await.map_err(program_not_found)?;
+    db.run(move |conn| ProgramRevision::all(conn, id))
+        .await
+        .map(Json)
+        .map_err(|e| Error::from(e).to_response(Status::InternalServerError))
+}
+
+#[get("/programs/<id>/revisions/<revision>")]
+async fn get_revision(db: DbConn, id: i32, revision: i32) -> OkResponse<ProgramRevision> {
+    db.run(move |conn| ProgramRevision::get(conn, id, revision))
+        .await
+        .map(Json)
+        .map_err(revision_not_found)
+}
+
+#[post("/programs/<id>/revisions", format = "json", data = "<revision>")]
+async fn create_revision(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    in_use: FilesInUse,
+    id: i32,
+    revision: Json<InsertableRevision>,
+) -> RevisionResult {
+    Program::get(&db, id).await.map_err(program_not_found)?;
+    let source = save_source(files, &revision.file, &revision.data)?;
+    insert_revision(db, files, in_use, id, source).await
+}
+
+#[post(
+    "/programs/<id>/revisions",
+    format = "multipart/form-data",
+    data = "<upload>"
+)]
+async fn upload_revision(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    in_use: FilesInUse,
+    id: i32,
+    mut upload: Form<RevisionUpload<'_>>,
+) -> RevisionResult {
+    Program::get(&db, id).await.map_err(program_not_found)?;
+    if !upload.file.is_complete() {
+        return Err(too_large());
+    }
+    // Rocket gives us the name without any directory or extension.
+    let name = format!("{}.st", upload.file.name().unwrap_or("program"));
+    let file = files
+        .save_upload(&name, &mut upload.file)
+        .await
+        .map_err(storage_error)?;
+    let source = import_stored(files, &name, file)?;
+    insert_revision(db, files, in_use, id, source).await
+}
+
+#[post(
+    "/programs/<id>/revisions?<query..>",
+    format = "text/plain",
+    data = "<data>"
+)]
+async fn upload_revision_text(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    in_use: FilesInUse,
+    id: i32,
+    query: RevisionQuery,
+    data: Capped<TempFile<'_>>,
+) -> RevisionResult {
+    upload_revision_body(db, files, in_use, id, query, data).await
+}
+
+#[post(
+    "/programs/<id>/revisions?<query..>",
+    format = "binary",
+    data = "<data>"
+)]
+async fn upload_revision_binary(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    in_use: FilesInUse,
+    id: i32,
+    query: RevisionQuery,
+    data: Capped<TempFile<'_>>,
+) -> RevisionResult {
+    upload_revision_body(db, files, in_use, id, query, data).await
+}
+
+#[post("/programs/<id>/revisions?<query..>", format = "xml", data = "<data>")]
+async fn upload_revision_xml(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    in_use: FilesInUse,
+    id: i32,
+    query: RevisionQuery,
+    data: Capped<TempFile<'_>>,
+) -> RevisionResult {
+    upload_revision_body(db, files, in_use, id, query, data).await
+}
+
+async fn upload_revision_body(
+    db: DbConn,
+    files: &State<ProgramFiles>,
+    in_use: FilesInUse,
+    id: i32,
+    query: RevisionQuery,
+    mut data: Capped<TempFile<'_>>,
+) -> RevisionResult {
+    Program::get(&db, id).await.map_err(program_not_found)?;
+    if !data.is_complete() {
+        return Err(too_large());
+    }
+    let file = files
+        .save_upload(&query.file, &mut data)
+        .await
+        .map_err(storage_error)?;
+    let source = import_stored(files, &query.file, file)?;
+    insert_revision(db, files, in_use, id, source).await
+}
+
+// Makes the stored file the newest revision of the program and then
+// removes the revisions that we no longer keep. The metadata of the
+// program is now the metadata of this revision, and the same for the
+// outline. If the file is the same as the newest revision, then we keep
+// that revision instead.
+async fn insert_revision(
+    db: DbConn,
+    files: &ProgramFiles,
+    in_use: FilesInUse,
+    id: i32,
+    source: StoredSource,
+) -> RevisionResult {
+    let StoredSource {
+        file,
+        name,
+        metadata,
+        outline,
+    } = source;
+    let hash = match files.hash(&file) {
+        Ok(hash) => hash,
+        Err(e) => {
+            let _ = files.remove(&file);
+            return Err(storage_error(e));
+        }
+    };
+    let keep = files.keep_revisions();
+    let stored = file.clone();
+    let result = db
+        .run(move |conn| {
+            // Take the write lock before we read the newest revision, so
+            // that we wait for other writers rather than fail.
+            conn.immediate_transaction(|| {
+                let latest = ProgramRevision::latest(conn, id)?;
+                if latest.hash == hash {
+                    return Ok((latest, Vec::new(), false));
+                }
+                let revision = ProgramRevision::insert(conn, id, file, hash)?;
+                diesel::update(programs::table.find(id))
+                    .set((
+                        programs::file.eq(&revision.file),
+                        programs::date_updated.eq(Some(revision.date_upload)),
+                        programs::metadata.eq(metadata),
+                        programs::outline.eq(outline),
+                        programs::source_name.eq(Some(name)),
+                    ))
+                    .execute(conn)?;
+                let pruned = match keep {
+                    Some(keep) => ProgramRevision::prune(conn, id, keep, &in_use.0)?,
+                    None => Vec::new(),
+                };
+                Ok((revision, pruned, true))
+            })
+        })
+        .await;
+
+    let (revision, pruned, created) = result.map_err(|e| {
+        // Nothing refers to the file, so don't keep it.
+        let _ = files.remove(&stored);
+        Error::from(e).to_response(Status::InternalServerError)
+    })?;
+    files.remove_unused(pruned);
+
+    if !created {
+        // Nothing refers to the file, so don't keep it.
+        let _ = files.remove(&stored);
+        return Ok(RevisionResponse::Unchanged(Json(revision)));
+    }
+
+    let location = format!("/programs/{}/revisions/{}", id, revision.revision);
+    Ok(RevisionResponse::Created(
+        Created::new(location).body(Json(revision)),
+    ))
+}
+
+pub fn mount(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
+    rocket.mount(
+        "/",
+        routes![
+            get_revisions,
+            get_revision,
+            create_revision,
+            upload_revision,
+            upload_revision_text,
+            upload_revision_binary,
+            upload_revision_xml,
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::runtime::SimulatedRuntime;
+    use super::super::{test_plc, test_rocket};
+    use super::*;
+    use rocket::http::ContentType;
+    use rocket::local::blocking::Client;
+    use rocket::serde::json::Value;
+
+    // Creates a client with one program that keeps the number of
+    // revisions.
+    fn client(keep: usize) -> Client {
+        let rocket = test_rocket(test_plc(&SimulatedRuntime::new()));
+        let figment = rocket
+            .figment()
+            .clone()
+            .merge(("keep_program_revisions", keep));
+        let client = Client::tracked(rocket.configure(figment)).expect("valid rocket instance");
+        let status = client
+            .post("/programs")
+            .header(ContentType::JSON)
+            .body(
+                r#"{"name":"Blink","description":"","fileName":"blink.st","data":"PROGRAM blink END_PROGRAM"}"#,
+            )
+            .dispatch()
+            .status();
+        assert_eq!(status, Status::Created);
+        client
+    }
+
+    fn upload(client: &Client, data: &str) -> Value {
+        let response = client
+            .post("/programs/1/revisions?fileName=blink.st")
+            .header(ContentType::Plain)
+            .body(data)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        response.into_json::<Value>().unwrap()
+    }
+
+    fn revisions(client: &Client) -> Vec<Value> {
+        let response = client.get("/programs/1/revisions").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json::<Vec<Value>>().unwrap()
+    }
+
+    fn stored_file(client: &Client, file: &Value) -> std::path::PathBuf {
+        let files = client.rocket().state::<ProgramFiles>().unwrap();
+        files.path(file.as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn upload_creates_revision() {
+        let client = client(10);
+        let response = client
+            .post("/programs/1/revisions")
+            .header(ContentType::JSON)
+            .body(r#"{"fileName":"blink.st","data":"PROGRAM abc END_PROGRAM"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("/programs/1/revisions/2")
+        );
+        let revision = response.into_json::<Value>().unwrap();
+        assert_eq!(revision["programId"], 1);
+        assert_eq!(revision["revision"], 2);
+        assert_eq!(
+            revision["hash"],
+            "9bfa2e48709d14082093d82a81049c2346cb1ffc96caa5ae2616bf4dbeb2d0ec"
+        );
+
+        // The program now uses the newest revision.
+        let program = client
+            .get("/programs/1")
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        assert_eq!(program["fileName"], revision["fileName"]);
+        assert_ne!(program["updatedAt"], Value::Null);
+
+        let response = client.get("/programs/1/revisions/2").dispatch();
+        assert_eq!(response.into_json::<Value>().unwrap(), revision);
+    }
+
+    #[test]
+    fn list_revisions_newest_first() {
+        let client = client(10);
+        upload(&client, "PROGRAM blink2 END_PROGRAM");
+        upload(&client, "PROGRAM blink3 END_PROGRAM");
+
+        let revisions = revisions(&client);
+        let numbers = revisions
+            .iter()
+            .map(|r| r["revision"].as_i64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, vec![3, 2, 1]);
+        // Each revision has its own file.
+        assert_ne!(revisions[0]["fileName"], revisions[1]["fileName"]);
+        assert_ne!(revisions[0]["hash"], revisions[1]["hash"]);
+    }
+
+    #[test]
+    fn revisions_of_missing_program_are_not_found() {
+        let client = client(10);
+        let response = client.get("/programs/7/revisions").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.get("/programs/1/revisions/7").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .post("/programs/7/revisions?fileName=blink.st")
+            .header(ContentType::Plain)
+            .body("PROGRAM blink END_PROGRAM")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn compile_revision() {
+        let client = client(10);
+        upload(&client, "PROGRAM blink2 END_PROGRAM");
+
+        let response = client
+            .put("/programs/1/actions/compile?revision=1")
+            .dispatch();
+        assert_eq!(response.status(), Status::Accepted);
+        client.delete("/compilations/1").dispatch();
+
+        let response = client
+            .put("/programs/1/actions/compile?revision=7")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .put("/programs/1/actions/deploy?revision=7")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn prune_old_revisions() {
+        let client = client(2);
+        let first = revisions(&client).remove(0);
+        upload(&client, "PROGRAM blink2 END_PROGRAM");
+        upload(&client, "PROGRAM blink3 END_PROGRAM");
+
+        let revisions = revisions(&client);
+        let numbers = revisions
+            .iter()
+            .map(|r| r["revision"].as_i64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, vec![3, 2]);
+        assert!(!stored_file(&client, &first["fileName"]).exists());
+        assert!(stored_file(&client, &revisions[1]["fileName"]).exists());
+    }
+
+    #[test]
+    fn prune_keeps_revisions_in_use() {
+        let client = client(1);
+        let first = revisions(&client).remove(0);
+        let response = client.put("/programs/1/actions/compile").dispatch();
+        assert_eq!(response.status(), Status::Accepted);
+
+        upload(&client, "PROGRAM blink2 END_PROGRAM");
+        upload(&client, "PROGRAM blink3 END_PROGRAM");
+
+        // The PLC has the first revision, so we keep it.
+        let revisions = revisions(&client);
+        let numbers = revisions
+            .iter()
+            .map(|r| r["revision"].as_i64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, vec![3, 1]);
+        assert!(stored_file(&client, &first["fileName"]).exists());
+    }
+
+    #[test]
+    fn prune_keeps_revision_that_starts_in_run() {
+        let client = client(1);
+        let first = revisions(&client).remove(0);
+        let file = String::from(first["fileName"].as_str().unwrap());
+        rocket::async_test(async {
+            let db = DbConn::get_one(client.rocket()).await.unwrap();
+            db.run(move |conn| Settings::set_last_compiled(conn, 1, file))
+                .await
+                .unwrap();
+        });
+
+        upload(&client, "PROGRAM blink2 END_PROGRAM");
+        upload(&client, "PROGRAM blink3 END_PROGRAM");
+        let numbers = revisions(&client)
+            .iter()
+            .map(|r| r["revision"].as_i64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, vec![3, 1]);
+    }
+
+    #[test]
+    fn upload_same_file_keeps_revision() {
+        let client = client(10);
+        let before = revisions(&client);
+        let response = client
+            .post("/programs/1/revisions?fileName=blink.st")
+            .header(ContentType::Plain)
+            .body("PROGRAM blink END_PROGRAM")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_json::<Value>().unwrap(), before[0]);
+        assert_eq!(revisions(&client), before);
+
+        // The upload isn't kept.
+        let file = stored_file(&client, &before[0]["fileName"]);
+        let stored = std::fs::read_dir(file.parent().unwrap()).unwrap().count();
+        assert_eq!(stored, 1);
+    }
+
+    #[test]
+    fn delete_program_removes_revisions() {
+        let client = client(10);
+        upload(&client, "PROGRAM blink2 END_PROGRAM");
+        let revisions = revisions(&client);
+
+        let response = client.delete("/programs/1").dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        for revision in revisions {
+            assert!(!stored_file(&client, &revision["fileName"]).exists());
+        }
+        let response = client.get("/programs/1/revisions").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
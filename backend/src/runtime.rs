@@ -0,0 +1,869 @@
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sysinfo::{Pid, PidExt, ProcessExt, Signal, System, SystemExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command as TokioCommand;
+
+use super::compilations::Compilation;
+use super::logs::{LogBuffer, LogSink, Stream};
+
+// The PLC runtime is the thing that actually knows how to manipulate
+// processes on the machine. The state machine decides what should happen
+// and the runtime makes it so.
+//
+// There are two runtimes:
+//
+// * ProcessRuntime runs the OpenPLC scripts and binaries as OS processes.
+// * SimulatedRuntime keeps an in-memory model of processes so that the
+//   state machine can be tested without an OpenPLC installation.
+pub trait PlcRuntime: Send + Sync {
+    // Determine if the PLC is already running (it may have been started
+    // without the web server), returning the process ID if so.
+    fn discover(&mut self) -> Result<Option<Pid>, String>;
+
+    // Start the PLC running the specified program.
+    fn start(&mut self, file: &str) -> Result<Pid, String>;
+
+    // Ask the process to stop (SIGTERM). This doesn't wait for the process
+    // to exit - use is_alive to determine when it is gone.
+    fn stop(&mut self, pid: Pid) -> Result<(), String>;
+
+    // Forcibly terminate the process (SIGKILL). Used when the process
+    // didn't respond to stop.
+    fn kill(&mut self, pid: Pid) -> Result<(), String>;
+
+    // Forcibly terminate the process and every process that it started
+    // (SIGKILL). The compiler runs other programs, so killing only the
+    // compiler would leave them running.
+    fn kill_tree(&mut self, pid: Pid) -> Result<(), String>;
+
+    // Start compiling the specified program. Compilation continues
+    // in the background while the process is alive, writing the output
+    // of the compiler into the log.
+    fn compile(&mut self, file: &str, log: Compilation) -> Result<Pid, String>;
+
+    // Change the hardware layer. This completes before returning.
+    fn change_hardware(&mut self, name: &str) -> Result<(), String>;
+
+    // Returns whether the process is still running.
+    fn is_alive(&mut self, pid: Pid) -> bool;
+
+    // Returns when the process started (in seconds since the epoch).
+    fn start_time(&self, pid: Pid) -> Option<u64>;
+}
+
+// How long we look for the PLC process after running the start command.
+// The start command may start the PLC in the background, so the PLC might
+// not be running as soon as we have started the command.
+const START_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+const START_DISCOVERY_INTERVAL: Duration = Duration::from_millis(50);
+
+// Returns the command to start the PLC.
+fn plc_start_command() -> &'static str {
+    let command = if cfg!(unix) {
+        "openplc"
+    } else if cfg!(windows) {
+        "startplc.bat"
+    } else {
+        "unknown"
+    };
+    command
+}
+
+fn plc_process_name() -> &'static str {
+    let process = if cfg!(unix) {
+        "openplc"
+    } else if cfg!(windows) {
+        "openplc.exe"
+    } else {
+        "unknown"
+    };
+    process
+}
+
+fn plc_compile_command() -> &'static str {
+    let command = if cfg!(unix) {
+        "compile"
+    } else if cfg!(windows) {
+        "compile.bat"
+    } else {
+        "unknown"
+    };
+    command
+}
+
+fn plc_change_hardware_command() -> &'static str {
+    let command = if cfg!(unix) {
+        "change_hardware_layer.sh"
+    } else if cfg!(windows) {
+        "change_hardware_layer.bat"
+    } else {
+        "unknown"
+    };
+    command
+}
+
+// The placeholders that we replace in the arguments of a command.
+const FILE_PLACEHOLDER: &str = "{file}";
+const HARDWARE_PLACEHOLDER: &str = "{hardware}";
+
+// How to run one of the OpenPLC commands.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CommandConfig {
+    // The program to run. A program that is just a name is found on the
+    // PATH, otherwise relative paths are relative to the scripts directory.
+    program: PathBuf,
+    // The arguments, where {file} is replaced by the program file and
+    // {hardware} is replaced by the hardware layer.
+    #[serde(default)]
+    args: Vec<String>,
+    // The directory to run the command in (relative to the root).
+    // Defaults to the root.
+    #[serde(default)]
+    working_dir: Option<PathBuf>,
+}
+
+impl CommandConfig {
+    fn new(program: &str, args: &[&str]) -> Self {
+        CommandConfig {
+            program: PathBuf::from(program),
+            args: args.iter().map(|arg| String::from(*arg)).collect(),
+            working_dir: None,
+        }
+    }
+}
+
+// Where OpenPLC is installed and how we run it. This is the plc_runtime
+// section of the configuration, for example in Rocket.toml or from
+// environment variables such as ROCKET_PLC_RUNTIME.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct RuntimeConfig {
+    // The directory where OpenPLC is installed.
+    root: PathBuf,
+    // The directory with the OpenPLC scripts (relative to the root).
+    scripts_dir: PathBuf,
+    // The name of the PLC process so that we can find it when it is running.
+    process_name: String,
+    start: CommandConfig,
+    compile: CommandConfig,
+    change_hardware: CommandConfig,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            root: PathBuf::from("."),
+            scripts_dir: PathBuf::from("."),
+            process_name: String::from(plc_process_name()),
+            start: CommandConfig::new(plc_start_command(), &[]),
+            compile: CommandConfig::new(plc_compile_command(), &[FILE_PLACEHOLDER]),
+            change_hardware: CommandConfig::new(
+                plc_change_hardware_command(),
+                &[HARDWARE_PLACEHOLDER],
+            ),
+        }
+    }
+}
+
+impl RuntimeConfig {
+    // Reads the configuration, using the defaults for anything that is
+    // not configured.
+    pub fn from_figment(figment: &Figment) -> Result<Self, String> {
+        if figment.find_value("plc_runtime").is_err() {
+            return Ok(RuntimeConfig::default());
+        }
+        figment
+            .extract_inner("plc_runtime")
+            .map_err(|e| format!("Invalid plc_runtime configuration: {}", e))
+    }
+
+    // Checks that we can find each of the programs and directories so
+    // that we find out about a bad installation now rather than when
+    // someone tries to run the PLC.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.root.is_dir() {
+            return Err(format!(
+                "The OpenPLC root {} is not a directory",
+                self.root.display()
+            ));
+        }
+        for (name, command) in [
+            ("start", &self.start),
+            ("compile", &self.compile),
+            ("change_hardware", &self.change_hardware),
+        ] {
+            self.program(command).ok_or_else(|| {
+                format!(
+                    "Unable to find the {} program {}",
+                    name,
+                    command.program.display()
+                )
+            })?;
+            let dir = self.working_dir(command);
+            if !dir.is_dir() {
+                return Err(format!(
+                    "The working directory {} for the {} program is not a directory",
+                    dir.display(),
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Finds the program that the command runs.
+    fn program(&self, command: &CommandConfig) -> Option<PathBuf> {
+        let program = &command.program;
+        if program.components().count() == 1 && !program.is_absolute() {
+            return find_on_path(program);
+        }
+        let program = self.root.join(&self.scripts_dir).join(program);
+        if program.is_file() {
+            Some(program)
+        } else {
+            None
+        }
+    }
+
+    fn working_dir(&self, command: &CommandConfig) -> PathBuf {
+        match &command.working_dir {
+            Some(dir) => self.root.join(dir),
+            None => self.root.clone(),
+        }
+    }
+
+    // Creates the command to run, replacing the placeholders in the arguments.
+    fn command(&self, command: &CommandConfig, file: &str, hardware: &str) -> Command {
+        let program = self
+            .program(command)
+            .unwrap_or_else(|| command.program.clone());
+        let mut cmd = Command::new(program);
+        cmd.current_dir(self.working_dir(command));
+        for arg in &command.args {
+            cmd.arg(
+                arg.replace(FILE_PLACEHOLDER, file)
+                    .replace(HARDWARE_PLACEHOLDER, hardware),
+            );
+        }
+        cmd
+    }
+}
+
+// Finds the program in one of the directories on the PATH.
+fn find_on_path(program: &Path) -> Option<PathBuf> {
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+// Validates the runtime configuration when we ignite so that we fail to
+// start with a clear error if OpenPLC isn't where we expect it.
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("PLC Runtime Configuration", |rocket| async {
+        match RuntimeConfig::from_figment(rocket.figment()).and_then(|c| c.validate()) {
+            Ok(_) => Ok(rocket),
+            Err(e) => {
+                error!("{}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+// Controls the PLC by running the OpenPLC commands as OS processes.
+pub struct ProcessRuntime {
+    config: RuntimeConfig,
+    // Where we write the output of the PLC.
+    log: LogBuffer,
+    // Compilations that are in progress.
+    compilations: Vec<(Pid, Compilation)>,
+}
+
+impl ProcessRuntime {
+    pub fn new(config: RuntimeConfig, log: LogBuffer) -> Self {
+        ProcessRuntime {
+            config,
+            log,
+            compilations: Vec::new(),
+        }
+    }
+}
+
+impl PlcRuntime for ProcessRuntime {
+    fn discover(&mut self) -> Result<Option<Pid>, String> {
+        // Try to determine if openplc application is running.
+        let s = System::new_all();
+        let pid = s
+            .processes_by_exact_name(&self.config.process_name)
+            .next()
+            .map(|process| process.pid());
+        Ok(pid)
+    }
+
+    fn start(&mut self, file: &str) -> Result<Pid, String> {
+        let cmd = self.config.command(&self.config.start, file, "");
+        let child_pid = run_command(TokioCommand::from(cmd), self.log.clone())?;
+
+        // Although we started a process, we don't know that
+        // what we started was the PLC process directly. So,
+        // we still need to find the process. If we can't find it,
+        // then what we started is the PLC.
+        let deadline = Instant::now() + START_DISCOVERY_TIMEOUT;
+        loop {
+            if let Some(pid) = self.discover()? {
+                return Ok(pid);
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(START_DISCOVERY_INTERVAL);
+        }
+        match self.is_alive(child_pid) {
+            true => Ok(child_pid),
+            false => Err(String::from("no such process")),
+        }
+    }
+
+    fn stop(&mut self, pid: Pid) -> Result<(), String> {
+        signal_process(pid, Signal::Term)
+    }
+
+    fn kill(&mut self, pid: Pid) -> Result<(), String> {
+        signal_process(pid, Signal::Kill)
+    }
+
+    fn kill_tree(&mut self, pid: Pid) -> Result<(), String> {
+        // Kill the parents before the children so that nothing in the
+        // tree can start another process once we have looked.
+        for pid in descendants(pid) {
+            signal_process(pid, Signal::Kill)?;
+        }
+        Ok(())
+    }
+
+    fn compile(&mut self, file: &str, log: Compilation) -> Result<Pid, String> {
+        let cmd = self.config.command(&self.config.compile, file, "");
+        let pid = run_command(TokioCommand::from(cmd), log.clone()).map_err(|e| {
+            log.append(Stream::Stderr, format!("Failed to start {}", e));
+            log.finish(None);
+            e
+        })?;
+
+        self.compilations.push((pid, log));
+        Ok(pid)
+    }
+
+    fn change_hardware(&mut self, name: &str) -> Result<(), String> {
+        let mut cmd = self.config.command(&self.config.change_hardware, "", name);
+        match cmd.status() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!("Changing the hardware failed with {}", status)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn is_alive(&mut self, pid: Pid) -> bool {
+        // Compilations are finished once the compiler has exited and
+        // we have collected all of the output.
+        if let Some(index) = self.compilations.iter().position(|(p, _)| *p == pid) {
+            if !self.compilations[index].1.is_finished() {
+                return true;
+            }
+            self.compilations.remove(index);
+            return false;
+        }
+
+        // Processes that we started are reaped by the task that collects
+        // their output, so we can just look for the process.
+        let mut s = System::new();
+        s.refresh_process(pid)
+    }
+
+    fn start_time(&self, pid: Pid) -> Option<u64> {
+        let mut s = System::new();
+        if !s.refresh_process(pid) {
+            return None;
+        }
+        s.process(pid).map(|process| process.start_time())
+    }
+}
+
+// Sends the signal to the process. A process that is already gone is
+// not an error since that is the outcome we were looking for.
+fn signal_process(pid: Pid, signal: Signal) -> Result<(), String> {
+    let mut s = System::new();
+    if !s.refresh_process(pid) {
+        return Ok(());
+    }
+
+    let process = match s.process(pid) {
+        Some(process) => process,
+        None => return Ok(()),
+    };
+
+    // Not every platform supports every signal. If the signal isn't
+    // supported, then the best we can do is kill the process.
+    let sent = match process.kill_with(signal) {
+        Some(sent) => sent,
+        None => process.kill(),
+    };
+
+    if sent {
+        Ok(())
+    } else {
+        Err(format!("Failed to send {:?} to process {}", signal, pid))
+    }
+}
+
+// Returns the process and all of its descendants, parents first.
+fn descendants(pid: Pid) -> Vec<Pid> {
+    let s = System::new_all();
+    let mut pids = vec![pid];
+    let mut index = 0;
+    while index < pids.len() {
+        let parent = pids[index];
+        pids.extend(
+            s.processes()
+                .values()
+                .filter(|process| process.parent() == Some(parent))
+                .map(|process| process.pid()),
+        );
+        index += 1;
+    }
+    pids
+}
+
+// Runs the command in the background, writing each line of output into
+// the log and recording the exit code once the command exits.
+fn run_command<S: LogSink>(mut cmd: TokioCommand, log: S) -> Result<Pid, String> {
+    // Specify that we want the command's standard output piped back to us.
+    // By default, standard input/output/error will be inherited from the
+    // current process (for example, this means that standard input will
+    // come from the keyboard and standard output/error will go directly to
+    // the terminal if this process is invoked from the command line).
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+
+    let mut child = cmd.spawn().map_err(|e| e.to_string())?;
+    let pid = match child.id() {
+        Some(id) => Pid::from_u32(id),
+        None => return Err(String::from("process exited before it started")),
+    };
+
+    let stdout = child
+        .stdout
+        .take()
+        .map(|stdout| tokio::spawn(append_lines(stdout, Stream::Stdout, log.clone())));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|stderr| tokio::spawn(append_lines(stderr, Stream::Stderr, log.clone())));
+
+    // Ensure the child process is spawned in the runtime so it can
+    // make progress on its own while we collect any output.
+    tokio::spawn(async move {
+        let status = child.wait().await;
+
+        // Make sure we have all of the output before we say that we
+        // are finished.
+        for reader in [stdout, stderr].into_iter().flatten() {
+            let _ = reader.await;
+        }
+
+        match status {
+            Ok(status) => log.finish(status.code()),
+            Err(e) => {
+                log.append(
+                    Stream::Stderr,
+                    format!("child process encountered an error: {}", e),
+                );
+                log.finish(None);
+            }
+        }
+    });
+
+    Ok(pid)
+}
+
+async fn append_lines<R: AsyncRead + Unpin, S: LogSink>(reader: R, stream: Stream, log: S) {
+    let mut reader = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = reader.next_line().await {
+        log.append(stream, line);
+    }
+}
+
+// The in-memory model of the processes for the simulated runtime.
+#[derive(Default)]
+struct Simulation {
+    next_pid: u32,
+    // The process ID of the PLC if it is running.
+    plc: Option<Pid>,
+    // All processes that are currently alive and when they started.
+    alive: HashMap<Pid, u64>,
+    // The last program that was started.
+    program: Option<String>,
+    // The last selected hardware layer.
+    hardware: Option<String>,
+    // If set, the next command fails with this message.
+    failure: Option<String>,
+    // If set, processes ignore stop and only exit when killed.
+    ignore_stop: bool,
+    // The logs for compilations that are in progress.
+    compilations: HashMap<Pid, Compilation>,
+}
+
+impl Simulation {
+    fn terminate(&mut self, pid: Pid) -> Result<(), String> {
+        if let Some(msg) = self.failure.take() {
+            return Err(msg);
+        }
+        self.exit(pid, None);
+        Ok(())
+    }
+
+    fn exit(&mut self, pid: Pid, exit_code: Option<i32>) {
+        self.alive.remove(&pid);
+        if self.plc == Some(pid) {
+            self.plc = None;
+        }
+        if let Some(log) = self.compilations.remove(&pid) {
+            log.finish(exit_code);
+        }
+    }
+
+    fn spawn(&mut self) -> Result<Pid, String> {
+        if let Some(msg) = self.failure.take() {
+            return Err(msg);
+        }
+        self.next_pid += 1;
+        let pid = Pid::from_u32(self.next_pid);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.alive.insert(pid, now);
+        Ok(pid)
+    }
+}
+
+// A runtime that doesn't start any real processes. Processes "run" until
+// they are stopped or until the test says they exited, so tests can
+// drive every transition of the state machine deterministically.
+//
+// The runtime is cheap to clone and all clones share the same simulation,
+// so a test can keep a handle after giving the runtime to the state machine.
+#[derive(Clone, Default)]
+pub struct SimulatedRuntime {
+    sim: Arc<Mutex<Simulation>>,
+}
+
+#[allow(dead_code)]
+impl SimulatedRuntime {
+    pub fn new() -> Self {
+        SimulatedRuntime::default()
+    }
+
+    // Simulate the PLC having been started outside of the web server.
+    pub fn launch_external(&self) -> Pid {
+        let mut sim = self.sim.lock().unwrap();
+        let pid = sim.spawn().unwrap_or_else(|_| Pid::from_u32(0));
+        sim.plc = Some(pid);
+        pid
+    }
+
+    // Simulate the process exiting on its own (for example, a
+    // compile completing or the PLC crashing).
+    pub fn exit(&self, pid: Pid) {
+        self.exit_with_code(pid, 0);
+    }
+
+    pub fn exit_with_code(&self, pid: Pid, exit_code: i32) {
+        self.sim.lock().unwrap().exit(pid, Some(exit_code));
+    }
+
+    pub fn is_running(&self, pid: Pid) -> bool {
+        self.sim.lock().unwrap().alive.contains_key(&pid)
+    }
+
+    // Makes processes ignore requests to stop so that they must be killed.
+    pub fn ignore_stop(&self) {
+        self.sim.lock().unwrap().ignore_stop = true;
+    }
+
+    // Makes the next command given to the runtime fail.
+    pub fn fail_next(&self, msg: &str) {
+        self.sim.lock().unwrap().failure = Some(String::from(msg));
+    }
+
+    pub fn program(&self) -> Option<String> {
+        self.sim.lock().unwrap().program.clone()
+    }
+
+    pub fn hardware(&self) -> Option<String> {
+        self.sim.lock().unwrap().hardware.clone()
+    }
+}
+
+impl PlcRuntime for SimulatedRuntime {
+    fn discover(&mut self) -> Result<Option<Pid>, String> {
+        Ok(self.sim.lock().unwrap().plc)
+    }
+
+    fn start(&mut self, file: &str) -> Result<Pid, String> {
+        let mut sim = self.sim.lock().unwrap();
+        let pid = sim.spawn()?;
+        sim.plc = Some(pid);
+        sim.program = Some(String::from(file));
+        Ok(pid)
+    }
+
+    fn stop(&mut self, pid: Pid) -> Result<(), String> {
+        let mut sim = self.sim.lock().unwrap();
+        if sim.ignore_stop {
+            return Ok(());
+        }
+        sim.terminate(pid)
+    }
+
+    fn kill(&mut self, pid: Pid) -> Result<(), String> {
+        self.sim.lock().unwrap().terminate(pid)
+    }
+
+    fn kill_tree(&mut self, pid: Pid) -> Result<(), String> {
+        // Simulated processes don't start other processes.
+        self.kill(pid)
+    }
+
+    fn compile(&mut self, file: &str, log: Compilation) -> Result<Pid, String> {
+        let mut sim = self.sim.lock().unwrap();
+        let pid = match sim.spawn() {
+            Ok(pid) => pid,
+            Err(msg) => {
+                log.append(Stream::Stderr, msg.clone());
+                log.finish(None);
+                return Err(msg);
+            }
+        };
+        log.append(Stream::Stdout, format!("Compiling {}", file));
+        sim.compilations.insert(pid, log);
+        Ok(pid)
+    }
+
+    fn change_hardware(&mut self, name: &str) -> Result<(), String> {
+        let mut sim = self.sim.lock().unwrap();
+        if let Some(msg) = sim.failure.take() {
+            return Err(msg);
+        }
+        sim.hardware = Some(String::from(name));
+        Ok(())
+    }
+
+    fn is_alive(&mut self, pid: Pid) -> bool {
+        self.sim.lock().unwrap().alive.contains_key(&pid)
+    }
+
+    fn start_time(&self, pid: Pid) -> Option<u64> {
+        self.sim.lock().unwrap().alive.get(&pid).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rocket::figment::providers::{Format, Toml};
+
+    // An OpenPLC installation in a temporary directory. We remove the
+    // directory when the installation is dropped.
+    struct Installation(PathBuf);
+
+    impl std::ops::Deref for Installation {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for Installation {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Creates an installation with an empty script in the scripts
+    // directory.
+    fn installation(name: &str) -> Installation {
+        let root = env::temp_dir().join(format!("openplc-runtime-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("scripts")).unwrap();
+        std::fs::write(root.join("scripts").join("start.sh"), "").unwrap();
+        Installation(root)
+    }
+
+    fn config(root: &Path, toml: &str) -> RuntimeConfig {
+        let toml = format!(
+            "[plc_runtime]\nroot = {:?}\nscripts_dir = \"scripts\"\n{}",
+            root.to_string_lossy(),
+            toml
+        );
+        RuntimeConfig::from_figment(&Figment::from(Toml::string(&toml))).expect("valid config")
+    }
+
+    #[test]
+    fn test_config_defaults_when_not_configured() {
+        let config = RuntimeConfig::from_figment(&Figment::new()).expect("valid config");
+        assert_eq!(config, RuntimeConfig::default());
+        assert_eq!(config.process_name, plc_process_name());
+        assert_eq!(config.compile.args, vec![FILE_PLACEHOLDER]);
+    }
+
+    #[test]
+    fn test_config_invalid_is_error() {
+        let figment = Figment::from(Toml::string("[plc_runtime]\nroot = 3"));
+        let err = RuntimeConfig::from_figment(&figment).unwrap_err();
+        assert!(err.contains("Invalid plc_runtime configuration"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_config_validate_finds_programs() {
+        let root = installation("valid");
+        let config = config(
+            &root,
+            r#"
+            start = { program = "./start.sh" }
+            compile = { program = "sh", args = ["{file}"] }
+            change_hardware = { program = "./start.sh", working_dir = "scripts" }
+            "#,
+        );
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_config_validate_missing_program_is_error() {
+        let root = installation("missing");
+        let config = config(&root, r#"start = { program = "./missing.sh" }"#);
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("Unable to find the start program ./missing.sh"));
+    }
+
+    #[test]
+    fn test_config_validate_missing_root_is_error() {
+        let installation = installation("root");
+        let config = config(&installation.join("missing"), "");
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("is not a directory"));
+    }
+
+    #[test]
+    fn test_config_command_replaces_placeholders() {
+        let root = installation("command");
+        let config = config(
+            &root,
+            r#"compile = { program = "./start.sh", args = ["-f", "{file}", "--hw={hardware}"], working_dir = "scripts" }"#,
+        );
+        let cmd = config.command(&config.compile, "prog.st", "blank");
+        assert_eq!(
+            PathBuf::from(cmd.get_program()),
+            root.join("scripts").join("./start.sh")
+        );
+        let args: Vec<_> = cmd.get_args().collect();
+        assert_eq!(args, vec!["-f", "prog.st", "--hw=blank"]);
+        assert_eq!(cmd.get_current_dir(), Some(root.join("scripts").as_path()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_change_hardware_runs_hardware_command() {
+        let root = installation("hardware");
+        let config = config(
+            &root,
+            r#"change_hardware = { program = "sh", args = ["-c", "test {hardware} = blank"] }"#,
+        );
+        let mut runtime = ProcessRuntime::new(config, LogBuffer::new(10));
+        assert_eq!(runtime.change_hardware("blank"), Ok(()));
+        assert!(runtime.change_hardware("unipi").is_err());
+    }
+
+    #[cfg(unix)]
+    #[rocket::async_test]
+    async fn test_start_finds_plc_started_in_background() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // The start command exits once it has started the PLC, and the
+        // PLC only appears after a moment.
+        let root = installation("background");
+        let name = format!("plc{}", std::process::id());
+        let plc = root.join("scripts").join(&name);
+        std::fs::write(&plc, "#!/bin/sh\nsleep 30\n").unwrap();
+        std::fs::set_permissions(&plc, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut config = config(
+            &root,
+            &format!(
+                r#"start = {{ program = "sh", args = ["-c", "sleep 0.2; ./{} &"], working_dir = "scripts" }}"#,
+                name
+            ),
+        );
+        config.process_name = name.clone();
+        let mut runtime = ProcessRuntime::new(config, LogBuffer::new(10));
+
+        let pid = runtime.start("prog.st").expect("started");
+        let system = System::new_all();
+        let process = system.process(pid).expect("running");
+        assert_eq!(process.name(), name);
+        runtime.kill(pid).expect("killed");
+    }
+
+    #[cfg(unix)]
+    #[rocket::async_test]
+    async fn test_run_command_captures_output_and_exit_code() {
+        let log = Compilation::new(1, 1, String::from("prog.st"), LogBuffer::new(10));
+        let mut cmd = TokioCommand::new("sh");
+        cmd.arg("-c").arg("echo out; echo err >&2; exit 3");
+        run_command(cmd, log.clone()).expect("command started");
+
+        while !log.is_finished() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let compile_log = log.log();
+        assert_eq!(compile_log.exit_code(), Some(3));
+        assert!(compile_log.data().contains("out"));
+        assert!(compile_log.data().contains("err"));
+    }
+
+    #[cfg(unix)]
+    #[rocket::async_test]
+    async fn test_kill_tree_kills_child_processes() {
+        let log = Compilation::new(1, 1, String::from("prog.st"), LogBuffer::new(10));
+        let mut cmd = TokioCommand::new("sh");
+        cmd.arg("-c").arg("sleep 30 & echo started; wait");
+        let pid = run_command(cmd, log.clone()).expect("command started");
+        while !log.log().data().contains("started") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut runtime = ProcessRuntime::new(RuntimeConfig::default(), LogBuffer::new(10));
+        runtime.kill_tree(pid).expect("killed");
+
+        // The sleep holds onto the output, so we only finish once it is
+        // gone too.
+        let exit_code = tokio::time::timeout(Duration::from_secs(5), log.finished())
+            .await
+            .expect("compilation finished");
+        assert_eq!(exit_code, None);
+    }
+}
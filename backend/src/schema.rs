@@ -4,7 +4,18 @@ table! {
         name -> Text,
         description -> Text,
         file -> Text,
-        date_upload -> Integer,
+        date_upload -> BigInt,
+        date_updated -> Nullable<BigInt>,
+        metadata -> Nullable<Text>,
+        outline -> Nullable<Text>,
+        source_name -> Nullable<Text>,
+    }
+}
+
+table! {
+    settings (key) {
+        key -> Text,
+        value -> Text,
     }
 }
 
@@ -44,3 +55,51 @@ table! {
         password -> Text,
     }
 }
+
+table! {
+    state_audit (audit_id) {
+        audit_id -> Nullable<Integer>,
+        date_created -> BigInt,
+        user -> Text,
+        requested_state -> Text,
+        message -> Text,
+        outcome -> Text,
+    }
+}
+
+table! {
+    state_journal (journal_id) {
+        journal_id -> Nullable<Integer>,
+        date_created -> BigInt,
+        user -> Text,
+        event -> Text,
+        prior_state -> Text,
+        resulting_state -> Text,
+        error -> Nullable<Text>,
+    }
+}
+
+table! {
+    program_revisions (revision_id) {
+        revision_id -> Nullable<Integer>,
+        prog_id -> Integer,
+        revision -> Integer,
+        file -> Text,
+        hash -> Text,
+        date_upload -> BigInt,
+    }
+}
+
+table! {
+    compilations (job_id) {
+        job_id -> Integer,
+        prog_id -> Integer,
+        date_created -> BigInt,
+        date_started -> Nullable<BigInt>,
+        date_finished -> Nullable<BigInt>,
+        duration_ms -> Nullable<BigInt>,
+        exit_code -> Nullable<Integer>,
+        status -> Text,
+        log -> Text,
+    }
+}
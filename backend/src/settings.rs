@@ -1,6 +1,35 @@
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::serde::Serialize;
 use rocket::Build;
+use std::collections::HashMap;
+
+use super::plc;
+use super::programs::Program;
+use super::response::*;
+use super::schema::settings;
+use super::sqlite::DbConn;
+
+use self::diesel::prelude::*;
+use rocket_sync_db_pools::diesel;
+
+type QueryResult<T> = std::result::Result<T, diesel::result::Error>;
+
+// The keys in the settings table. These are the same as the keys that
+// the original web server used so that we can read its database.
+const MODBUS_PORT: &str = "Modbus_port";
+const DNP3_PORT: &str = "Dnp3_port";
+const ENIP_PORT: &str = "Enip_port";
+const PSTORAGE_POLLING: &str = "Pstorage_polling";
+const START_RUN_MODE: &str = "Start_run_mode";
+// The ID of the last program that compiled successfully.
+const LAST_COMPILED_PROGRAM: &str = "Last_compiled_program";
+// The file of the program revision that we compiled.
+const LAST_COMPILED_FILE: &str = "Last_compiled_file";
+
+// A value that turns off a setting that is otherwise a number.
+const DISABLED: &str = "disabled";
 
 #[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -21,27 +50,369 @@ pub struct Settings {
     //timeout: Duration,
 }
 
+// The last program that compiled successfully and the file of the
+// revision that we compiled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastCompiled {
+    pub program_id: i32,
+    // We didn't record the file before we had revisions.
+    pub file: Option<String>,
+}
+
+// A row in the settings table.
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "settings"]
+struct Setting {
+    key: String,
+    value: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            modbus_enabled: true,
+            modbus_port: 502,
+
+            dnp3_enabled: true,
+            dnp3_port: 20000,
+
+            enip_enabled: true,
+            enip_port: 44818,
+
+            persistent_storage_enabled: false,
+            //persistent_storage_period: Duration::seconds(10),
+            start_in_run: false,
+            //slave_poll_period: Duration::seconds(10),
+
+            //timeout: Duration::milliseconds(1000)
+        }
+    }
+}
+
+// Reads a port setting. The port is either a number or disabled.
+fn port(values: &HashMap<String, String>, key: &str, enabled: &mut bool, port: &mut u32) {
+    match values.get(key).map(|v| v.as_str()) {
+        Some(DISABLED) => *enabled = false,
+        Some(value) => {
+            if let Ok(value) = value.parse() {
+                *enabled = true;
+                *port = value;
+            }
+        }
+        None => {}
+    }
+}
+
+impl Settings {
+    // Loads the settings, using the default for any that are not stored.
+    pub async fn load(db: &DbConn) -> Result<Settings, diesel::result::Error> {
+        let values: HashMap<String, String> = db
+            .run(move |conn| settings::table.load::<Setting>(conn))
+            .await?
+            .into_iter()
+            .map(|s| (s.key, s.value))
+            .collect();
+
+        let mut settings = Settings::default();
+        port(
+            &values,
+            MODBUS_PORT,
+            &mut settings.modbus_enabled,
+            &mut settings.modbus_port,
+        );
+        port(
+            &values,
+            DNP3_PORT,
+            &mut settings.dnp3_enabled,
+            &mut settings.dnp3_port,
+        );
+        port(
+            &values,
+            ENIP_PORT,
+            &mut settings.enip_enabled,
+            &mut settings.enip_port,
+        );
+        if let Some(value) = values.get(PSTORAGE_POLLING) {
+            settings.persistent_storage_enabled = value != DISABLED;
+        }
+        if let Some(value) = values.get(START_RUN_MODE) {
+            settings.start_in_run = value == "true";
+        }
+        Ok(settings)
+    }
+
+    fn store(conn: &SqliteConnection, key: &str, value: String) -> QueryResult<usize> {
+        let setting = Setting {
+            key: String::from(key),
+            value,
+        };
+        diesel::replace_into(settings::table)
+            .values(&setting)
+            .execute(conn)
+    }
+
+    #[cfg(test)]
+    async fn set(db: &DbConn, key: &str, value: String) -> Result<usize, diesel::result::Error> {
+        let key = String::from(key);
+        db.run(move |conn| Settings::store(conn, &key, value)).await
+    }
+
+    fn value(conn: &SqliteConnection, key: &str) -> QueryResult<Option<String>> {
+        settings::table
+            .find(key)
+            .first::<Setting>(conn)
+            .optional()
+            .map(|setting| setting.map(|s| s.value))
+    }
+
+    // Gets the last program that compiled successfully.
+    pub async fn last_compiled(db: &DbConn) -> Result<Option<LastCompiled>, diesel::result::Error> {
+        db.run(move |conn| {
+            let program_id =
+                Settings::value(conn, LAST_COMPILED_PROGRAM)?.and_then(|value| value.parse().ok());
+            let file = Settings::value(conn, LAST_COMPILED_FILE)?;
+            Ok(program_id.map(|program_id| LastCompiled { program_id, file }))
+        })
+        .await
+    }
+
+    pub fn set_last_compiled(
+        conn: &SqliteConnection,
+        program_id: i32,
+        file: String,
+    ) -> QueryResult<()> {
+        conn.transaction(|| {
+            Settings::store(conn, LAST_COMPILED_PROGRAM, program_id.to_string())?;
+            Settings::store(conn, LAST_COMPILED_FILE, file)?;
+            Ok(())
+        })
+    }
+}
+
 #[get("/settings")]
-fn settings() -> Json<Settings> {
-    Json(Settings {
-        modbus_enabled: true,
-        modbus_port: 502,
+async fn get_settings(db: DbConn) -> OkResponse<Settings> {
+    Settings::load(&db)
+        .await
+        .map(|settings| Ok(Json(settings)))
+        .map_err(|e| Error::from(e).to_response(Status::InternalServerError))?
+}
 
-        dnp3_enabled: true,
-        dnp3_port: 20000,
+// Starts the revision of the program that last compiled successfully if
+// the settings say that we should start in run mode and the PLC isn't
+// already running.
+async fn start_in_run(plc: &plc::SharedPlcStateMachine, db: &DbConn) -> Result<(), String> {
+    let settings = Settings::load(db).await.map_err(|e| e.to_string())?;
+    if !settings.start_in_run {
+        return Ok(());
+    }
 
-        enip_enabled: true,
-        enip_port: 44818,
+    // Discover whether the runtime is already running.
+    let state = plc
+        .run(plc::PlcEvent::NoOp)
+        .await
+        .map_err(|e| e.to_string())?;
+    if state != plc::PlcState::Stopped {
+        return Ok(());
+    }
 
-        persistent_storage_enabled: false,
-        //persistent_storage_period: Duration::seconds(10),
-        start_in_run: false,
-        //slave_poll_period: Duration::seconds(10),
+    let last = Settings::last_compiled(db)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| String::from("No program has compiled successfully"))?;
+    let program = Program::get(db, last.program_id)
+        .await
+        .map_err(|e| format!("Unable to find program {}: {}", last.program_id, e))?;
+    let file = last.file.unwrap_or_else(|| String::from(program.file()));
 
-        //timeout: Duration::milliseconds(1000)
+    plc.run(plc::PlcEvent::Run(file))
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Honors the start in run setting once we have launched (and so have
+// a database).
+pub fn stage() -> AdHoc {
+    AdHoc::on_liftoff("Start in Run", |rocket| {
+        Box::pin(async move {
+            let plc = match rocket.state::<plc::SharedPlcStateMachine>() {
+                Some(plc) => plc,
+                None => return,
+            };
+            let db = match DbConn::get_one(rocket).await {
+                Some(db) => db,
+                None => {
+                    error!("Unable to start in run mode: no database connection");
+                    return;
+                }
+            };
+            if let Err(e) = start_in_run(plc, &db).await {
+                error!("Unable to start in run mode: {}", e);
+            }
+        })
     })
 }
 
 pub fn mount(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
-    rocket.mount("/", routes![settings])
+    rocket.mount("/", routes![get_settings])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::runtime::SimulatedRuntime;
+    use super::super::schema::programs;
+    use super::super::test_async_client;
+    use super::*;
+
+    async fn add_program(db: &DbConn) -> i32 {
+        db.run(|conn| {
+            diesel::insert_into(programs::table)
+                .values((
+                    programs::name.eq("Blink"),
+                    programs::description.eq("Blinks a light"),
+                    programs::file.eq("blink.st"),
+                    programs::date_upload.eq(0),
+                ))
+                .execute(conn)?;
+            programs::table
+                .select(programs::prog_id)
+                .first::<Option<i32>>(conn)
+        })
+        .await
+        .expect("program")
+        .expect("program ID")
+    }
+
+    #[rocket::async_test]
+    async fn test_load_defaults_when_not_stored() {
+        let (client, _plc) = test_async_client(&SimulatedRuntime::new()).await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let settings = Settings::load(&db).await.unwrap();
+        assert!(settings.modbus_enabled);
+        assert_eq!(settings.modbus_port, 502);
+        assert!(!settings.start_in_run);
+    }
+
+    #[rocket::async_test]
+    async fn test_load_stored_values() {
+        let (client, _plc) = test_async_client(&SimulatedRuntime::new()).await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        Settings::set(&db, MODBUS_PORT, String::from("disabled"))
+            .await
+            .unwrap();
+        Settings::set(&db, DNP3_PORT, String::from("20001"))
+            .await
+            .unwrap();
+        Settings::set(&db, PSTORAGE_POLLING, String::from("10"))
+            .await
+            .unwrap();
+        Settings::set(&db, START_RUN_MODE, String::from("true"))
+            .await
+            .unwrap();
+
+        let settings = Settings::load(&db).await.unwrap();
+        assert!(!settings.modbus_enabled);
+        assert!(settings.dnp3_enabled);
+        assert_eq!(settings.dnp3_port, 20001);
+        assert!(settings.persistent_storage_enabled);
+        assert!(settings.start_in_run);
+    }
+
+    #[rocket::async_test]
+    async fn test_start_in_run_starts_last_compiled_program() {
+        let runtime = SimulatedRuntime::new();
+        let (client, plc) = test_async_client(&runtime).await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let id = add_program(&db).await;
+        db.run(move |conn| Settings::set_last_compiled(conn, id, String::from("blink.st")))
+            .await
+            .unwrap();
+        Settings::set(&db, START_RUN_MODE, String::from("true"))
+            .await
+            .unwrap();
+
+        start_in_run(&plc, &db).await.expect("started");
+        assert_matches!(plc.snapshot().state, plc::PlcState::Running(_));
+        assert_eq!(runtime.program(), Some(String::from("blink.st")));
+    }
+
+    #[rocket::async_test]
+    async fn test_start_in_run_starts_compiled_revision() {
+        let runtime = SimulatedRuntime::new();
+        let (client, plc) = test_async_client(&runtime).await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let id = add_program(&db).await;
+        // We compiled an older revision than the program's newest.
+        db.run(move |conn| Settings::set_last_compiled(conn, id, String::from("blink-old.st")))
+            .await
+            .unwrap();
+        Settings::set(&db, START_RUN_MODE, String::from("true"))
+            .await
+            .unwrap();
+
+        start_in_run(&plc, &db).await.expect("started");
+        assert_eq!(runtime.program(), Some(String::from("blink-old.st")));
+    }
+
+    #[rocket::async_test]
+    async fn test_start_in_run_without_compiled_file_starts_program() {
+        let runtime = SimulatedRuntime::new();
+        let (client, plc) = test_async_client(&runtime).await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let id = add_program(&db).await;
+        Settings::set(&db, LAST_COMPILED_PROGRAM, id.to_string())
+            .await
+            .unwrap();
+        Settings::set(&db, START_RUN_MODE, String::from("true"))
+            .await
+            .unwrap();
+
+        start_in_run(&plc, &db).await.expect("started");
+        assert_eq!(runtime.program(), Some(String::from("blink.st")));
+    }
+
+    #[rocket::async_test]
+    async fn test_start_in_run_disabled_does_nothing() {
+        let runtime = SimulatedRuntime::new();
+        let (client, plc) = test_async_client(&runtime).await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let id = add_program(&db).await;
+        db.run(move |conn| Settings::set_last_compiled(conn, id, String::from("blink.st")))
+            .await
+            .unwrap();
+
+        start_in_run(&plc, &db).await.expect("nothing to do");
+        assert_eq!(runtime.program(), None);
+    }
+
+    #[rocket::async_test]
+    async fn test_start_in_run_already_running_does_nothing() {
+        let runtime = SimulatedRuntime::new();
+        let pid = runtime.launch_external();
+        let (client, plc) = test_async_client(&runtime).await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let id = add_program(&db).await;
+        db.run(move |conn| Settings::set_last_compiled(conn, id, String::from("blink.st")))
+            .await
+            .unwrap();
+        Settings::set(&db, START_RUN_MODE, String::from("true"))
+            .await
+            .unwrap();
+
+        start_in_run(&plc, &db).await.expect("already running");
+        assert_eq!(plc.snapshot().state, plc::PlcState::Running(pid));
+        assert_eq!(runtime.program(), None);
+    }
+
+    #[rocket::async_test]
+    async fn test_start_in_run_without_compiled_program_fails() {
+        let (client, plc) = test_async_client(&SimulatedRuntime::new()).await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        Settings::set(&db, START_RUN_MODE, String::from("true"))
+            .await
+            .unwrap();
+
+        assert!(start_in_run(&plc, &db).await.is_err());
+        assert_matches!(plc.snapshot().state, plc::PlcState::Stopped);
+    }
 }
@@ -1,10 +1,69 @@
 use rocket::fairing::AdHoc;
-use rocket::{Build, Rocket};
+use rocket::{Build, Phase, Rocket};
 use rocket_sync_db_pools::{database, diesel};
 
+use self::diesel::connection::SimpleConnection;
+use self::diesel::prelude::*;
+use diesel_migrations::MigrationConnection;
+
 #[database("sqlite_logs")]
 pub struct DbConn(diesel::SqliteConnection);
 
+// The database for tasks that run in the background for the life of the
+// server. Holding a connection from the pool would take it from the
+// requests, so these open a connection each time they need one.
+#[derive(Clone)]
+pub struct BackgroundDb {
+    url: String,
+}
+
+impl BackgroundDb {
+    pub fn new<P: Phase>(rocket: &Rocket<P>) -> Result<BackgroundDb, String> {
+        rocket
+            .figment()
+            .extract_inner::<String>("databases.sqlite_logs.url")
+            .map(|url| BackgroundDb { url })
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn run<F, R>(&self, f: F) -> Result<R, String>
+    where
+        F: FnOnce(&SqliteConnection) -> QueryResult<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let url = self.url.clone();
+        rocket::tokio::task::spawn_blocking(move || {
+            let conn = SqliteConnection::establish(&url).map_err(|e| e.to_string())?;
+            // The same settings as the connections in the pool.
+            conn.batch_execute("PRAGMA busy_timeout = 1000; PRAGMA foreign_keys = ON;")
+                .map_err(|e| e.to_string())?;
+            f(&conn).map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+}
+
+// SQLite can't run 20220101000000_create_v3_tables (the tables of the
+// OpenPLC v3 database) because its statements are not separated by
+// semicolons, so no database can have run it. Diesel knows a migration only
+// by its version, so rather than change a migration that has been released,
+// we record it as run and 20220102000000_create_v3_tables creates the tables
+// instead. That migration only creates tables that don't exist so that it
+// also works on a database copied from OpenPLC v3.
+const V3_TABLES_MIGRATION: &str = "20220101000000";
+
+fn skip_v3_tables_migration(conn: &SqliteConnection) -> QueryResult<()> {
+    diesel_migrations::setup_database(conn)?;
+    if !conn
+        .previously_run_migration_versions()?
+        .contains(V3_TABLES_MIGRATION)
+    {
+        conn.insert_new_migration(V3_TABLES_MIGRATION)?;
+    }
+    Ok(())
+}
+
 async fn run_migrations(rocket: Rocket<Build>) -> Rocket<Build> {
     // This macro from `diesel_migrations` defines an `embedded_migrations`
     // module containing a function named `run` that runs the migrations in the
@@ -12,6 +71,9 @@ async fn run_migrations(rocket: Rocket<Build>) -> Rocket<Build> {
     embed_migrations!("db/migrations");
 
     let conn = DbConn::get_one(&rocket).await.expect("database connection");
+    conn.run(|c| skip_v3_tables_migration(c))
+        .await
+        .expect("diesel migrations");
     conn.run(|c| embedded_migrations::run(c))
         .await
         .expect("diesel migrations");
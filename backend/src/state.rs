@@ -1,10 +1,21 @@
 use rocket::http::Status;
+use rocket::response::stream::EventStream;
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
-use rocket::{Build, State};
+use rocket::{Build, Shutdown, State};
 
+use std::time::Duration;
+use sysinfo::{System, SystemExt};
+
+use super::audit::AuditEntry;
+use super::compilations::{Compilations, CompileLog};
+use super::deployments::{deploying, Deployment, Deployments};
+use super::logs::{event_stream, LogStreams};
 use super::plc;
+use super::programs::Program;
 use super::response::*;
+use super::sqlite::DbConn;
+use super::users::Operator;
 
 // We control state as a finite state machine.
 // The state functions allow direct control of the
@@ -14,12 +25,30 @@ use super::response::*;
 // server can manipulate the state, it doesn't own
 // the state and needs to query to determine the actual
 // state.
-#[derive(Deserialize, Serialize, Copy, Clone)]
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq)]
 #[serde(crate = "rocket::serde")]
 pub enum AppState {
+    // We have not yet determined whether the PLC is running.
+    INITIALIZING,
     RUNNING,
     COMPILING,
+    STOPPING,
     STOPPED,
+    // The PLC crashed and we stopped trying to restart it.
+    FAULTED,
+}
+
+impl From<plc::PlcState> for AppState {
+    fn from(state: plc::PlcState) -> Self {
+        match state {
+            plc::PlcState::Initialize => AppState::INITIALIZING,
+            plc::PlcState::Stopped => AppState::STOPPED,
+            plc::PlcState::Stopping(_) => AppState::STOPPING,
+            plc::PlcState::Running(_) => AppState::RUNNING,
+            plc::PlcState::Compiling(_) => AppState::COMPILING,
+            plc::PlcState::Faulted => AppState::FAULTED,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -32,6 +61,12 @@ pub struct StateInfo {
     hostname: String,
     uptime: String,
     state: AppState,
+    // Why the PLC is faulted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fault: Option<String>,
+    // The most recent deployment, so that clients can follow it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deployment: Option<Deployment>,
 }
 
 #[derive(Deserialize)]
@@ -47,54 +82,306 @@ pub struct Logs {
     data: String,
 }
 
-#[get("/state")]
-pub fn state(plc: &State<plc::SharedPlcStateMachine>) -> OkResponse<StateInfo> {
-    match plc.sm.read() {
-        Ok(plc) => {
-            // TODO something meaningful here
-            return Ok(Json(StateInfo {
-                name: String::from("world"),
-                description: String::from("hello"),
-                path: String::from("file_name"),
-                hostname: String::from("localhost"),
-                uptime: String::from("f"),
-                state: AppState::STOPPED,
-            }));
-        }
-        Err(e) => return Err(Error::response(Status::ImATeapot, "", "")),
+// Formats the uptime as days, hours, minutes and seconds.
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let days = secs / 86400;
+    let hours = (secs % 86400) / 3600;
+    let minutes = (secs % 3600) / 60;
+    let seconds = secs % 60;
+    if days > 0 {
+        format!("{}d {:02}:{:02}:{:02}", days, hours, minutes, seconds)
+    } else {
+        format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
     }
 }
 
+// Gets the information about the PLC state and the program that is
+// loaded on the PLC.
+async fn state_info(
+    plc: &plc::SharedPlcStateMachine,
+    deployments: &Deployments,
+    db: DbConn,
+) -> OkResponse<StateInfo> {
+    let snapshot = plc.snapshot();
+    let program = match snapshot.program.clone() {
+        Some(file) => Program::find_by_file(db, file).await.ok(),
+        None => None,
+    };
+
+    let hostname = System::new()
+        .host_name()
+        .unwrap_or_else(|| String::from("localhost"));
+
+    Ok(Json(StateInfo {
+        name: program
+            .as_ref()
+            .map(|p| p.name().to_string())
+            .unwrap_or_default(),
+        description: program
+            .as_ref()
+            .map(|p| p.description().to_string())
+            .unwrap_or_default(),
+        path: program
+            .as_ref()
+            .map(|p| p.file().to_string())
+            .unwrap_or_default(),
+        hostname,
+        uptime: snapshot.uptime().map(format_uptime).unwrap_or_default(),
+        state: AppState::from(snapshot.state),
+        fault: snapshot.fault,
+        deployment: deployments.current(),
+    }))
+}
+
+#[get("/state")]
+pub async fn state(
+    plc: &State<plc::SharedPlcStateMachine>,
+    deployments: &State<Deployments>,
+    db: DbConn,
+) -> OkResponse<StateInfo> {
+    state_info(plc, deployments, db).await
+}
+
 #[put("/state", format = "json", data = "<message>")]
-pub fn set_state(
+pub async fn set_state(
     plc: &State<plc::SharedPlcStateMachine>,
+    deployments: &State<Deployments>,
+    operator: Operator,
+    db: DbConn,
     message: Json<StateRequest>,
 ) -> OkResponse<StateInfo> {
-    // TODO this needs stuff
-    Ok(Json(StateInfo {
-        name: String::from("world"),
-        description: String::from("hello"),
-        path: String::from("file_name"),
-        hostname: String::from("localhost"),
-        uptime: String::from("f"),
-        state: message.state,
-    }))
+    if deployments.in_progress() {
+        return Err(deploying());
+    }
+    let request = message.into_inner();
+    let result = match request.state {
+        AppState::RUNNING => run_selected_program(plc, &operator.0).await,
+        // Transitioning with no event stops the PLC.
+        AppState::STOPPED => {
+            plc.transition_as(plc::PlcEvent::NoOp, Duration::from_secs(2), &operator.0)
+                .await
+        }
+        state => Err(plc::PlcError::InvalidTransition(format!(
+            "Cannot request state {:?}",
+            state
+        ))),
+    };
+
+    // Record who asked and why, whether or not it worked.
+    let outcome = match &result {
+        Ok(state) => format!("{:?}", AppState::from(*state)),
+        Err(e) => e.to_string(),
+    };
+    let entry = AuditEntry::new(
+        operator.0,
+        format!("{:?}", request.state),
+        request.message,
+        outcome,
+    );
+    // The PLC has already changed, so we still tell the caller about it.
+    if let Err(e) = AuditEntry::create(&db, entry).await {
+        error!("Unable to record the state change: {}", e);
+    }
+
+    result.map_err(Error::from_plc)?;
+    state_info(plc, deployments, db).await
+}
+
+// Runs the program that is currently selected (the program that was
+// last compiled or run).
+async fn run_selected_program(plc: &plc::SharedPlcStateMachine, user: &str) -> plc::StateResult {
+    let file = plc
+        .snapshot()
+        .program
+        .ok_or_else(|| plc::PlcError::InvalidTransition(String::from("No program is selected")))?;
+    plc.run_as(plc::PlcEvent::Run(file), user).await
 }
 
 #[get("/logs")]
-pub fn logs() -> Json<Logs> {
+pub fn logs(logs: &State<LogStreams>) -> Json<Logs> {
     Json(Logs {
-        data: String::from("logs content"),
+        data: logs.runtime.text(),
     })
 }
 
+// Streams the output of the PLC runtime as server-sent events.
+#[get("/logs/stream")]
+pub fn stream_logs(logs: &State<LogStreams>, shutdown: Shutdown) -> EventStream![] {
+    event_stream(&logs.runtime, shutdown)
+}
+
 #[get("/compileLogs")]
-pub fn compile_logs() -> Json<Logs> {
-    Json(Logs {
-        data: String::from("logs content"),
-    })
+pub async fn compile_logs(
+    db: DbConn,
+    compilations: &State<Compilations>,
+) -> OkResponse<CompileLog> {
+    compilations
+        .find_latest(&db)
+        .await
+        .map_err(|e| Error::from(e).to_response(Status::InternalServerError))?
+        .map(Json)
+        .ok_or_else(|| {
+            Error::response(
+                Status::NotFound,
+                "not_found",
+                "No program has been compiled",
+            )
+        })
+}
+
+// Streams the output of the compiler as server-sent events.
+#[get("/compileLogs/stream")]
+pub fn stream_compile_logs(logs: &State<LogStreams>, shutdown: Shutdown) -> EventStream![] {
+    event_stream(&logs.compiler, shutdown)
 }
 
 pub fn mount(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
-    rocket.mount("/", routes![state, set_state, logs, compile_logs,])
+    rocket.mount(
+        "/",
+        routes![
+            state,
+            set_state,
+            logs,
+            stream_logs,
+            compile_logs,
+            stream_compile_logs,
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::compilations::Compilation;
+    use super::super::logs::LogBuffer;
+    use super::super::runtime::SimulatedRuntime;
+    use super::super::test_client;
+    use super::*;
+    use rocket::http::{ContentType, Header};
+    use rocket::local::blocking::{Client, LocalResponse};
+    use rocket_sync_db_pools::diesel::{self, RunQueryDsl};
+
+    fn put_state<'c>(client: &'c Client, body: &str) -> LocalResponse<'c> {
+        client
+            .put("/state")
+            .header(ContentType::JSON)
+            .header(Header::new("X-User", "operator1"))
+            .body(body)
+            .dispatch()
+    }
+
+    #[test]
+    fn test_set_state_stopped_stops_plc() {
+        let runtime = SimulatedRuntime::new();
+        let pid = runtime.launch_external();
+        let (client, plc) = test_client(&runtime);
+
+        let response = put_state(&client, r#"{"state": "STOPPED", "message": "maintenance"}"#);
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains(r#""state":"STOPPED""#));
+        assert!(!runtime.is_running(pid));
+        assert_matches!(plc.snapshot().state, plc::PlcState::Stopped);
+
+        let audit = client.get("/state/audit").dispatch().into_string().unwrap();
+        assert!(audit.contains(r#""user":"operator1""#));
+        assert!(audit.contains(r#""message":"maintenance""#));
+        assert!(audit.contains(r#""outcome":"STOPPED""#));
+    }
+
+    #[test]
+    fn test_set_state_without_audit_still_stops_plc() {
+        let runtime = SimulatedRuntime::new();
+        let pid = runtime.launch_external();
+        let (client, plc) = test_client(&runtime);
+        rocket::async_test(async {
+            let db = DbConn::get_one(client.rocket()).await.unwrap();
+            db.run(|conn| diesel::sql_query("DROP TABLE state_audit").execute(conn))
+                .await
+                .unwrap();
+        });
+
+        let response = put_state(&client, r#"{"state": "STOPPED", "message": "maintenance"}"#);
+        assert_eq!(response.status(), Status::Ok);
+        assert!(!runtime.is_running(pid));
+        assert_matches!(plc.snapshot().state, plc::PlcState::Stopped);
+    }
+
+    #[test]
+    fn test_set_state_running_without_program_conflicts() {
+        let (client, plc) = test_client(&SimulatedRuntime::new());
+        rocket::async_test(plc.run(plc::PlcEvent::NoOp)).unwrap();
+
+        let response = put_state(&client, r#"{"state": "RUNNING", "message": "go"}"#);
+        assert_eq!(response.status(), Status::Conflict);
+
+        let audit = client.get("/state/audit").dispatch().into_string().unwrap();
+        assert!(audit.contains("No program is selected"));
+    }
+
+    #[test]
+    fn test_set_state_running_runs_selected_program() {
+        let runtime = SimulatedRuntime::new();
+        let (client, plc) = test_client(&runtime);
+        rocket::async_test(async {
+            plc.run(plc::PlcEvent::NoOp).await.unwrap();
+            let log = Compilation::new(1, 1, String::from("prog.st"), LogBuffer::new(10));
+            if let Ok(plc::PlcState::Compiling(pid)) = plc
+                .run(plc::PlcEvent::Compile(String::from("prog.st"), log))
+                .await
+            {
+                runtime.exit(pid);
+            }
+            plc.run(plc::PlcEvent::NoOp).await.unwrap();
+        });
+
+        let response = put_state(&client, r#"{"state": "RUNNING", "message": "start shift"}"#);
+        assert_eq!(response.status(), Status::Ok);
+        assert_matches!(plc.snapshot().state, plc::PlcState::Running(_));
+        assert_eq!(runtime.program(), Some(String::from("prog.st")));
+
+        // Running again isn't a valid transition.
+        let response = put_state(&client, r#"{"state": "RUNNING", "message": "again"}"#);
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    #[test]
+    fn test_set_state_compiling_conflicts() {
+        let (client, _plc) = test_client(&SimulatedRuntime::new());
+        let response = put_state(&client, r#"{"state": "COMPILING", "message": "no"}"#);
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(59)), "00:00:59");
+        assert_eq!(format_uptime(Duration::from_secs(3661)), "01:01:01");
+        assert_eq!(format_uptime(Duration::from_secs(90061)), "1d 01:01:01");
+    }
+
+    #[test]
+    fn test_app_state_from_plc_state() {
+        use sysinfo::{Pid, PidExt};
+        let pid = Pid::from_u32(1);
+        assert_eq!(
+            AppState::from(plc::PlcState::Initialize),
+            AppState::INITIALIZING
+        );
+        assert_eq!(AppState::from(plc::PlcState::Stopped), AppState::STOPPED);
+        assert_eq!(
+            AppState::from(plc::PlcState::Stopping(pid)),
+            AppState::STOPPING
+        );
+        assert_eq!(
+            AppState::from(plc::PlcState::Running(pid)),
+            AppState::RUNNING
+        );
+        assert_eq!(
+            AppState::from(plc::PlcState::Compiling(pid)),
+            AppState::COMPILING
+        );
+        assert_eq!(AppState::from(plc::PlcState::Faulted), AppState::FAULTED);
+    }
 }
@@ -0,0 +1,1097 @@
+use rocket::serde::{Deserialize, Serialize};
+
+// Checks the syntax of IEC 61131-3 Structured Text before we give it to
+// the compiler. We check the tokens and that the blocks (PROGRAM, IF, VAR
+// and so on) are complete and correctly nested. The compiler still checks
+// the types and the details of the expressions.
+
+// A problem with the syntax. Lines and columns start at 1.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Diagnostic {
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+impl Diagnostic {
+    pub fn new(line: usize, column: usize, message: String) -> Self {
+        Diagnostic {
+            line,
+            column,
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    // An identifier or keyword in upper case (the language is not case
+    // sensitive).
+    Word(String),
+    // A number, string, time, typed or direct variable literal.
+    Literal,
+    Symbol(&'static str),
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: Kind,
+    line: usize,
+    column: usize,
+    // Where the token is in the source, in characters.
+    start: usize,
+    end: usize,
+}
+
+// The longest symbols are first so that we match them first.
+const SYMBOLS: [&str; 25] = [
+    ":=", "=>", "<=", ">=", "<>", "**", "..", ";", ":", ",", "(", ")", "[", "]", "+", "-", "*",
+    "/", "=", "<", ">", ".", "^", "&", "#",
+];
+
+// The blocks that must be closed and the keyword that closes each one.
+const BLOCKS: [(&str, &str); 26] = [
+    ("PROGRAM", "END_PROGRAM"),
+    ("FUNCTION", "END_FUNCTION"),
+    ("FUNCTION_BLOCK", "END_FUNCTION_BLOCK"),
+    ("CONFIGURATION", "END_CONFIGURATION"),
+    ("RESOURCE", "END_RESOURCE"),
+    ("TYPE", "END_TYPE"),
+    ("STRUCT", "END_STRUCT"),
+    ("VAR", "END_VAR"),
+    ("VAR_INPUT", "END_VAR"),
+    ("VAR_OUTPUT", "END_VAR"),
+    ("VAR_IN_OUT", "END_VAR"),
+    ("VAR_GLOBAL", "END_VAR"),
+    ("VAR_EXTERNAL", "END_VAR"),
+    ("VAR_TEMP", "END_VAR"),
+    ("VAR_ACCESS", "END_VAR"),
+    ("VAR_CONFIG", "END_VAR"),
+    ("IF", "END_IF"),
+    ("CASE", "END_CASE"),
+    ("FOR", "END_FOR"),
+    ("WHILE", "END_WHILE"),
+    ("REPEAT", "END_REPEAT"),
+    ("ACTION", "END_ACTION"),
+    ("STEP", "END_STEP"),
+    ("INITIAL_STEP", "END_STEP"),
+    ("TRANSITION", "END_TRANSITION"),
+    ("METHOD", "END_METHOD"),
+];
+
+// The blocks that can be at the top of the file.
+const TOP_LEVEL: [&str; 5] = [
+    "PROGRAM",
+    "FUNCTION",
+    "FUNCTION_BLOCK",
+    "CONFIGURATION",
+    "TYPE",
+];
+
+// The keywords that are not blocks. Two names or values next to each
+// other are a missing semicolon, unless one of them is a keyword.
+const KEYWORDS: [&str; 33] = [
+    "THEN",
+    "ELSIF",
+    "ELSE",
+    "DO",
+    "TO",
+    "BY",
+    "OF",
+    "UNTIL",
+    "NOT",
+    "AND",
+    "OR",
+    "XOR",
+    "MOD",
+    "AT",
+    "ON",
+    "WITH",
+    "TASK",
+    "FROM",
+    "RETURN",
+    "EXIT",
+    "CONTINUE",
+    "CONSTANT",
+    "RETAIN",
+    "NON_RETAIN",
+    "ARRAY",
+    "REF_TO",
+    "POINTER",
+    "EXTENDS",
+    "IMPLEMENTS",
+    "READ_ONLY",
+    "READ_WRITE",
+    "R_EDGE",
+    "F_EDGE",
+];
+
+fn closer(word: &str) -> Option<&'static str> {
+    BLOCKS
+        .iter()
+        .find(|(opener, _)| *opener == word)
+        .map(|(_, closer)| *closer)
+}
+
+fn is_keyword(word: &str) -> bool {
+    closer(word).is_some() || word.starts_with("END_") || KEYWORDS.contains(&word)
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer {
+    fn new(source: &str) -> Self {
+        Lexer {
+            chars: source.chars().collect(),
+            pos: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars()
+            .enumerate()
+            .all(|(i, c)| self.peek(i) == Some(c))
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek(0)?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn bump_while(&mut self, f: impl Fn(char) -> bool) {
+        while self.peek(0).is_some_and(&f) {
+            self.bump();
+        }
+    }
+
+    // Skips up to and including the end, returning false if we got to the
+    // end of the file first.
+    fn skip_past(&mut self, end: &str) -> bool {
+        while self.peek(0).is_some() {
+            if self.starts_with(end) {
+                end.chars().for_each(|_| {
+                    self.bump();
+                });
+                return true;
+            }
+            self.bump();
+        }
+        false
+    }
+
+    // Reads a string literal, where $ escapes the next character.
+    fn string(&mut self, quote: char) -> bool {
+        self.bump();
+        while let Some(c) = self.bump() {
+            match c {
+                '$' => {
+                    self.bump();
+                }
+                c if c == quote => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    // Reads the value of a typed literal after the #, such as T#20ms,
+    // 16#FF, INT#-5 or DT#2022-01-01-12:00:00.
+    fn typed_value(&mut self) -> bool {
+        self.bump();
+        match self.peek(0) {
+            Some(quote @ ('\'' | '"')) => return self.string(quote),
+            Some('+' | '-') => {
+                self.bump();
+            }
+            _ => {}
+        }
+        let start = self.pos;
+        self.bump_while(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | ':' | '-'));
+        self.pos > start
+    }
+
+    fn number(&mut self) {
+        self.bump_while(|c| c.is_ascii_digit() || c == '_');
+        if self.peek(0) == Some('#') {
+            self.typed_value();
+            return;
+        }
+        // A range such as 1..10 is two numbers.
+        if self.peek(0) == Some('.') && self.peek(1).is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+            self.bump_while(|c| c.is_ascii_digit() || c == '_');
+        }
+        if matches!(self.peek(0), Some('e' | 'E')) {
+            let sign = usize::from(matches!(self.peek(1), Some('+' | '-')));
+            if self.peek(1 + sign).is_some_and(|c| c.is_ascii_digit()) {
+                self.bump();
+                self.bump();
+                self.bump_while(|c| c.is_ascii_digit());
+            }
+        }
+    }
+
+    fn tokens(mut self, diagnostics: &mut Vec<Diagnostic>) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        while let Some(c) = self.peek(0) {
+            let (line, column, start) = (self.line, self.column, self.pos);
+            let mut error = |message: &str| {
+                diagnostics.push(Diagnostic::new(line, column, String::from(message)));
+            };
+            let kind = if c.is_whitespace() {
+                self.bump();
+                continue;
+            } else if self.starts_with("(*") {
+                if !self.skip_past("*)") {
+                    error("The comment is not closed");
+                }
+                continue;
+            } else if self.starts_with("//") {
+                self.bump_while(|c| c != '\n');
+                continue;
+            } else if c == '{' {
+                // Pragmas are for the compiler.
+                if !self.skip_past("}") {
+                    error("The pragma is not closed");
+                }
+                continue;
+            } else if c == '\'' || c == '"' {
+                if !self.string(c) {
+                    error("The string is not closed");
+                }
+                Kind::Literal
+            } else if c.is_ascii_digit() {
+                self.number();
+                Kind::Literal
+            } else if c == '%' {
+                // A directly represented variable such as %IX0.0.
+                self.bump();
+                self.bump_while(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '*'));
+                Kind::Literal
+            } else if c.is_alphabetic() || c == '_' {
+                self.bump_while(|c| c.is_alphanumeric() || c == '_');
+                let word = self.chars[start..self.pos]
+                    .iter()
+                    .collect::<String>()
+                    .to_uppercase();
+                if self.peek(0) == Some('#') {
+                    if !self.typed_value() {
+                        error("The literal has no value");
+                    }
+                    Kind::Literal
+                } else if word == "TRUE" || word == "FALSE" {
+                    Kind::Literal
+                } else {
+                    Kind::Word(word)
+                }
+            } else if let Some(symbol) = SYMBOLS.iter().find(|s| self.starts_with(s)) {
+                symbol.chars().for_each(|_| {
+                    self.bump();
+                });
+                Kind::Symbol(symbol)
+            } else {
+                self.bump();
+                error(&format!("Unexpected character '{}'", c));
+                continue;
+            };
+            tokens.push(Token {
+                kind,
+                line,
+                column,
+                start,
+                end: self.pos,
+            });
+        }
+        tokens
+    }
+}
+
+struct Block {
+    opener: String,
+    closer: &'static str,
+    line: usize,
+    column: usize,
+    // Whether a REPEAT has its UNTIL.
+    until: bool,
+}
+
+struct Parser {
+    blocks: Vec<Block>,
+    // The open brackets.
+    brackets: Vec<Token>,
+    // The keyword that must come next in the statement, such as the THEN
+    // of an IF.
+    expect: Option<(&'static str, String)>,
+    // Whether we are in the header of a block such as PROGRAM blink, where
+    // the statements that follow the name don't need a semicolon first.
+    header: bool,
+    // Whether we already reported what is outside of the blocks.
+    outside: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Parser {
+    fn error(&mut self, token: &Token, message: String) {
+        self.diagnostics
+            .push(Diagnostic::new(token.line, token.column, message));
+    }
+
+    fn innermost(&self) -> Option<&str> {
+        self.blocks.last().map(|block| block.opener.as_str())
+    }
+
+    // A statement or declaration ended, so anything that it left open is
+    // an error.
+    fn end_statement(&mut self, token: &Token) {
+        self.header = false;
+        if let Some((keyword, after)) = self.expect.take() {
+            self.error(token, format!("Expected {} after {}", keyword, after));
+        }
+        for bracket in std::mem::take(&mut self.brackets) {
+            if let Kind::Symbol(symbol) = bracket.kind {
+                self.error(&bracket, format!("'{}' is not closed", symbol));
+            }
+        }
+    }
+
+    fn open(&mut self, token: &Token, word: &str, closer: &'static str) {
+        let expect = match word {
+            "IF" => Some("THEN"),
+            "CASE" => Some("OF"),
+            "FOR" | "WHILE" => Some("DO"),
+            _ => None,
+        };
+        self.expect = expect.map(|keyword| (keyword, String::from(word)));
+        self.header = expect.is_none();
+        self.blocks.push(Block {
+            opener: String::from(word),
+            closer,
+            line: token.line,
+            column: token.column,
+            until: false,
+        });
+    }
+
+    fn close(&mut self, token: &Token, word: &str) {
+        let position = match self.blocks.iter().rposition(|block| block.closer == word) {
+            Some(position) => position,
+            None => return self.error(token, format!("Unexpected {}", word)),
+        };
+        // Close what the block contains, reporting each one.
+        while self.blocks.len() > position + 1 {
+            let block = self.blocks.pop().unwrap();
+            self.error(
+                token,
+                format!(
+                    "Expected {} to close the {} on line {} before {}",
+                    block.closer, block.opener, block.line, word
+                ),
+            );
+        }
+        let block = self.blocks.pop().unwrap();
+        if block.opener == "REPEAT" && !block.until {
+            self.error(token, String::from("Expected UNTIL before END_REPEAT"));
+        }
+    }
+
+    fn word(&mut self, token: &Token, word: &str) {
+        if let Some((keyword, _)) = &self.expect {
+            if *keyword == word {
+                self.expect = None;
+                return;
+            }
+        }
+
+        // A PROGRAM within a configuration is an instance of a program.
+        let instance =
+            word == "PROGRAM" && matches!(self.innermost(), Some("CONFIGURATION" | "RESOURCE"));
+        if self.blocks.is_empty() {
+            if TOP_LEVEL.contains(&word) {
+                self.outside = false;
+            } else if !word.starts_with("END_") {
+                if !self.outside {
+                    self.error(
+                        token,
+                        String::from(
+                            "Expected PROGRAM, FUNCTION, FUNCTION_BLOCK, CONFIGURATION or TYPE",
+                        ),
+                    );
+                }
+                self.outside = true;
+                return;
+            }
+        }
+
+        match (word, closer(word)) {
+            (_, Some(closer)) if !instance => {
+                self.end_statement(token);
+                self.open(token, word, closer);
+            }
+            (word, _) if word.starts_with("END_") => {
+                self.end_statement(token);
+                self.close(token, word);
+            }
+            ("ELSIF", _) => {
+                self.end_statement(token);
+                if self.innermost() != Some("IF") {
+                    self.error(token, String::from("ELSIF without IF"));
+                }
+                self.expect = Some(("THEN", String::from("ELSIF")));
+            }
+            ("ELSE", _) => {
+                self.end_statement(token);
+                if !matches!(self.innermost(), Some("IF" | "CASE")) {
+                    self.error(token, String::from("ELSE without IF or CASE"));
+                }
+            }
+            ("UNTIL", _) => match self.blocks.last_mut() {
+                Some(block) if block.opener == "REPEAT" => block.until = true,
+                _ => self.error(token, String::from("UNTIL without REPEAT")),
+            },
+            ("THEN" | "DO", _) => self.error(token, format!("Unexpected {}", word)),
+            _ => {}
+        }
+    }
+
+    fn symbol(&mut self, token: &Token, symbol: &str) {
+        match symbol {
+            ";" => self.end_statement(token),
+            "(" | "[" => self.brackets.push(token.clone()),
+            ")" | "]" => {
+                let open = if symbol == ")" { "(" } else { "[" };
+                match self.brackets.pop() {
+                    Some(Token {
+                        kind: Kind::Symbol(s),
+                        ..
+                    }) if s == open => {}
+                    _ => self.error(token, format!("Unexpected '{}'", symbol)),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn parse(mut self, tokens: &[Token]) -> Vec<Diagnostic> {
+        let mut previous: Option<&Token> = None;
+        for token in tokens {
+            // Two values in a row means that there is no semicolon between
+            // them, or that a keyword is missing.
+            if !self.blocks.is_empty() && is_value(token) && previous.is_some_and(ends_value) {
+                match self.expect.take() {
+                    Some((keyword, after)) => {
+                        self.error(token, format!("Expected {} after {}", keyword, after))
+                    }
+                    None if self.header => self.header = false,
+                    None => self.error(token, String::from("Expected ';'")),
+                }
+            }
+            match &token.kind {
+                Kind::Word(word) => self.word(token, word),
+                Kind::Symbol(symbol) => self.symbol(token, symbol),
+                Kind::Literal if self.blocks.is_empty() && !self.outside => {
+                    self.error(
+                        token,
+                        String::from(
+                            "Expected PROGRAM, FUNCTION, FUNCTION_BLOCK, CONFIGURATION or TYPE",
+                        ),
+                    );
+                    self.outside = true;
+                }
+                Kind::Literal => {}
+            }
+            previous = Some(token);
+        }
+
+        if let Some(last) = tokens.last() {
+            self.end_statement(last);
+        }
+        for block in std::mem::take(&mut self.blocks) {
+            self.diagnostics.push(Diagnostic::new(
+                block.line,
+                block.column,
+                format!("Expected {} to close the {}", block.closer, block.opener),
+            ));
+        }
+        self.diagnostics
+    }
+}
+
+fn is_value(token: &Token) -> bool {
+    match &token.kind {
+        Kind::Word(word) => !is_keyword(word),
+        Kind::Literal => true,
+        Kind::Symbol(_) => false,
+    }
+}
+
+fn ends_value(token: &Token) -> bool {
+    is_value(token) || matches!(token.kind, Kind::Symbol(")" | "]"))
+}
+
+// Checks the syntax of the program, returning the problems in the order
+// that we found them. An empty list means that the syntax is valid.
+pub fn validate(source: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let tokens = Lexer::new(source).tokens(&mut diagnostics);
+    let parser = Parser {
+        blocks: Vec::new(),
+        brackets: Vec::new(),
+        expect: None,
+        header: false,
+        outside: false,
+        diagnostics,
+    };
+    let mut diagnostics = parser.parse(&tokens);
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    diagnostics
+}
+
+// What a program contains, so that we can review a program without
+// reading all of it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Outline {
+    pous: Vec<PouOutline>,
+    configurations: Vec<ConfigurationOutline>,
+    // The variables at an address, which are the I/O of the program.
+    #[serde(rename = "locatedVariables")]
+    located_variables: Vec<LocatedVariable>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PouOutline {
+    name: String,
+    // PROGRAM, FUNCTION_BLOCK or FUNCTION.
+    #[serde(rename = "pouType")]
+    pou_type: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConfigurationOutline {
+    name: String,
+    resources: Vec<ResourceOutline>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ResourceOutline {
+    name: String,
+    tasks: Vec<TaskOutline>,
+    programs: Vec<ProgramInstance>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TaskOutline {
+    name: String,
+    // The values as they are written, such as T#20ms.
+    interval: Option<String>,
+    priority: Option<String>,
+    // The variable that triggers the task, if it isn't cyclic.
+    single: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ProgramInstance {
+    name: String,
+    task: Option<String>,
+    #[serde(rename = "typeName")]
+    type_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LocatedVariable {
+    name: String,
+    address: String,
+    // Whether the address is an input, output or memory.
+    area: String,
+    #[serde(rename = "type")]
+    data_type: String,
+    // The POU or configuration that declares the variable.
+    scope: Option<String>,
+}
+
+// Reads the parts of the program that we put in the outline.
+struct OutlineReader<'t> {
+    chars: Vec<char>,
+    tokens: &'t [Token],
+    pos: usize,
+}
+
+impl<'t> OutlineReader<'t> {
+    fn word(&self, offset: usize) -> Option<&'t str> {
+        match &self.tokens.get(self.pos + offset)?.kind {
+            Kind::Word(word) => Some(word),
+            _ => None,
+        }
+    }
+
+    // The name as it is written, since words are in upper case.
+    fn name(&self, offset: usize) -> Option<String> {
+        self.word(offset)?;
+        Some(self.text(self.pos + offset, self.pos + offset + 1))
+    }
+
+    fn is_symbol(&self, offset: usize, symbol: &str) -> bool {
+        matches!(
+            self.tokens.get(self.pos + offset),
+            Some(Token { kind: Kind::Symbol(s), .. }) if *s == symbol
+        )
+    }
+
+    // The source of the tokens, as it is written.
+    fn text(&self, from: usize, to: usize) -> String {
+        match (self.tokens.get(from), self.tokens.get(to.saturating_sub(1))) {
+            (Some(first), Some(last)) if from < to => {
+                self.chars[first.start..last.end].iter().collect()
+            }
+            _ => String::new(),
+        }
+    }
+
+    // The source from the token up to (but not including) the first of
+    // the symbols.
+    fn text_until(&mut self, symbols: &[&str]) -> String {
+        let from = self.pos;
+        while self.pos < self.tokens.len() && !symbols.iter().any(|s| self.is_symbol(0, s)) {
+            self.pos += 1;
+        }
+        self.text(from, self.pos)
+    }
+
+    // Reads TASK name(INTERVAL := T#20ms, PRIORITY := 0), starting at
+    // the name.
+    fn task(&mut self) -> Option<TaskOutline> {
+        let mut task = TaskOutline {
+            name: self.name(0)?,
+            ..TaskOutline::default()
+        };
+        self.pos += 1;
+        if !self.is_symbol(0, "(") {
+            return Some(task);
+        }
+        while self.pos < self.tokens.len() && !self.is_symbol(0, ")") {
+            self.pos += 1;
+            let key = self.word(0);
+            if key.is_none() || !self.is_symbol(1, ":=") {
+                continue;
+            }
+            self.pos += 2;
+            let value = Some(self.text_until(&[",", ")", ";"]));
+            match key {
+                Some("INTERVAL") => task.interval = value,
+                Some("PRIORITY") => task.priority = value,
+                Some("SINGLE") => task.single = value,
+                _ => {}
+            }
+        }
+        Some(task)
+    }
+
+    // Reads PROGRAM name WITH task : type, starting at the name.
+    fn instance(&mut self) -> Option<ProgramInstance> {
+        let name = self.name(0)?;
+        let task = match self.word(1) {
+            Some("WITH") => {
+                self.pos += 2;
+                self.name(0)
+            }
+            _ => None,
+        };
+        if !self.is_symbol(1, ":") {
+            return None;
+        }
+        self.pos += 2;
+        Some(ProgramInstance {
+            name,
+            task,
+            type_name: self.name(0)?,
+        })
+    }
+
+    // Reads name, name AT %IX0.0 : BOOL, starting at the AT.
+    fn located(&mut self, scope: &Option<String>) -> Vec<LocatedVariable> {
+        let at = self.pos;
+        let mut from = at;
+        while from > 0 {
+            let previous = &self.tokens[from - 1];
+            match &previous.kind {
+                Kind::Symbol(";") => break,
+                Kind::Word(word) if is_keyword(word) => break,
+                _ => from -= 1,
+            }
+        }
+        let names = self.text(from, at);
+        let address = match self.tokens.get(at + 1) {
+            Some(token) if self.chars.get(token.start) == Some(&'%') => self.text(at + 1, at + 2),
+            _ => return Vec::new(),
+        };
+        let area = match address.chars().nth(1) {
+            Some('I') | Some('i') => "input",
+            Some('Q') | Some('q') => "output",
+            _ => "memory",
+        };
+        self.pos = at + 2;
+        let data_type = match self.is_symbol(0, ":") {
+            true => {
+                self.pos += 1;
+                self.text_until(&[":=", ";"])
+            }
+            false => String::new(),
+        };
+        names
+            .split(',')
+            .map(|name| LocatedVariable {
+                name: String::from(name.trim()),
+                address: address.clone(),
+                area: String::from(area),
+                data_type: data_type.clone(),
+                scope: scope.clone(),
+            })
+            .collect()
+    }
+}
+
+// Finds the POUs, configurations and located variables in the program.
+// The program should be valid, but we outline as much as we can anyway.
+pub fn outline(source: &str) -> Outline {
+    let tokens = Lexer::new(source).tokens(&mut Vec::new());
+    let mut reader = OutlineReader {
+        chars: source.chars().collect(),
+        tokens: &tokens,
+        pos: 0,
+    };
+    let mut outline = Outline::default();
+    // The POU or configuration that we are in.
+    let mut scope: Option<String> = None;
+    let mut configuration = false;
+
+    while reader.pos < tokens.len() {
+        match reader.word(0) {
+            Some("PROGRAM") if configuration => {
+                reader.pos += 1;
+                let instance = reader.instance();
+                let resource = outline
+                    .configurations
+                    .last_mut()
+                    .and_then(|c| c.resources.last_mut());
+                if let (Some(instance), Some(resource)) = (instance, resource) {
+                    resource.programs.push(instance);
+                }
+            }
+            Some(pou_type @ ("PROGRAM" | "FUNCTION" | "FUNCTION_BLOCK")) => {
+                if let Some(name) = reader.name(1) {
+                    outline.pous.push(PouOutline {
+                        name: name.clone(),
+                        pou_type: String::from(pou_type),
+                    });
+                    scope = Some(name);
+                }
+            }
+            Some("CONFIGURATION") => {
+                if let Some(name) = reader.name(1) {
+                    outline.configurations.push(ConfigurationOutline {
+                        name: name.clone(),
+                        resources: Vec::new(),
+                    });
+                    scope = Some(name);
+                    configuration = true;
+                }
+            }
+            Some("RESOURCE") => {
+                let name = reader.name(1);
+                if let (Some(name), Some(configuration)) = (name, outline.configurations.last_mut())
+                {
+                    configuration.resources.push(ResourceOutline {
+                        name,
+                        tasks: Vec::new(),
+                        programs: Vec::new(),
+                    });
+                }
+            }
+            Some("TASK") => {
+                reader.pos += 1;
+                let task = reader.task();
+                let resource = outline
+                    .configurations
+                    .last_mut()
+                    .and_then(|c| c.resources.last_mut());
+                if let (Some(task), Some(resource)) = (task, resource) {
+                    resource.tasks.push(task);
+                }
+                continue;
+            }
+            Some("AT") => {
+                let located = reader.located(&scope);
+                outline.located_variables.extend(located);
+                continue;
+            }
+            Some("END_PROGRAM" | "END_FUNCTION" | "END_FUNCTION_BLOCK") => scope = None,
+            Some("END_CONFIGURATION") => {
+                scope = None;
+                configuration = false;
+            }
+            _ => {}
+        }
+        reader.pos += 1;
+    }
+    outline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The program that the OpenPLC editor creates for a new project.
+    const BLINK: &str = r#"
+PROGRAM blink
+  VAR
+    lamp AT %QX0.0 : BOOL := FALSE;
+    timer : TON;
+    counts : ARRAY [1..10] OF INT;
+  END_VAR
+  (* Toggle the lamp every second *)
+  timer(IN := NOT timer.Q, PT := T#1s);
+  IF timer.Q THEN
+    lamp := NOT lamp;
+  ELSIF lamp AND counts[1] > 16#0F THEN
+    counts[1] := counts[1] + 1;
+  ELSE
+    counts[2] := -1;
+  END_IF;
+  FOR i := 1 TO 10 BY 2 DO
+    counts[i] := 0;
+  END_FOR;
+  CASE counts[1] OF
+    1, 2: lamp := TRUE;
+  ELSE
+    lamp := FALSE;
+  END_CASE;
+  REPEAT
+    counts[3] := counts[3] + 1;
+  UNTIL counts[3] > 5.0E1
+  END_REPEAT;
+  // A message with a 'quote' in a comment
+  message := 'It$'s done';
+END_PROGRAM
+
+CONFIGURATION Config0
+  RESOURCE Res0 ON PLC
+    TASK task0(INTERVAL := T#20ms,PRIORITY := 0);
+    PROGRAM instance0 WITH task0 : blink;
+  END_RESOURCE
+END_CONFIGURATION
+"#;
+
+    fn messages(source: &str) -> Vec<(usize, usize, String)> {
+        validate(source)
+            .into_iter()
+            .map(|d| (d.line, d.column, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_program_has_no_diagnostics() {
+        assert_eq!(messages(BLINK), vec![]);
+        assert_eq!(messages(""), vec![]);
+        assert_eq!(
+            messages("program lower\nvar x : int; end_var\nx := 1;\nend_program"),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_unclosed_block() {
+        assert_eq!(
+            messages("PROGRAM blink\n  IF x THEN\n    y := 1;\nEND_PROGRAM\n"),
+            vec![(
+                4,
+                1,
+                String::from("Expected END_IF to close the IF on line 2 before END_PROGRAM")
+            )]
+        );
+        assert_eq!(
+            messages("PROGRAM blink\n"),
+            vec![(
+                1,
+                1,
+                String::from("Expected END_PROGRAM to close the PROGRAM")
+            )]
+        );
+    }
+
+    #[test]
+    fn test_missing_keyword() {
+        assert_eq!(
+            messages("PROGRAM p\nIF x\n  y := 1;\nEND_IF;\nEND_PROGRAM"),
+            vec![(3, 3, String::from("Expected THEN after IF"))]
+        );
+        assert_eq!(
+            messages("PROGRAM p\nREPEAT\n  y := 1;\nEND_REPEAT;\nEND_PROGRAM"),
+            vec![(4, 1, String::from("Expected UNTIL before END_REPEAT"))]
+        );
+        assert_eq!(
+            messages("PROGRAM p\nx := 1;\nELSE\nEND_PROGRAM"),
+            vec![(3, 1, String::from("ELSE without IF or CASE"))]
+        );
+    }
+
+    #[test]
+    fn test_missing_semicolon() {
+        assert_eq!(
+            messages("PROGRAM p\nx := 1\ny := 2;\nEND_PROGRAM"),
+            vec![(3, 1, String::from("Expected ';'"))]
+        );
+    }
+
+    #[test]
+    fn test_brackets() {
+        assert_eq!(
+            messages("PROGRAM p\nx := (1 + 2;\ny := 3);\nEND_PROGRAM"),
+            vec![
+                (2, 6, String::from("'(' is not closed")),
+                (3, 7, String::from("Unexpected ')'")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexer_errors() {
+        assert_eq!(
+            messages("PROGRAM p\nx := 'abc;\nEND_PROGRAM"),
+            vec![
+                (
+                    1,
+                    1,
+                    String::from("Expected END_PROGRAM to close the PROGRAM")
+                ),
+                (2, 6, String::from("The string is not closed")),
+            ]
+        );
+        assert_eq!(
+            messages("PROGRAM p\nx := 1 ? 2;\n(* open\nEND_PROGRAM"),
+            vec![
+                (
+                    1,
+                    1,
+                    String::from("Expected END_PROGRAM to close the PROGRAM")
+                ),
+                (2, 8, String::from("Unexpected character '?'")),
+                (2, 10, String::from("Expected ';'")),
+                (3, 1, String::from("The comment is not closed")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_outside_of_blocks() {
+        assert_eq!(
+            messages("x := 1;\ny := 2;\nPROGRAM p\nEND_PROGRAM\nEND_IF"),
+            vec![
+                (
+                    1,
+                    1,
+                    String::from(
+                        "Expected PROGRAM, FUNCTION, FUNCTION_BLOCK, CONFIGURATION or TYPE"
+                    )
+                ),
+                (5, 1, String::from("Unexpected END_IF")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_outline() {
+        let outline = outline(BLINK);
+        assert_eq!(
+            outline.pous,
+            vec![PouOutline {
+                name: String::from("blink"),
+                pou_type: String::from("PROGRAM"),
+            }]
+        );
+        let resource = &outline.configurations[0].resources[0];
+        assert_eq!(outline.configurations[0].name, "Config0");
+        assert_eq!(resource.name, "Res0");
+        assert_eq!(
+            resource.tasks,
+            vec![TaskOutline {
+                name: String::from("task0"),
+                interval: Some(String::from("T#20ms")),
+                priority: Some(String::from("0")),
+                single: None,
+            }]
+        );
+        assert_eq!(
+            resource.programs,
+            vec![ProgramInstance {
+                name: String::from("instance0"),
+                task: Some(String::from("task0")),
+                type_name: String::from("blink"),
+            }]
+        );
+        assert_eq!(
+            outline.located_variables,
+            vec![LocatedVariable {
+                name: String::from("lamp"),
+                address: String::from("%QX0.0"),
+                area: String::from("output"),
+                data_type: String::from("BOOL"),
+                scope: Some(String::from("blink")),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_outline_located_variables() {
+        let outline = outline(
+            "FUNCTION_BLOCK io\nVAR_INPUT\n  a, b AT %IX0.0 : BOOL;\nEND_VAR\nEND_FUNCTION_BLOCK\n\
+             CONFIGURATION c\n  VAR_GLOBAL\n    level AT %IW100 : ARRAY [0..1] OF INT := [0, 0];\n  END_VAR\nEND_CONFIGURATION\n",
+        );
+        let located = outline
+            .located_variables
+            .iter()
+            .map(|v| {
+                (
+                    v.name.as_str(),
+                    v.address.as_str(),
+                    v.area.as_str(),
+                    v.data_type.as_str(),
+                    v.scope.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            located,
+            vec![
+                ("a", "%IX0.0", "input", "BOOL", Some("io")),
+                ("b", "%IX0.0", "input", "BOOL", Some("io")),
+                ("level", "%IW100", "input", "ARRAY [0..1] OF INT", Some("c")),
+            ]
+        );
+        assert_eq!(outline.pous[0].pou_type, "FUNCTION_BLOCK");
+    }
+}
@@ -1,9 +1,12 @@
 use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
 use rocket::response::{status::Created, status::NoContent};
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
 use rocket::Build;
+use std::convert::Infallible;
 
+use super::pagination::{ListQuery, Page, PageResponse, Sort};
 use super::response::*;
 use super::schema::users;
 use super::sqlite::DbConn;
@@ -24,6 +27,26 @@ pub struct User {
     password: String,
 }
 
+// The person making a request. We don't have sessions yet, so the client
+// identifies the user with the X-User header. If it doesn't, then we
+// use the address that the request came from.
+pub struct Operator(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Operator {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let name = request
+            .headers()
+            .get_one("X-User")
+            .map(String::from)
+            .or_else(|| request.client_ip().map(|ip| ip.to_string()))
+            .unwrap_or_else(|| String::from("unknown"));
+        Outcome::Success(Operator(name))
+    }
+}
+
 impl User {
     async fn create(db: DbConn, user: User) -> Result<User, diesel::result::Error> {
         db.run(
@@ -37,8 +60,45 @@ impl User {
         .await
     }
 
-    async fn all(db: DbConn) -> Result<Vec<User>, diesel::result::Error> {
-        db.run(move |conn| users::table.load(conn)).await
+    // Gets a page of the users whose name or username match the pattern,
+    // and the number of users that match.
+    async fn list(
+        db: DbConn,
+        sort: Sort,
+        pattern: Option<String>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<User>, i64), diesel::result::Error> {
+        db.run(move |conn| {
+            let matching = || {
+                let mut query = users::table.into_boxed();
+                if let Some(pattern) = &pattern {
+                    query = query.filter(
+                        users::name
+                            .like(pattern.clone())
+                            .escape('\\')
+                            .or(users::username.like(pattern.clone()).escape('\\')),
+                    );
+                }
+                query
+            };
+            let total = matching().count().get_result::<i64>(conn)?;
+            let query = match (sort.field(), sort.descending()) {
+                ("name", false) => matching().order(users::name.asc()),
+                ("name", true) => matching().order(users::name.desc()),
+                ("username", false) => matching().order(users::username.asc()),
+                ("username", true) => matching().order(users::username.desc()),
+                (_, false) => matching().order(users::user_id.asc()),
+                (_, true) => matching().order(users::user_id.desc()),
+            };
+            let users = query
+                .then_order_by(users::user_id.asc())
+                .limit(limit)
+                .offset(offset)
+                .load(conn)?;
+            Ok((users, total))
+        })
+        .await
     }
 
     async fn update(db: DbConn, id: i32, user: User) -> Result<User, diesel::result::Error> {
@@ -76,12 +136,14 @@ impl User {
     }
 }
 
-#[get("/users")]
-async fn get_users(db: DbConn) -> OkResponse<Vec<User>> {
-    User::all(db)
+#[get("/users?<list..>")]
+async fn get_users(db: DbConn, list: ListQuery) -> PageResponse<User> {
+    let sort = list.sort(&["id", "name", "username"], "id")?;
+    let offset = list.offset()?;
+    User::list(db, sort, list.pattern(), list.limit(), offset)
         .await
-        .map(|users| Ok(Json(users)))
-        .map_err(|e| Error::from(e).to_response(Status::ImATeapot))?
+        .map(|(users, total)| Page::new(users, offset, total))
+        .map_err(|e| Error::from(e).to_response(Status::ImATeapot))
 }
 
 #[post("/users", format = "json", data = "<user>")]
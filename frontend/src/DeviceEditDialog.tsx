@@ -55,6 +55,7 @@ interface NumberControlDefinition {
     id: string;
     constValue?: number;
     value?: number;
+    max?: number;
     onChange: (value: number) => void;
 }
 
@@ -65,13 +66,16 @@ function NumberControl(def: NumberControlDefinition) {
     return (
         <FormControl isReadOnly={isConst}>
             <FormLabel htmlFor={def.id}>{def.title + (isConst ? ' (device-defined)' : '')}</FormLabel>
-            <NumberInput max={50} min={0} value={value} onChange={(str, num) => def.onChange(num)} style={style}>
+            <NumberInput max={def.max ?? 50} min={0} value={value} onChange={(str, num) => def.onChange(num)} style={style}>
                 <NumberInputField id={def.id} />
             </NumberInput>
         </FormControl>
     );
 }
 
+// Modbus addresses are 16 bits.
+const MAX_REGISTER = 65535;
+
 interface RegisterDefinitionProps {
     title: string;
     id: string;
@@ -89,6 +93,7 @@ function RegistersControl(props: RegisterDefinitionProps) {
                 <NumberControl
                     title="Start Address"
                     id={props.id + 'start'}
+                    max={MAX_REGISTER}
                     onChange={props.onChange.bind(null, props.id + '.start')}
                 />
             </GridItem>
@@ -96,6 +101,7 @@ function RegistersControl(props: RegisterDefinitionProps) {
                 <NumberControl
                     title="Size"
                     id={props.id + 'size'}
+                    max={MAX_REGISTER}
                     onChange={props.onChange.bind(null, props.id + '.size')}
                 />
             </GridItem>
@@ -125,12 +125,16 @@ const updateState = async (state: StateRequest) => {
 };
 
 const fetchPrograms = async () => {
-    return get<{ id: string; name: string; fileName: string; createdAt: number }[]>('programs').then((data) => {
+    return get<{ id: string; name: string; fileName: string; createdAt: string; updatedAt?: string }[]>(
+        'programs',
+    ).then((data) => {
         return data.map((item) => {
             const createdAt = new Date(item.createdAt);
+            const updatedAt = item.updatedAt ? new Date(item.updatedAt) : undefined;
             return {
                 ...item,
                 createdAt,
+                updatedAt,
             } as Program;
         });
     });
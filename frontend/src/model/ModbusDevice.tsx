@@ -3,6 +3,7 @@ export enum Protocol {
     RTU = 2,
 }
 
+// The start and size are Modbus addresses, from 0 to 65535.
 export interface RegisterDefinition {
     start?: number;
     size?: number;
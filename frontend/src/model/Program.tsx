@@ -4,6 +4,7 @@ export interface Program {
     description: string;
     fileName: string;
     createdAt: Date;
+    updatedAt?: Date;
 }
 
 export interface NewProgram {
@@ -1,7 +1,10 @@
 export enum State {
+    INITIALIZING = 'INITIALIZING',
     RUNNING = 'RUNNING',
     COMPILING = 'COMPILING',
+    STOPPING = 'STOPPING',
     STOPPED = 'STOPPED',
+    FAULTED = 'FAULTED',
 }
 
 export interface StateInfo {
@@ -11,6 +14,17 @@ export interface StateInfo {
     path: string;
     hostname: string;
     uptime: string;
+    fault?: string;
+    deployment?: Deployment;
+}
+
+export type DeployPhase = 'stopping' | 'compiling' | 'starting' | 'rollingBack' | 'succeeded' | 'rolledBack' | 'failed';
+
+export interface Deployment {
+    programId: number;
+    jobId: number;
+    phase: DeployPhase;
+    error?: string;
 }
 
 export interface StateRequest {